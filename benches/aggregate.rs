@@ -0,0 +1,108 @@
+//! Compare merging per-exchange books with re-sorting a combined summary on every update.
+//!
+//! Each iteration applies one exchange's update to a market which seven exchanges contribute to, then produces the
+//! best levels on each side, as the aggregator does for every book it receives.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_decimal::Decimal;
+use spreadget::{AggregatedBook, AnonymousLevel, Level};
+
+const SUMMARY_LEN: usize = 10;
+
+/// Each exchange, and how many levels it sends on each side.
+const EXCHANGES: [(&str, usize); 7] = [
+    ("binance", 20),
+    ("bitfinex", 25),
+    ("bitstamp", 100),
+    ("coinbase", 100),
+    ("htx", 150),
+    ("kraken", 10),
+    ("okx", 5),
+];
+
+/// A book whose levels are spaced a tick apart around a mid price, offset per exchange so that they interleave.
+fn book(offset: i64, len: usize) -> (Vec<AnonymousLevel>, Vec<AnonymousLevel>) {
+    let level = |ticks: i64| AnonymousLevel {
+        price: Decimal::new(7_036_500 + ticks, 8),
+        amount: Decimal::new(100_000_000 + ticks.abs() * 1_000, 8),
+    };
+    let bids = (0..len as i64).map(|i| level(-offset - 7 * i)).collect();
+    let asks = (0..len as i64).map(|i| level(offset + 1 + 7 * i)).collect();
+    (bids, asks)
+}
+
+/// The approach which [`AggregatedBook`] replaced: keep only the summary, and on every update drop the exchange's
+/// old levels, add its new ones, and sort and truncate both sides.
+fn retain_extend_sort(
+    summary: &mut (Vec<Level>, Vec<Level>),
+    exchange: &str,
+    bids: Vec<AnonymousLevel>,
+    asks: Vec<AnonymousLevel>,
+) {
+    for (levels, new_data) in [(&mut summary.0, bids), (&mut summary.1, asks)] {
+        levels.retain(|level| level.exchange != exchange);
+        levels.extend(
+            new_data
+                .into_iter()
+                .map(|level| level.associate(exchange.to_string())),
+        );
+    }
+    summary.0.sort_unstable_by(|left, right| {
+        left.price
+            .cmp(&right.price)
+            .reverse()
+            .then_with(|| left.amount.cmp(&right.amount).reverse())
+    });
+    summary.1.sort_unstable_by(|left, right| {
+        left.price
+            .cmp(&right.price)
+            .then_with(|| left.amount.cmp(&right.amount).reverse())
+    });
+    summary.0.truncate(SUMMARY_LEN);
+    summary.1.truncate(SUMMARY_LEN);
+}
+
+fn update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update");
+
+    for (updating, (exchange, len)) in EXCHANGES.into_iter().enumerate() {
+        let (bids, asks) = book(updating as i64, len);
+
+        let mut summary = (Vec::new(), Vec::new());
+        for (offset, (name, len)) in EXCHANGES.into_iter().enumerate() {
+            let (bids, asks) = book(offset as i64, len);
+            retain_extend_sort(&mut summary, name, bids, asks);
+        }
+        group.bench_with_input(
+            BenchmarkId::new("retain_extend_sort", exchange),
+            &(bids.clone(), asks.clone()),
+            |b, (bids, asks)| {
+                b.iter(|| {
+                    retain_extend_sort(&mut summary, exchange, bids.clone(), asks.clone());
+                    black_box((summary.0.clone(), summary.1.clone()))
+                })
+            },
+        );
+
+        let mut aggregated = AggregatedBook::new(SUMMARY_LEN);
+        for (offset, (name, len)) in EXCHANGES.into_iter().enumerate() {
+            let (bids, asks) = book(offset as i64, len);
+            aggregated.replace(name, bids, asks);
+        }
+        group.bench_with_input(
+            BenchmarkId::new("k_way_merge", exchange),
+            &(bids, asks),
+            |b, (bids, asks)| {
+                b.iter(|| {
+                    aggregated.replace(exchange, bids.clone(), asks.clone());
+                    black_box((aggregated.bids(), aggregated.asks()))
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, update);
+criterion_main!(benches);
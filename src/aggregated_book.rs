@@ -0,0 +1,755 @@
+use crate::{AnonymousLevel, ConsolidatedLevel, Level, Side};
+use rust_decimal::Decimal;
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// The best levels of several exchanges' order books for the same market.
+///
+/// Each exchange's levels are kept apart, best first, so that replacing one exchange's book leaves every other
+/// exchange's levels intact. They are kept whole, not just as deep as the merge, so that the depth can grow.
+///
+/// The best levels across all exchanges are a k-way merge of the exchanges' books, which is maintained
+/// incrementally: when one exchange's book changes, the merge of every other exchange's book is still right as far
+/// as it goes, so it need only be carried on as far as the exchange's old levels reached, and then merged with the
+/// exchange's new levels.
+#[derive(Debug, Clone)]
+pub struct AggregatedBook {
+    depth: usize,
+    /// Every level of each exchange's book, keyed by exchange.
+    books: BTreeMap<&'static str, ExchangeBook>,
+    /// The best bids across all exchanges, best first.
+    bids: Vec<(&'static str, AnonymousLevel)>,
+    /// The best asks across all exchanges, best first.
+    asks: Vec<(&'static str, AnonymousLevel)>,
+}
+
+/// Where one exchange's bids are higher than another exchange's asks, so that buying from the one and selling to the
+/// other would make a profit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crossing {
+    /// The exchange whose asks to buy from.
+    pub buy_exchange: &'static str,
+    /// The exchange whose bids to sell to.
+    pub sell_exchange: &'static str,
+    /// The best ask on the exchange to buy from.
+    pub best_ask: Decimal,
+    /// The best bid on the exchange to sell to.
+    pub best_bid: Decimal,
+    /// How much can be bought and sold again at a profit, across every crossed level.
+    pub quantity: Decimal,
+    /// The profit from buying and selling the whole quantity, before fees.
+    pub gross_profit: Decimal,
+}
+
+/// The levels of a single exchange's book, best first.
+#[derive(Debug, Clone, Default)]
+struct ExchangeBook {
+    bids: Vec<AnonymousLevel>,
+    asks: Vec<AnonymousLevel>,
+}
+
+impl ExchangeBook {
+    fn side(&self, side: Side) -> &[AnonymousLevel] {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+}
+
+impl AggregatedBook {
+    /// Create an empty book which keeps the best `depth` levels on each side.
+    pub fn new(depth: usize) -> Self {
+        AggregatedBook {
+            depth,
+            books: BTreeMap::new(),
+            bids: Vec::new(),
+            asks: Vec::new(),
+        }
+    }
+
+    /// How many of the best levels on each side are kept.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Change how many of the best levels on each side are kept.
+    ///
+    /// Since every exchange's book is kept whole, the merge is immediately as deep as it can be.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        for (side, merged) in [(Side::Bid, &mut self.bids), (Side::Ask, &mut self.asks)] {
+            merged.truncate(depth);
+            continue_merge(side, depth, self.books.iter(), merged);
+        }
+    }
+
+    /// `true` when no exchange has any levels.
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Replace all levels from the named exchange.
+    ///
+    /// The levels needn't be in order, but exchanges almost always send them best first, which makes sorting them
+    /// cheap.
+    pub fn replace(
+        &mut self,
+        exchange: &'static str,
+        mut bids: Vec<AnonymousLevel>,
+        mut asks: Vec<AnonymousLevel>,
+    ) {
+        bids.sort_unstable_by(|left, right| compare(Side::Bid, right, left));
+        asks.sort_unstable_by(|left, right| compare(Side::Ask, right, left));
+        self.books.insert(exchange, ExchangeBook { bids, asks });
+        self.remerge(exchange);
+    }
+
+    /// Remove all levels from the named exchange.
+    ///
+    /// Returns `true` if any levels were removed.
+    pub fn evict(&mut self, exchange: &str) -> bool {
+        let Some((exchange, book)) = self.books.remove_entry(exchange) else {
+            return false;
+        };
+        self.remerge(exchange);
+        !book.bids.is_empty() || !book.asks.is_empty()
+    }
+
+    /// The best bids across all exchanges, best (highest) first.
+    pub fn bids(&self) -> Vec<Level> {
+        associate_all(&self.bids)
+    }
+
+    /// The best asks across all exchanges, best (lowest) first.
+    pub fn asks(&self) -> Vec<Level> {
+        associate_all(&self.asks)
+    }
+
+    /// The best `depth` prices on one side across all exchanges, each with every exchange's levels at that price
+    /// combined.
+    ///
+    /// With a nonzero `tick`, prices are instead grouped into buckets of that size: bids round down to a multiple of
+    /// the tick and asks round up, so that every level in a bucket is at least as good as its price.
+    pub fn consolidated(&self, side: Side, depth: usize, tick: Decimal) -> Vec<ConsolidatedLevel> {
+        let books = self
+            .books
+            .iter()
+            .map(|(exchange, book)| (*exchange, book.side(side)));
+        let mut consolidated: Vec<ConsolidatedLevel> = Vec::new();
+        // prices only get worse as the merge goes on, so each bucket's levels are all together
+        for (exchange, level) in Merge::new(side, books) {
+            let price = bucket(side, level.price, tick);
+            if let Some(last) = consolidated.last_mut().filter(|last| last.price == price) {
+                last.add(exchange, level.amount);
+            } else if consolidated.len() < depth {
+                consolidated.push(ConsolidatedLevel::new(price, exchange, level.amount));
+            } else {
+                break;
+            }
+        }
+        for level in consolidated.iter_mut() {
+            level
+                .contributions
+                .sort_unstable_by(|left, right| left.exchange.cmp(&right.exchange));
+        }
+        consolidated
+    }
+
+    /// The best `depth` levels on one side across all exchanges, at prices adjusted by `adjust`, such as to account
+    /// for each exchange's fees.
+    ///
+    /// The adjustment is given each level's exchange and price, and mustn't change the order of an exchange's levels.
+    pub fn adjusted(
+        &self,
+        side: Side,
+        depth: usize,
+        adjust: impl Fn(&str, Decimal) -> Decimal,
+    ) -> Vec<Level> {
+        // only an exchange's best levels can be among the best of all, once adjusted
+        let books: Vec<(&'static str, Vec<AnonymousLevel>)> = self
+            .books
+            .iter()
+            .map(|(exchange, book)| {
+                let levels = book
+                    .side(side)
+                    .iter()
+                    .take(depth)
+                    .map(|level| AnonymousLevel {
+                        price: adjust(exchange, level.price),
+                        amount: level.amount,
+                    });
+                (*exchange, levels.collect())
+            })
+            .collect();
+        let books = books
+            .iter()
+            .map(|(exchange, levels)| (*exchange, levels.as_slice()));
+        let merged: Vec<_> = Merge::new(side, books).take(depth).collect();
+        associate_all(&merged)
+    }
+
+    /// Every pair of exchanges whose books cross, ordered by the exchange to buy from, then the exchange to sell to.
+    ///
+    /// The quantity and profit only account for the levels which each exchange sends, which may not be all of
+    /// those which cross.
+    pub fn crossings(&self) -> Vec<Crossing> {
+        let mut crossings = Vec::new();
+        for (buy_exchange, buy_book) in self.books.iter() {
+            for (sell_exchange, sell_book) in self.books.iter() {
+                if buy_exchange != sell_exchange {
+                    crossings.extend(cross(buy_exchange, buy_book, sell_exchange, sell_book));
+                }
+            }
+        }
+        crossings
+    }
+
+    /// Bring the merged levels up to date after the named exchange's book has changed.
+    fn remerge(&mut self, exchange: &'static str) {
+        for (side, merged) in [(Side::Bid, &mut self.bids), (Side::Ask, &mut self.asks)] {
+            // without the exchange's old levels, what remains is the start of the merge of every other book
+            merged.retain(|(name, _)| *name != exchange);
+            let others = self.books.iter().filter(|(name, _)| **name != exchange);
+            continue_merge(side, self.depth, others, merged);
+
+            if let Some(book) = self.books.get(exchange) {
+                merge_in(side, self.depth, exchange, book.side(side), merged);
+            }
+        }
+    }
+}
+
+/// Walk one exchange's asks and another's bids, best first, for as long as the bids are higher.
+fn cross(
+    buy_exchange: &'static str,
+    buy_book: &ExchangeBook,
+    sell_exchange: &'static str,
+    sell_book: &ExchangeBook,
+) -> Option<Crossing> {
+    let (best_ask, best_bid) = (buy_book.asks.first()?, sell_book.bids.first()?);
+    if best_bid.price <= best_ask.price {
+        return None;
+    }
+
+    let mut asks = buy_book.asks.iter().map(|ask| (ask.price, ask.amount));
+    let mut bids = sell_book.bids.iter().map(|bid| (bid.price, bid.amount));
+    let (mut ask, mut bid) = (asks.next(), bids.next());
+    let mut quantity = Decimal::ZERO;
+    let mut gross_profit = Decimal::ZERO;
+    while let (Some((ask_price, ask_amount)), Some((bid_price, bid_amount))) = (&mut ask, &mut bid)
+    {
+        if *bid_price <= *ask_price {
+            break;
+        }
+        let traded = (*ask_amount).min(*bid_amount);
+        quantity += traded;
+        gross_profit += traded * (*bid_price - *ask_price);
+        *ask_amount -= traded;
+        *bid_amount -= traded;
+        if ask_amount.is_zero() {
+            ask = asks.next();
+        }
+        if bid_amount.is_zero() {
+            bid = bids.next();
+        }
+    }
+
+    Some(Crossing {
+        buy_exchange,
+        sell_exchange,
+        best_ask: best_ask.price,
+        best_bid: best_bid.price,
+        quantity,
+        gross_profit,
+    })
+}
+
+/// Carry on the merge of one side of several books, of which `merged` is the start, as far as the depth.
+fn continue_merge<'a>(
+    side: Side,
+    depth: usize,
+    books: impl Iterator<Item = (&'a &'static str, &'a ExchangeBook)>,
+    merged: &mut Vec<(&'static str, AnonymousLevel)>,
+) {
+    if merged.len() >= depth {
+        return;
+    }
+
+    // each book's levels which have yet to be merged; those already merged are always its best
+    let remaining = books.map(|(exchange, book)| {
+        let levels = book.side(side);
+        let taken = merged.iter().filter(|(name, _)| name == exchange).count();
+        (*exchange, &levels[taken.min(levels.len())..])
+    });
+    let more = depth - merged.len();
+    merged.extend(Merge::new(side, remaining).take(more));
+}
+
+/// The levels on one side of several books, best first.
+///
+/// There are only ever a handful of exchanges, so scanning them all for the best level is cheaper than keeping them in
+/// a heap. Equally good levels are taken from exchanges in order of their names, so that merging is deterministic.
+struct Merge<'a> {
+    side: Side,
+    remaining: Vec<(&'static str, &'a [AnonymousLevel])>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(side: Side, books: impl Iterator<Item = (&'static str, &'a [AnonymousLevel])>) -> Self {
+        Merge {
+            side,
+            remaining: books.filter(|(_, levels)| !levels.is_empty()).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = (&'static str, AnonymousLevel);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = &mut self.remaining;
+        let best = (0..remaining.len()).max_by(|&left, &right| {
+            let (left_exchange, left_levels) = remaining[left];
+            let (right_exchange, right_levels) = remaining[right];
+            compare(self.side, &left_levels[0], &right_levels[0])
+                .then_with(|| right_exchange.cmp(left_exchange))
+        })?;
+        let (exchange, levels) = &mut remaining[best];
+        let next = (*exchange, levels[0]);
+        *levels = &levels[1..];
+        if levels.is_empty() {
+            remaining.swap_remove(best);
+        }
+        Some(next)
+    }
+}
+
+/// Merge one exchange's levels, best first, into the merged levels of every other exchange, up to the depth.
+fn merge_in(
+    side: Side,
+    depth: usize,
+    exchange: &'static str,
+    levels: &[AnonymousLevel],
+    merged: &mut Vec<(&'static str, AnonymousLevel)>,
+) {
+    // each level goes after every better level, and since both are in order, after the previous level too
+    let mut start = 0;
+    for level in levels {
+        let position = start
+            + merged[start..].partition_point(|(other_exchange, other)| {
+                compare(side, other, level)
+                    .then_with(|| exchange.cmp(other_exchange))
+                    .is_gt()
+            });
+        if position >= depth {
+            break;
+        }
+        merged.insert(position, (exchange, *level));
+        start = position + 1;
+    }
+    merged.truncate(depth);
+}
+
+/// Compare two levels on the same side of a book: the better level is greater.
+///
+/// Higher bids and lower asks are better; at the same price, the larger amount is better.
+fn compare(side: Side, left: &AnonymousLevel, right: &AnonymousLevel) -> Ordering {
+    match side {
+        Side::Bid => left.price.cmp(&right.price),
+        Side::Ask => right.price.cmp(&left.price),
+    }
+    .then_with(|| left.amount.cmp(&right.amount))
+}
+
+/// The price of the bucket to which a price belongs: a multiple of the tick, rounded towards worse prices.
+fn bucket(side: Side, price: Decimal, tick: Decimal) -> Decimal {
+    if tick.is_zero() {
+        return price;
+    }
+    let ticks = price / tick;
+    match side {
+        Side::Bid => ticks.floor() * tick,
+        Side::Ask => ticks.ceil() * tick,
+    }
+}
+
+fn associate_all(levels: &[(&'static str, AnonymousLevel)]) -> Vec<Level> {
+    levels
+        .iter()
+        .map(|(exchange, level)| level.associate(exchange.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(price: i64, amount: i64) -> AnonymousLevel {
+        AnonymousLevel {
+            price: Decimal::new(price, 2),
+            amount: Decimal::from(amount),
+        }
+    }
+
+    fn levels(levels: &[(i64, i64)]) -> Vec<AnonymousLevel> {
+        levels
+            .iter()
+            .map(|&(price, amount)| level(price, amount))
+            .collect()
+    }
+
+    /// The merge computed from scratch: every exchange's levels, sorted best first, with ties broken by exchange.
+    fn full_sort(
+        side: Side,
+        depth: usize,
+        books: &BTreeMap<&'static str, (Vec<AnonymousLevel>, Vec<AnonymousLevel>)>,
+    ) -> Vec<Level> {
+        let mut all: Vec<_> = books
+            .iter()
+            .flat_map(|(exchange, (bids, asks))| {
+                let levels = match side {
+                    Side::Bid => bids,
+                    Side::Ask => asks,
+                };
+                levels.iter().map(move |level| (*exchange, *level))
+            })
+            .collect();
+        all.sort_by(|(left_exchange, left), (right_exchange, right)| {
+            compare(side, right, left).then_with(|| left_exchange.cmp(right_exchange))
+        });
+        all.truncate(depth);
+        associate_all(&all)
+    }
+
+    /// Apply the same changes to an aggregated book and to a plain record of each exchange's levels, checking after
+    /// each that the incremental merge matches a full re-sort.
+    struct Check {
+        book: AggregatedBook,
+        books: BTreeMap<&'static str, (Vec<AnonymousLevel>, Vec<AnonymousLevel>)>,
+    }
+
+    impl Check {
+        fn new(depth: usize) -> Self {
+            Check {
+                book: AggregatedBook::new(depth),
+                books: BTreeMap::new(),
+            }
+        }
+
+        fn replace(&mut self, exchange: &'static str, bids: &[(i64, i64)], asks: &[(i64, i64)]) {
+            self.book.replace(exchange, levels(bids), levels(asks));
+            self.books.insert(exchange, (levels(bids), levels(asks)));
+            self.check();
+        }
+
+        fn evict(&mut self, exchange: &'static str) {
+            self.book.evict(exchange);
+            self.books.remove(exchange);
+            self.check();
+        }
+
+        fn set_depth(&mut self, depth: usize) {
+            self.book.set_depth(depth);
+            self.check();
+        }
+
+        fn check(&self) {
+            let depth = self.book.depth();
+            assert_eq!(self.book.bids(), full_sort(Side::Bid, depth, &self.books));
+            assert_eq!(self.book.asks(), full_sort(Side::Ask, depth, &self.books));
+        }
+    }
+
+    #[test]
+    fn replace_shrink_grow_and_evict() {
+        let mut check = Check::new(5);
+        check.replace("a", &[(100, 1), (99, 1), (98, 1)], &[(101, 1), (102, 1)]);
+        check.replace("b", &[(100, 2), (97, 1)], &[(103, 1), (104, 1), (105, 1)]);
+        check.replace("c", &[(96, 1)], &[(101, 3)]);
+
+        // replace one exchange's book with entirely different levels
+        check.replace("a", &[(95, 1), (94, 1)], &[(106, 1), (107, 1)]);
+        // shrink it
+        check.replace("b", &[(100, 2)], &[]);
+        // grow it beyond the depth
+        check.replace(
+            "b",
+            &[(100, 2), (99, 2), (98, 2), (97, 2), (96, 2), (95, 2)],
+            &[(101, 2), (102, 2), (103, 2), (104, 2), (105, 2), (106, 2)],
+        );
+        // levels out of order are sorted
+        check.replace("c", &[(90, 1), (101, 1), (95, 5)], &[(108, 1), (100, 1)]);
+
+        check.evict("b");
+        check.evict("missing");
+        check.evict("a");
+        check.evict("c");
+        assert!(check.book.is_empty());
+    }
+
+    #[test]
+    fn ties_between_exchanges() {
+        let mut check = Check::new(6);
+        // identical levels on every exchange are ordered by exchange
+        check.replace("c", &[(100, 1), (99, 1)], &[(101, 1), (102, 1)]);
+        check.replace("a", &[(100, 1), (99, 1)], &[(101, 1), (102, 1)]);
+        check.replace("b", &[(100, 1), (99, 1)], &[(101, 1), (102, 1)]);
+        assert_eq!(
+            check
+                .book
+                .bids()
+                .iter()
+                .map(|level| level.exchange.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b", "c", "a", "b", "c"]
+        );
+
+        // at the same price, the larger amount comes first whatever the exchange
+        check.replace("c", &[(100, 2), (99, 1)], &[(101, 2), (102, 1)]);
+        assert_eq!(check.book.bids()[0].exchange, "c");
+        check.replace("b", &[(100, 1), (99, 1)], &[(101, 1), (102, 1)]);
+        check.evict("a");
+        check.replace("a", &[(100, 2)], &[(101, 2)]);
+    }
+
+    #[test]
+    fn depth_changes() {
+        let mut check = Check::new(2);
+        check.replace("a", &[(100, 1), (98, 1), (96, 1)], &[(101, 1), (103, 1)]);
+        check.replace("b", &[(99, 1), (97, 1), (95, 1)], &[(102, 1), (104, 1)]);
+        check.set_depth(5);
+        check.set_depth(1);
+        check.replace("a", &[(94, 1)], &[(105, 1)]);
+        check.set_depth(10);
+    }
+
+    #[test]
+    fn random_changes() {
+        // a small linear congruential generator, so that the sequence is repeatable
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % bound
+        };
+        let exchanges = ["a", "b", "c", "d"];
+
+        let mut check = Check::new(8);
+        for _ in 0..2_000 {
+            let exchange = exchanges[next(exchanges.len() as u64) as usize];
+            match next(10) {
+                0 => check.evict(exchange),
+                1 => check.set_depth(1 + next(12) as usize),
+                _ => {
+                    // few distinct prices and amounts, so that there are plenty of ties
+                    let mut side = |base: i64, direction: i64| -> Vec<(i64, i64)> {
+                        (0..next(12))
+                            .map(|_| {
+                                let price = base + direction * next(10) as i64;
+                                (price, 1 + next(3) as i64)
+                            })
+                            .collect()
+                    };
+                    let bids = side(100, -1);
+                    let asks = side(100, 1);
+                    check.replace(exchange, &bids, &asks);
+                }
+            }
+        }
+    }
+
+    fn price(price: &str) -> Decimal {
+        price.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_rounds_towards_worse_prices() {
+        let tick = price("0.05");
+        assert_eq!(bucket(Side::Bid, price("1.23"), tick), price("1.20"));
+        assert_eq!(bucket(Side::Ask, price("1.23"), tick), price("1.25"));
+        // prices already on a tick stay put
+        assert_eq!(bucket(Side::Bid, price("1.25"), tick), price("1.25"));
+        assert_eq!(bucket(Side::Ask, price("1.25"), tick), price("1.25"));
+        // ticks needn't be powers of ten
+        assert_eq!(bucket(Side::Bid, price("17"), price("5")), price("15"));
+        assert_eq!(bucket(Side::Ask, price("17"), price("5")), price("20"));
+        // without a tick, every price is its own bucket
+        assert_eq!(
+            bucket(Side::Ask, price("1.2345"), Decimal::ZERO),
+            price("1.2345")
+        );
+    }
+
+    fn consolidated_level(price: i64, contributions: &[(&str, i64)]) -> ConsolidatedLevel {
+        let (first_exchange, first_amount) = contributions[0];
+        let mut level = ConsolidatedLevel::new(
+            Decimal::new(price, 2),
+            first_exchange,
+            Decimal::from(first_amount),
+        );
+        for &(exchange, amount) in &contributions[1..] {
+            level.add(exchange, Decimal::from(amount));
+        }
+        level
+    }
+
+    fn example_book() -> AggregatedBook {
+        let mut book = AggregatedBook::new(10);
+        book.replace(
+            "b",
+            levels(&[(100, 1), (98, 2), (96, 3)]),
+            levels(&[(101, 1), (103, 2)]),
+        );
+        book.replace(
+            "a",
+            levels(&[(100, 4), (99, 5), (96, 6)]),
+            levels(&[(102, 4), (103, 5), (105, 6)]),
+        );
+        book
+    }
+
+    #[test]
+    fn consolidated_sums_contributions_at_the_same_price() {
+        let book = example_book();
+        assert_eq!(
+            book.consolidated(Side::Bid, 10, Decimal::ZERO),
+            [
+                consolidated_level(100, &[("a", 4), ("b", 1)]),
+                consolidated_level(99, &[("a", 5)]),
+                consolidated_level(98, &[("b", 2)]),
+                consolidated_level(96, &[("a", 6), ("b", 3)]),
+            ]
+        );
+        assert_eq!(
+            book.consolidated(Side::Ask, 10, Decimal::ZERO),
+            [
+                consolidated_level(101, &[("b", 1)]),
+                consolidated_level(102, &[("a", 4)]),
+                consolidated_level(103, &[("a", 5), ("b", 2)]),
+                consolidated_level(105, &[("a", 6)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn consolidated_buckets_by_tick() {
+        let book = example_book();
+        let tick = price("0.02");
+        // bids round down, so 0.99 joins 0.98
+        assert_eq!(
+            book.consolidated(Side::Bid, 10, tick),
+            [
+                consolidated_level(100, &[("a", 4), ("b", 1)]),
+                consolidated_level(98, &[("a", 5), ("b", 2)]),
+                consolidated_level(96, &[("a", 6), ("b", 3)]),
+            ]
+        );
+        // asks round up, so 1.01 joins 1.02, and 1.03 and 1.05 go to their own buckets
+        assert_eq!(
+            book.consolidated(Side::Ask, 10, tick),
+            [
+                consolidated_level(102, &[("a", 4), ("b", 1)]),
+                consolidated_level(104, &[("a", 5), ("b", 2)]),
+                consolidated_level(106, &[("a", 6)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn consolidated_depth_counts_prices_not_levels() {
+        let book = example_book();
+        assert_eq!(
+            book.consolidated(Side::Bid, 2, Decimal::ZERO),
+            [
+                consolidated_level(100, &[("a", 4), ("b", 1)]),
+                consolidated_level(99, &[("a", 5)]),
+            ]
+        );
+        // the last bucket is complete, even though it takes levels beyond the depth
+        assert_eq!(
+            book.consolidated(Side::Ask, 1, price("0.05")),
+            [consolidated_level(105, &[("a", 15), ("b", 3)])]
+        );
+        assert!(book.consolidated(Side::Bid, 0, Decimal::ZERO).is_empty());
+        // the consolidated depth is independent of the book's own
+        let mut shallow = example_book();
+        shallow.set_depth(1);
+        assert_eq!(
+            shallow.consolidated(Side::Bid, 10, Decimal::ZERO),
+            book.consolidated(Side::Bid, 10, Decimal::ZERO)
+        );
+    }
+
+    #[test]
+    fn cross_walks_every_crossed_level() {
+        let mut book = AggregatedBook::new(10);
+        // buy from "a" at 1.00, 1.01, and 1.03; sell to "b" at 1.04, 1.02, and 1.01
+        book.replace(
+            "a",
+            levels(&[(95, 1)]),
+            levels(&[(100, 2), (101, 3), (103, 5)]),
+        );
+        book.replace(
+            "b",
+            levels(&[(104, 1), (102, 3), (101, 4)]),
+            levels(&[(110, 1)]),
+        );
+
+        assert_eq!(
+            book.crossings(),
+            [Crossing {
+                buy_exchange: "a",
+                sell_exchange: "b",
+                best_ask: price("1.00"),
+                best_bid: price("1.04"),
+                // 1 at 1.00 sold at 1.04, 1 at 1.00 sold at 1.02, and 2 at 1.01 sold at 1.02; 1.01 doesn't cross 1.01
+                quantity: Decimal::from(4),
+                gross_profit: price("0.04") + price("0.02") + price("0.02"),
+            }]
+        );
+    }
+
+    #[test]
+    fn cross_is_limited_by_the_shallower_book() {
+        let mut book = AggregatedBook::new(10);
+        book.replace("a", Vec::new(), levels(&[(100, 10)]));
+        book.replace("b", levels(&[(102, 1), (101, 2)]), Vec::new());
+
+        let crossings = book.crossings();
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].quantity, Decimal::from(3));
+        assert_eq!(crossings[0].gross_profit, price("0.04"));
+    }
+
+    #[test]
+    fn no_crossings() {
+        let mut book = AggregatedBook::new(10);
+        book.replace("a", levels(&[(99, 1)]), levels(&[(101, 1)]));
+        // touching but not crossing
+        book.replace("b", levels(&[(101, 1)]), levels(&[(102, 1)]));
+        // one-sided
+        book.replace("c", levels(&[(98, 1)]), Vec::new());
+        book.replace("d", Vec::new(), Vec::new());
+        assert!(book.crossings().is_empty());
+    }
+
+    #[test]
+    fn crossings_in_every_direction() {
+        let mut book = AggregatedBook::new(10);
+        // "c" crosses both others, and each of them crosses it, but they don't cross each other
+        book.replace("a", levels(&[(99, 1)]), levels(&[(100, 1)]));
+        book.replace("b", levels(&[(99, 2)]), levels(&[(100, 2)]));
+        book.replace("c", levels(&[(105, 1)]), levels(&[(95, 1)]));
+
+        let pairs: Vec<_> = book
+            .crossings()
+            .iter()
+            .map(|crossing| (crossing.buy_exchange, crossing.sell_exchange))
+            .collect();
+        assert_eq!(pairs, [("a", "c"), ("b", "c"), ("c", "a"), ("c", "b")]);
+
+        book.evict("c");
+        assert!(book.crossings().is_empty());
+    }
+}
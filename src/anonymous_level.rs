@@ -1,5 +1,6 @@
+use rust_decimal::Decimal;
 use serde::{
-    de::{Error as _, SeqAccess},
+    de::{Error as _, IgnoredAny, SeqAccess},
     Deserialize,
 };
 
@@ -9,8 +10,8 @@ use serde::{
 /// the exchange on which the offer is made.
 #[derive(Debug, Clone, Copy)]
 pub struct AnonymousLevel {
-    pub price: f64,
-    pub amount: f64,
+    pub price: Decimal,
+    pub amount: Decimal,
 }
 
 impl AnonymousLevel {
@@ -36,21 +37,20 @@ impl<'de> Deserialize<'de> for AnonymousLevel {
             type Value = AnonymousLevel;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("list of two numbers")
+                formatter.write_str("list of at least two numbers")
             }
 
             fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
                 let mut expect_element = |field_name: &'static str| {
-                    seq.next_element::<StringFloat>()?
+                    seq.next_element::<StringDecimal>()?
                         .ok_or_else(|| A::Error::missing_field(field_name))
                 };
 
                 let price = expect_element("price")?;
                 let amount = expect_element("qty")?;
 
-                if seq.next_element::<StringFloat>()?.is_some() {
-                    return Err(A::Error::invalid_length(3, &self));
-                }
+                // Some exchanges append further fields, such as order counts, which we don't need.
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
 
                 Ok(AnonymousLevel {
                     price: price.into(),
@@ -64,9 +64,12 @@ impl<'de> Deserialize<'de> for AnonymousLevel {
 }
 
 /// This helper type exists so that we can deserialize a string representation of a number into itself.
-struct StringFloat(f64);
+///
+/// Numbers are kept as exact decimals, so that e.g. `"0.07036500"` isn't approximated by the nearest float.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StringDecimal(pub(crate) Decimal);
 
-impl<'de> Deserialize<'de> for StringFloat {
+impl<'de> Deserialize<'de> for StringDecimal {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -74,31 +77,126 @@ impl<'de> Deserialize<'de> for StringFloat {
         struct Visitor;
 
         impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = StringFloat;
+            type Value = StringDecimal;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("string containing a floating point literal")
+                formatter.write_str("string containing a decimal literal")
             }
 
             /// In case we get a raw float instead of a string, we can handle it.
+            ///
+            /// The float's shortest representation is taken to be the number which the exchange sent.
             fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
-                Ok(StringFloat(v))
+                v.to_string()
+                    .parse::<Decimal>()
+                    .map(StringDecimal)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Float(v), &self))
+            }
+
+            /// Raw numbers without a fractional part arrive as integers.
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(StringDecimal(v.into()))
+            }
+
+            /// Raw numbers without a fractional part arrive as integers.
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(StringDecimal(v.into()))
             }
 
-            /// In case we get a string, try to parse it as a float.
+            /// In case we get a string, try to parse it as a decimal, in scientific notation if need be.
             fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
-                v.parse::<f64>()
-                    .map(StringFloat)
+                v.parse::<Decimal>()
+                    .map(StringDecimal)
                     .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(v), &self))
             }
         }
 
-        deserializer.deserialize_str(Visitor)
+        // Some exchanges send raw numbers rather than strings, so we can't just ask for a string.
+        deserializer.deserialize_any(Visitor)
     }
 }
 
-impl From<StringFloat> for f64 {
-    fn from(sf: StringFloat) -> Self {
-        sf.0
+impl From<StringDecimal> for Decimal {
+    fn from(sd: StringDecimal) -> Self {
+        sd.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(json: &str) -> Decimal {
+        serde_json::from_str::<StringDecimal>(json).unwrap().into()
+    }
+
+    #[test]
+    fn strings_are_exact() {
+        let price = decimal(r#""0.07036500""#);
+        assert_eq!(price.to_string(), "0.07036500");
+        assert_eq!(price.scale(), 8);
+        assert_eq!(decimal(r#""-12.5""#).to_string(), "-12.5");
+        assert_eq!(decimal(r#""123""#).to_string(), "123");
+        assert_eq!(decimal(r#""1.5e-5""#).to_string(), "0.000015");
+    }
+
+    #[test]
+    fn floats_take_their_shortest_representation() {
+        assert_eq!(decimal("0.1").to_string(), "0.1");
+        assert_eq!(decimal("0.07015").to_string(), "0.07015");
+        assert_eq!(decimal("1.6864241").to_string(), "1.6864241");
+        assert_eq!(decimal("-0.5").to_string(), "-0.5");
+        // trailing zeros can't survive the trip through a float
+        assert_eq!(decimal("6.50").to_string(), "6.5");
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(decimal("42").to_string(), "42");
+        assert_eq!(decimal("-42").to_string(), "-42");
+        assert_eq!(decimal(&u64::MAX.to_string()), Decimal::from(u64::MAX));
+        assert_eq!(decimal(&i64::MIN.to_string()), Decimal::from(i64::MIN));
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        for json in [
+            r#""""#,
+            r#""abc""#,
+            r#""1.2.3""#,
+            r#""0x10""#,
+            "true",
+            "null",
+            "[1]",
+        ] {
+            assert!(
+                serde_json::from_str::<StringDecimal>(json).is_err(),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let level: AnonymousLevel = serde_json::from_str(r#"["0.07036500","1.5"]"#).unwrap();
+        assert_eq!(level.price.to_string(), "0.07036500");
+        assert_eq!(level.amount.to_string(), "1.5");
+
+        let level: AnonymousLevel = serde_json::from_str("[0.07015, 2]").unwrap();
+        assert_eq!(level.price.to_string(), "0.07015");
+        assert_eq!(level.amount.to_string(), "2");
+
+        // extra elements, such as order counts, are ignored whatever their type
+        let level: AnonymousLevel =
+            serde_json::from_str(r#"["0.07036500","1.5",3,"extra",[null]]"#).unwrap();
+        assert_eq!(level.price.to_string(), "0.07036500");
+        assert_eq!(level.amount.to_string(), "1.5");
+
+        for json in [r#"["0.07036500"]"#, "[]", r#"["0.07036500","lots"]"#, "{}"] {
+            assert!(
+                serde_json::from_str::<AnonymousLevel>(json).is_err(),
+                "{json}"
+            );
+        }
     }
 }
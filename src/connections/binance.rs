@@ -11,60 +11,199 @@
 //! {"lastUpdateId":5071750763,"bids":[["0.07036500","13.01310000"],["0.07036400","0.10000000"],["0.07036200","1.45170000"],["0.07036000","0.26890000"],["0.07035800","0.15590000"],["0.07035700","1.02620000"],["0.07035600","0.10000000"],["0.07035400","9.44760000"],["0.07035200","0.10150000"],["0.07035000","0.10000000"],["0.07034900","0.62010000"],["0.07034800","0.10000000"],["0.07034600","0.10000000"],["0.07034500","0.01080000"],["0.07034400","0.35240000"],["0.07034300","3.12960000"],["0.07034200","0.12070000"],["0.07034100","0.05130000"],["0.07034000","4.79100000"],["0.07033800","0.10000000"]],"asks":[["0.07036600","6.77250000"],["0.07036700","0.90840000"],["0.07036800","9.41920000"],["0.07036900","1.12060000"],["0.07037000","2.64990000"],["0.07037100","1.26440000"],["0.07037200","0.12240000"],["0.07037300","2.58580000"],["0.07037400","4.24210000"],["0.07037500","0.04240000"],["0.07037600","1.43410000"],["0.07037700","2.96460000"],["0.07037800","0.31060000"],["0.07037900","0.11760000"],["0.07038000","15.76550000"],["0.07038100","12.05310000"],["0.07038200","0.20270000"],["0.07038300","0.12960000"],["0.07038400","1.88930000"],["0.07038500","0.14370000"]]}
 //! {"lastUpdateId":5071750764,"bids":[["0.07036500","13.01310000"],["0.07036400","0.10000000"],["0.07036200","1.45170000"],["0.07036000","0.26890000"],["0.07035800","0.15590000"],["0.07035700","1.02620000"],["0.07035600","0.10000000"],["0.07035400","9.44760000"],["0.07035200","0.10150000"],["0.07035000","0.10000000"],["0.07034800","0.10000000"],["0.07034600","0.10000000"],["0.07034500","0.01080000"],["0.07034400","0.35240000"],["0.07034300","3.12960000"],["0.07034200","0.12070000"],["0.07034100","0.05130000"],["0.07034000","4.79100000"],["0.07033800","0.10000000"],["0.07033600","0.10000000"]],"asks":[["0.07036600","6.77250000"],["0.07036700","0.90840000"],["0.07036800","9.41920000"],["0.07036900","1.12060000"],["0.07037000","2.64990000"],["0.07037100","1.26440000"],["0.07037200","0.12240000"],["0.07037300","2.58580000"],["0.07037400","4.24210000"],["0.07037500","0.04240000"],["0.07037600","1.43410000"],["0.07037700","2.96460000"],["0.07037800","0.31060000"],["0.07037900","0.11760000"],["0.07038000","15.76550000"],["0.07038100","12.05310000"],["0.07038200","0.20270000"],["0.07038300","0.12960000"],["0.07038400","1.88930000"],["0.07038500","0.14370000"]]}
 //! ```
+//!
+//! In [full-depth mode][BinanceMode::FullDepth], we instead follow Binance's [documented procedure][1] for
+//! maintaining a local order book: buffer the diff-depth stream, fetch a REST snapshot, and then apply the
+//! buffered and subsequent diffs in `U`/`u` order, fetching a fresh snapshot whenever the diffs stop lining up.
+//!
+//! Example diff-depth data:
+//!
+//! ```json
+//! {"e":"depthUpdate","E":1648041918792,"s":"ETHBTC","U":5071750765,"u":5071750767,"b":[["0.07036500","12.91310000"]],"a":[["0.07036600","0.00000000"],["0.07038600","1.20000000"]]}
+//! ```
+//!
+//! Several symbols can share a connection in partial-depth mode, through Binance's combined streams, which wrap
+//! each message in an envelope naming its stream:
+//!
+//! ```json
+//! {"stream":"ethbtc@depth20@100ms","data":{"lastUpdateId":5071750763,"bids":[...],"asks":[...]}}
+//! ```
+//!
+//! [1]: https://binance-docs.github.io/apidocs/spot/en/#how-to-manage-a-local-order-book-correctly
 
-use super::{read_book, ExchangeConnection};
-use crate::{AnonymousLevel, SimpleOrderBook};
-use futures::StreamExt;
+use super::{
+    connect_each,
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Gap, Liveness, Route,
+    SequenceFilter, Silent, Watchdog,
+};
+use crate::{from_unix_millis, AnonymousLevel, LocalBook, Pair, Side, SimpleOrderBook};
+use std::{collections::VecDeque, time::Duration};
 use tokio::sync::mpsc::Sender;
 use tokio_tungstenite::tungstenite::error::Error as TungsteniteError;
 
-const EXCHANGE_NAME: &'static str = "binance";
+const EXCHANGE_NAME: &str = "binance";
+
+/// Default websocket endpoint for Binance market data streams.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://stream.binance.com:9443";
+
+/// Default base URL for the Binance REST API.
+pub const DEFAULT_REST_ENDPOINT: &str = "https://api.binance.com";
+
+/// In full-depth mode, this many of the best levels on each side are sent to the aggregator by default.
+pub const DEFAULT_FULL_DEPTH_LEN: usize = 100;
+
+/// Binance permits at most this many streams over a single connection.
+const MAX_STREAMS_PER_CONNECTION: usize = 1024;
+
+/// How many levels to request in a REST snapshot; this is the maximum which Binance permits.
+const SNAPSHOT_LIMIT: usize = 5000;
+
+/// How long to wait before requesting another snapshot when the previous one was too old to use.
+///
+/// Snapshots are expensive in terms of Binance's request weight limits, so we don't want to hammer the API.
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// At most this many diffs are buffered while awaiting a snapshot; older ones are dropped to make way for newer.
+///
+/// Binance sends a diff every 100ms, so this covers several minutes of failed or outdated snapshots. A snapshot
+/// which only the dropped diffs could have brought up to date is too old to use anyway.
+const MAX_BUFFERED_DIFFS: usize = 2048;
 
 /// Message type for Binance partial book stream.
+///
+/// The REST depth snapshot has the same shape.
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Message {
-    _last_update_id: usize,
+    last_update_id: u64,
     bids: Vec<AnonymousLevel>,
     asks: Vec<AnonymousLevel>,
 }
 
 impl From<Message> for SimpleOrderBook {
     fn from(msg: Message) -> Self {
-        SimpleOrderBook {
-            bids: msg.bids.into_iter().map(Into::into).collect(),
-            asks: msg.asks.into_iter().map(Into::into).collect(),
-        }
+        SimpleOrderBook::new(msg.bids, msg.asks)
     }
 }
 
+/// Envelope for messages on a combined stream.
+#[derive(Debug, serde::Deserialize)]
+struct CombinedMessage<T> {
+    stream: String,
+    data: T,
+}
+
+/// Message type for Binance diff-depth stream.
+#[derive(Debug, serde::Deserialize)]
+struct DepthUpdate {
+    #[serde(rename = "E")]
+    event_time: u64,
+    #[serde(rename = "U")]
+    first_update_id: u64,
+    #[serde(rename = "u")]
+    final_update_id: u64,
+    #[serde(rename = "b")]
+    bids: Vec<AnonymousLevel>,
+    #[serde(rename = "a")]
+    asks: Vec<AnonymousLevel>,
+}
+
+/// Which of Binance's depth streams to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinanceMode {
+    /// Subscribe to the top 20 levels, which Binance sends as a complete snapshot every 100ms.
+    PartialDepth,
+    /// Maintain a local full-depth book from the diff-depth stream and a REST snapshot.
+    FullDepth,
+}
+
 /// Manage a websocket connection to Binance.
-pub struct BinanceConnection;
+#[derive(Debug, Clone)]
+pub struct BinanceConnection {
+    mode: BinanceMode,
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    rest_endpoint: String,
+    full_depth_len: usize,
+}
 
-#[tonic::async_trait]
-impl ExchangeConnection for BinanceConnection {
-    fn exchange_name(&self) -> &'static str {
-        EXCHANGE_NAME
+impl Default for BinanceConnection {
+    fn default() -> Self {
+        BinanceConnection {
+            mode: BinanceMode::PartialDepth,
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            rest_endpoint: DEFAULT_REST_ENDPOINT.to_string(),
+            full_depth_len: DEFAULT_FULL_DEPTH_LEN,
+        }
     }
+}
 
-    async fn connect(
+impl BinanceConnection {
+    /// Create a connection in the given mode, using Binance's production endpoints.
+    pub fn new(mode: BinanceMode) -> Self {
+        BinanceConnection {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Fetch snapshots from a different REST API base URL, such as a local mock server.
+    pub fn with_rest_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.rest_endpoint = endpoint.into();
+        self
+    }
+
+    /// In full-depth mode, send this many of the best levels on each side to the aggregator.
+    pub fn with_full_depth_len(mut self, len: usize) -> Self {
+        self.full_depth_len = len;
+        self
+    }
+
+    /// Follow the partial book stream, forwarding each snapshot as it arrives.
+    async fn follow_partial_depth(
         &self,
         symbol: String,
-        updates: Sender<(&'static str, SimpleOrderBook)>,
-    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
-        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {symbol}");
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Error> {
+        let endpoint = format!("{}/ws/{symbol}@depth20@100ms", self.websocket_endpoint);
+        let (mut stream, _response) = connect_websocket(&endpoint, &self.proxy).await?;
+        // the stream is named in the URL, so there's no separate subscription to confirm
+        report_subscribed(&updates, EXCHANGE_NAME).await;
+        let mut watchdog = Watchdog::new(self.liveness);
+        let mut sequence = SequenceFilter::default();
 
-        let endpoint = format!("wss://stream.binance.com:9443/ws/{symbol}@depth20@100ms");
-        let (mut stream, _response) =
-            tokio_tungstenite::connect_async(&endpoint)
-                .await
-                .map_err(|err| {
-                    Box::new(Error::Tungstenite(err)) as Box<dyn std::error::Error + Send>
-                })?;
-
-        while let Some(maybe_message) = stream.next().await {
-            match read_book::<Message, Error>(maybe_message) {
-                Ok(book) => {
+        while let Some(maybe_message) = watchdog.next(&mut stream).await? {
+            match read_message::<Message, Error>(maybe_message) {
+                Ok(message) if !sequence.is_fresh(message.last_update_id) => {
+                    log::debug!(
+                        "[{EXCHANGE_NAME}] discarding stale book as of update {}",
+                        message.last_update_id
+                    );
+                }
+                Ok(message) => {
+                    let book = ExchangeUpdate::Book(message.into());
                     if let Err(_send_err) = updates.send((EXCHANGE_NAME, book)).await {
                         log::warn!("[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed");
                         return Ok(());
@@ -75,13 +214,322 @@ impl ExchangeConnection for BinanceConnection {
                 }
                 Err(err) => {
                     log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
-                    return Err(Box::new(err));
+                    return Err(err);
+                }
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Error::ConnectionDropped)
+    }
+
+    /// Follow the partial book streams of several symbols over one connection, forwarding each snapshot along
+    /// its symbol's route.
+    async fn follow_combined_partial_depth(&self, routes: Vec<Route>) -> Result<(), Error> {
+        let streams: Vec<_> = routes
+            .iter()
+            .map(|route| format!("{}@depth20@100ms", route.symbol))
+            .collect();
+        let endpoint = format!(
+            "{}/stream?streams={}",
+            self.websocket_endpoint,
+            streams.join("/")
+        );
+        let (mut stream, _response) = connect_websocket(&endpoint, &self.proxy).await?;
+        for route in routes.iter() {
+            report_subscribed(&route.updates, EXCHANGE_NAME).await;
+        }
+        let mut watchdog = Watchdog::new(self.liveness);
+        let mut sequences = vec![SequenceFilter::default(); routes.len()];
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await? {
+            match read_message::<CombinedMessage<Message>, Error>(maybe_message) {
+                Ok(CombinedMessage { stream, data }) => {
+                    let index = match streams.iter().position(|name| *name == stream) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    if !sequences[index].is_fresh(data.last_update_id) {
+                        log::debug!(
+                            "[{EXCHANGE_NAME}] discarding stale {} book as of update {}",
+                            routes[index].symbol,
+                            data.last_update_id
+                        );
+                        continue;
+                    }
+                    let book = ExchangeUpdate::Book(data.into());
+                    if let Err(_send_err) = routes[index].updates.send((EXCHANGE_NAME, book)).await
+                    {
+                        log::warn!("[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed");
+                        return Ok(());
+                    }
+                }
+                Err(Error::Irrelevant) => {
+                    // noop, we can just ignore that message
+                }
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
                 }
             }
         }
 
         log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
-        Err(Box::new(Error::ConnectionDropped))
+        Err(Error::ConnectionDropped)
+    }
+
+    /// Follow the diff-depth stream, maintaining a local full-depth book.
+    async fn follow_full_depth(
+        &self,
+        symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Error> {
+        let endpoint = format!("{}/ws/{symbol}@depth@100ms", self.websocket_endpoint);
+        let (mut stream, _response) = connect_websocket(&endpoint, &self.proxy).await?;
+        // the stream is named in the URL, so there's no separate subscription to confirm
+        report_subscribed(&updates, EXCHANGE_NAME).await;
+        let mut watchdog = Watchdog::new(self.liveness);
+
+        let client = self
+            .proxy
+            .apply_to(reqwest::Client::builder())
+            .build()
+            .map_err(Error::HttpClient)?;
+        let snapshot_url = format!(
+            "{}/api/v3/depth?symbol={}&limit={SNAPSHOT_LIMIT}",
+            self.rest_endpoint,
+            symbol.to_uppercase()
+        );
+        let mut sync = DiffDepthSync::default();
+
+        // The websocket must already be buffering diffs before we request the snapshot,
+        // otherwise we can't be sure that the diffs will bridge the gap from it.
+        let mut snapshot_request = Some(Box::pin(fetch_snapshot(
+            &client,
+            &snapshot_url,
+            Duration::ZERO,
+        )));
+
+        loop {
+            let update = tokio::select! {
+                snapshot = async {
+                    snapshot_request.as_mut().expect("guarded by precondition").await
+                }, if snapshot_request.is_some() => {
+                    snapshot_request = None;
+                    let snapshot = snapshot?;
+                    log::debug!("[{EXCHANGE_NAME}] received snapshot as of update {}", snapshot.last_update_id);
+                    match sync.on_snapshot(snapshot) {
+                        SyncState::Synchronized => {}
+                        SyncState::Pending => continue,
+                        SyncState::Desynchronized => {
+                            log::warn!("[{EXCHANGE_NAME}] snapshot does not line up with diff stream; fetching another");
+                            snapshot_request = Some(Box::pin(fetch_snapshot(&client, &snapshot_url, SNAPSHOT_RETRY_DELAY)));
+                            continue;
+                        }
+                    }
+                    ExchangeUpdate::Book(sync.top(self.full_depth_len))
+                }
+                next = watchdog.next(&mut stream) => {
+                    let maybe_message = match next? {
+                        Some(maybe_message) => maybe_message,
+                        None => {
+                            log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+                            return Err(Error::ConnectionDropped);
+                        }
+                    };
+                    let update = match read_message::<DepthUpdate, Error>(maybe_message) {
+                        Ok(update) => update,
+                        Err(Error::Irrelevant) => continue,
+                        Err(err) => {
+                            log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                            return Err(err);
+                        }
+                    };
+                    match sync.on_update(update) {
+                        SyncState::Synchronized => ExchangeUpdate::Book(sync.top(self.full_depth_len)),
+                        SyncState::Pending => continue,
+                        SyncState::Desynchronized => {
+                            log::warn!("[{EXCHANGE_NAME}] gap in diff stream; resynchronizing");
+                            if snapshot_request.is_none() {
+                                snapshot_request = Some(Box::pin(fetch_snapshot(&client, &snapshot_url, Duration::ZERO)));
+                            }
+                            ExchangeUpdate::Gap(Gap::Sequence)
+                        }
+                    }
+                }
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, update)).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for BinanceConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("")
+    }
+
+    async fn connect(
+        &self,
+        symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {symbol}");
+
+        let result = match self.mode {
+            BinanceMode::PartialDepth => self.follow_partial_depth(symbol, updates).await,
+            BinanceMode::FullDepth => self.follow_full_depth(symbol, updates).await,
+        };
+        result.map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send>)
+    }
+
+    fn max_symbols_per_connection(&self) -> usize {
+        match self.mode {
+            BinanceMode::PartialDepth => MAX_STREAMS_PER_CONNECTION,
+            // each symbol needs its own snapshots and synchronization, so keep them apart
+            BinanceMode::FullDepth => 1,
+        }
+    }
+
+    async fn connect_many(
+        &self,
+        routes: Vec<Route>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        if routes.len() < 2 || self.mode != BinanceMode::PartialDepth {
+            return connect_each(self, routes).await;
+        }
+        log::trace!(
+            "[{EXCHANGE_NAME}] entered `connect_many` for {} symbols",
+            routes.len()
+        );
+
+        self.follow_combined_partial_depth(routes)
+            .await
+            .map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send>)
+    }
+}
+
+/// Fetch a REST snapshot of the order book after waiting for `delay`.
+async fn fetch_snapshot(
+    client: &reqwest::Client,
+    url: &str,
+    delay: Duration,
+) -> Result<Message, Error> {
+    tokio::time::sleep(delay).await;
+    log::debug!("[{EXCHANGE_NAME}] requesting snapshot from {url}");
+    let snapshot = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(snapshot)
+}
+
+/// Whether the local book currently reflects the exchange's book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncState {
+    /// The local book is up to date.
+    Synchronized,
+    /// We are still waiting for a snapshot; diffs are being buffered.
+    Pending,
+    /// The diffs no longer line up with the local book; a fresh snapshot is required.
+    Desynchronized,
+}
+
+/// Keep a local book synchronized with the diff-depth stream.
+#[derive(Debug, Default)]
+struct DiffDepthSync {
+    book: LocalBook,
+    /// The final update ID reflected in `book`, or `None` if we're awaiting a snapshot.
+    last_update_id: Option<u64>,
+    /// The event time of the last diff applied to `book`, in milliseconds since the Unix epoch.
+    ///
+    /// REST snapshots carry no event time, so this is `None` until a diff has been applied to the snapshot.
+    event_time: Option<u64>,
+    /// The latest diffs received while awaiting a snapshot, at most [`MAX_BUFFERED_DIFFS`] of them.
+    buffer: VecDeque<DepthUpdate>,
+}
+
+impl DiffDepthSync {
+    /// Produce the best levels of the local book, as of the last diff applied.
+    fn top(&self, depth: usize) -> SimpleOrderBook {
+        let book = self.book.top(depth);
+        match self.event_time {
+            Some(event_time) => book.with_exchange_time(from_unix_millis(event_time)),
+            None => book,
+        }
+    }
+
+    fn on_update(&mut self, update: DepthUpdate) -> SyncState {
+        let last_update_id = match self.last_update_id {
+            Some(last_update_id) => last_update_id,
+            None => {
+                self.buffer_update(update);
+                return SyncState::Pending;
+            }
+        };
+
+        // this diff is entirely reflected in what we already have
+        if update.final_update_id <= last_update_id {
+            return SyncState::Synchronized;
+        }
+
+        // each diff must pick up immediately after the last one applied
+        let next_update_id = last_update_id + 1;
+        if update.first_update_id > next_update_id {
+            self.book.clear();
+            self.last_update_id = None;
+            self.buffer_update(update);
+            return SyncState::Desynchronized;
+        }
+
+        self.book.apply_all(Side::Bid, update.bids);
+        self.book.apply_all(Side::Ask, update.asks);
+        self.last_update_id = Some(update.final_update_id);
+        self.event_time = Some(update.event_time);
+        SyncState::Synchronized
+    }
+
+    /// Hold on to a diff until a snapshot arrives, dropping the oldest diff if the buffer is full.
+    fn buffer_update(&mut self, update: DepthUpdate) {
+        if self.buffer.len() >= MAX_BUFFERED_DIFFS {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(update);
+    }
+
+    fn on_snapshot(&mut self, snapshot: Message) -> SyncState {
+        // if the snapshot is older than every diff we have, those diffs can't bring it up to date
+        if let Some(first) = self.buffer.front() {
+            if snapshot.last_update_id + 1 < first.first_update_id {
+                return SyncState::Desynchronized;
+            }
+        }
+
+        self.book.replace(snapshot.bids, snapshot.asks);
+        self.last_update_id = Some(snapshot.last_update_id);
+        self.event_time = None;
+
+        let mut outcome = SyncState::Synchronized;
+        for update in std::mem::take(&mut self.buffer) {
+            // once desynchronized, the remaining diffs are simply buffered for the next snapshot
+            if self.on_update(update) == SyncState::Desynchronized {
+                outcome = SyncState::Desynchronized;
+            }
+        }
+        outcome
     }
 }
 
@@ -91,6 +539,12 @@ pub enum Error {
     Tungstenite(#[from] TungsteniteError),
     #[error("failed to deserialize message")]
     Deserialization(#[from] serde_json::Error),
+    #[error("failed to build HTTP client")]
+    HttpClient(reqwest::Error),
+    #[error("failed to fetch order book snapshot")]
+    Snapshot(#[from] reqwest::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
     #[error("connection dropped by host")]
     ConnectionDropped,
     #[error("message was irrelevant")]
@@ -102,3 +556,247 @@ impl super::Error for Error {
         Error::Irrelevant
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use rust_decimal::Decimal;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+    };
+    use tokio_tungstenite::tungstenite::Message as WebsocketMessage;
+
+    fn level(price: &str, amount: &str) -> AnonymousLevel {
+        AnonymousLevel {
+            price: price.parse().unwrap(),
+            amount: amount.parse().unwrap(),
+        }
+    }
+
+    fn snapshot(last_update_id: u64, bids: Vec<AnonymousLevel>) -> Message {
+        Message {
+            last_update_id,
+            bids,
+            asks: vec![level("0.07036600", "1")],
+        }
+    }
+
+    fn diff(first_update_id: u64, final_update_id: u64, bids: Vec<AnonymousLevel>) -> DepthUpdate {
+        DepthUpdate {
+            event_time: 1648041918792,
+            first_update_id,
+            final_update_id,
+            bids,
+            asks: Vec::new(),
+        }
+    }
+
+    fn bids(sync: &DiffDepthSync) -> Vec<(Decimal, Decimal)> {
+        sync.top(10)
+            .bids
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect()
+    }
+
+    fn entry(price: &str, amount: &str) -> (Decimal, Decimal) {
+        (price.parse().unwrap(), amount.parse().unwrap())
+    }
+
+    #[test]
+    fn diffs_are_buffered_until_the_snapshot_arrives() {
+        let mut sync = DiffDepthSync::default();
+        assert_eq!(
+            sync.on_update(diff(101, 102, vec![level("0.07036500", "2")])),
+            SyncState::Pending
+        );
+        assert_eq!(
+            sync.on_snapshot(snapshot(100, vec![level("0.07036500", "1")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(bids(&sync), vec![entry("0.07036500", "2")]);
+        assert_eq!(sync.last_update_id, Some(102));
+    }
+
+    #[test]
+    fn snapshot_older_than_the_buffered_diffs_is_rejected() {
+        let mut sync = DiffDepthSync::default();
+        sync.on_update(diff(110, 112, vec![level("0.07036500", "2")]));
+        assert_eq!(
+            sync.on_snapshot(snapshot(100, vec![level("0.07036500", "1")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(sync.last_update_id, None);
+        assert_eq!(sync.buffer.len(), 1);
+
+        // a fresh snapshot which the buffered diff follows on from is accepted
+        assert_eq!(
+            sync.on_snapshot(snapshot(109, vec![level("0.07036500", "1")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(bids(&sync), vec![entry("0.07036500", "2")]);
+    }
+
+    #[test]
+    fn stale_diffs_are_dropped() {
+        let mut sync = DiffDepthSync::default();
+        sync.on_update(diff(95, 98, vec![level("0.07036400", "5")]));
+        sync.on_update(diff(99, 100, vec![level("0.07036300", "5")]));
+        sync.on_update(diff(100, 101, vec![level("0.07036500", "3")]));
+        assert_eq!(
+            sync.on_snapshot(snapshot(100, vec![level("0.07036500", "1")])),
+            SyncState::Synchronized
+        );
+        // only the diff which straddles the snapshot is applied
+        assert_eq!(bids(&sync), vec![entry("0.07036500", "3")]);
+        assert!(sync.buffer.is_empty());
+
+        // once synchronized, diffs which are already reflected in the book are ignored
+        assert_eq!(
+            sync.on_update(diff(90, 101, vec![level("0.07036500", "7")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(bids(&sync), vec![entry("0.07036500", "3")]);
+    }
+
+    #[test]
+    fn gap_forces_a_resync() {
+        let mut sync = DiffDepthSync::default();
+        sync.on_snapshot(snapshot(100, vec![level("0.07036500", "1")]));
+        assert_eq!(
+            sync.on_update(diff(101, 102, vec![level("0.07036500", "2")])),
+            SyncState::Synchronized
+        );
+
+        // update 103 went missing
+        assert_eq!(
+            sync.on_update(diff(104, 105, vec![level("0.07036400", "4")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(sync.last_update_id, None);
+        assert!(bids(&sync).is_empty());
+        assert_eq!(
+            sync.on_update(diff(106, 106, vec![level("0.07036300", "6")])),
+            SyncState::Pending
+        );
+
+        assert_eq!(
+            sync.on_snapshot(snapshot(105, vec![level("0.07036500", "1")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            bids(&sync),
+            vec![entry("0.07036500", "1"), entry("0.07036300", "6")]
+        );
+        assert_eq!(sync.last_update_id, Some(106));
+    }
+
+    #[test]
+    fn buffer_is_capped() {
+        let mut sync = DiffDepthSync::default();
+        for id in 1..=MAX_BUFFERED_DIFFS as u64 + 10 {
+            sync.on_update(diff(id, id, Vec::new()));
+        }
+        assert_eq!(sync.buffer.len(), MAX_BUFFERED_DIFFS);
+        assert_eq!(sync.buffer.front().unwrap().first_update_id, 11);
+
+        // the oldest diffs are gone, so a snapshot which needed them is too old
+        assert_eq!(
+            sync.on_snapshot(snapshot(5, Vec::new())),
+            SyncState::Desynchronized
+        );
+        assert_eq!(
+            sync.on_snapshot(snapshot(10, Vec::new())),
+            SyncState::Synchronized
+        );
+    }
+
+    /// Serve a single REST snapshot, reporting the request line.
+    async fn serve_snapshot(listener: TcpListener, body: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        let request = String::from_utf8(request).unwrap();
+        request.lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn full_depth_against_mock_server() {
+        let websocket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connection = BinanceConnection::new(BinanceMode::FullDepth)
+            .with_websocket_endpoint(format!("ws://{}", websocket.local_addr().unwrap()))
+            .with_rest_endpoint(format!("http://{}", rest.local_addr().unwrap()))
+            .with_proxy(ProxyConfig::Direct);
+
+        // send the diffs only once the snapshot has been served, so that they can't all arrive before it is requested
+        let (served, snapshot_served) = oneshot::channel();
+        tokio::spawn(async move {
+            let request_line = serve_snapshot(
+                rest,
+                r#"{"lastUpdateId":100,"bids":[["0.07036500","1.00000000"]],"asks":[["0.07036600","1.00000000"]]}"#,
+            )
+            .await;
+            let _ = served.send(request_line);
+        });
+        let (request_line_sender, request_line) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = websocket.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = request_line_sender.send(snapshot_served.await.unwrap());
+            for diff in [
+                r#"{"e":"depthUpdate","E":1648041918792,"s":"ETHBTC","U":99,"u":100,"b":[["0.07036500","9.00000000"]],"a":[]}"#,
+                r#"{"e":"depthUpdate","E":1648041918892,"s":"ETHBTC","U":101,"u":102,"b":[["0.07036500","2.00000000"]],"a":[]}"#,
+            ] {
+                stream
+                    .send(WebsocketMessage::Text(diff.to_string()))
+                    .await
+                    .unwrap();
+            }
+            // hold the connection open until the test is done with it
+            futures::future::pending::<()>().await;
+        });
+
+        let (updates, mut received) = mpsc::channel(16);
+        tokio::spawn(async move { connection.connect("ethbtc".to_string(), updates).await });
+
+        // the snapshot may be read before or after the diffs, but either way only the second diff applies to it
+        let book = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (name, update) = received.recv().await.expect("the connection is running");
+                assert_eq!(name, EXCHANGE_NAME);
+                match update {
+                    ExchangeUpdate::Book(book) if book.exchange_time.is_some() => break book,
+                    ExchangeUpdate::Gap(_) => panic!("the diffs follow on from the snapshot"),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("the connection should apply the diff to the snapshot");
+
+        assert_eq!(
+            request_line.await.unwrap(),
+            "GET /api/v3/depth?symbol=ETHBTC&limit=5000 HTTP/1.1"
+        );
+        let levels = |levels: &[AnonymousLevel]| -> Vec<_> {
+            levels
+                .iter()
+                .map(|level| (level.price, level.amount))
+                .collect()
+        };
+        assert_eq!(levels(&book.bids), vec![entry("0.07036500", "2")]);
+        assert_eq!(levels(&book.asks), vec![entry("0.07036600", "1")]);
+        assert_eq!(book.exchange_time, Some(from_unix_millis(1648041918892)));
+    }
+}
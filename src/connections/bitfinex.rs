@@ -0,0 +1,546 @@
+//! Connection implementation for Bitfinex.
+//!
+//! Bitfinex's v2 websocket API mixes two styles of message. Control messages, such as subscription
+//! confirmations, are JSON objects distinguished by their `event` field. Channel data are JSON arrays,
+//! whose first element is the numeric ID of the channel, as assigned in the subscription confirmation.
+//!
+//! On the `book` channel, the first data message is a snapshot: an array of `[price, count, amount]`
+//! levels. Subsequent messages are single levels in the same format. A negative amount indicates an ask;
+//! a count of 0 indicates that the level should be deleted, in which case the amount is `1` for bids and
+//! `-1` for asks. The channel also carries heartbeats (`"hb"`) and, when the `OB_CHECKSUM` flag has been
+//! configured, checksums (`"cs"`) of the top 25 levels on each side, which we use to validate our local book.
+//!
+//! Example data:
+//!
+//! ```json
+//! {"event":"info","version":2,"serverId":"3b9c5ac6-ac8a-4e8e-9b0a-0a4ec8b9a2ab","platform":{"status":1}}
+//! {"event":"conf","status":"OK","flags":131072}
+//! {"event":"subscribed","channel":"book","chanId":10961,"symbol":"tETHBTC","prec":"P0","freq":"F0","len":"25","pair":"ETHBTC"}
+//! [10961,[[0.07012,2,6.5],[0.07011,1,1.26],[0.07015,1,-0.05],[0.07016,3,-1.6864241]]]
+//! [10961,[0.07012,0,1]]
+//! [10961,"cs",858946642]
+//! [10961,"hb"]
+//! ```
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Gap, Liveness, Silent,
+    Watchdog,
+};
+use crate::{AnonymousLevel, LocalBook, Pair, Side, StringDecimal};
+use futures::SinkExt;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::de::{Error as _, IgnoredAny, SeqAccess};
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+const EXCHANGE_NAME: &str = "bitfinex";
+
+/// Default websocket endpoint for Bitfinex's public v2 API.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+/// The default number of levels per side to which we subscribe.
+pub const DEFAULT_DEPTH: usize = 25;
+
+/// The configuration flag which enables book checksums.
+const OB_CHECKSUM: u32 = 131072;
+
+/// The checksum covers this many levels on each side of the book.
+const CHECKSUM_LEN: usize = 25;
+
+/// Bitfinex's names for some currencies, as `(canonical, bitfinex)` pairs.
+const ALIASES: &[(&str, &str)] = &[
+    ("usdt", "ust"),
+    ("dash", "dsh"),
+    ("iota", "iot"),
+    ("qtum", "qtm"),
+];
+
+/// Message type for Bitfinex's v2 websocket API.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Event(Event),
+    Channel(ChannelMessage),
+}
+
+/// Control messages.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Subscribed {
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+    },
+    Error {
+        msg: String,
+        code: Option<i64>,
+    },
+    /// Info, configuration confirmations, and so on.
+    #[serde(other)]
+    Other,
+}
+
+/// Data published on a channel.
+#[derive(Debug)]
+struct ChannelMessage {
+    chan_id: u64,
+    payload: Payload,
+}
+
+#[derive(Debug)]
+enum Payload {
+    Heartbeat,
+    Checksum(i32),
+    Snapshot(Vec<BookEntry>),
+    Update(BookEntry),
+}
+
+/// The second element of a channel message, which determines how the rest is interpreted.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum PayloadHead {
+    Keyword(String),
+    Snapshot(Vec<BookEntry>),
+    Update(BookEntry),
+}
+
+/// A single level: price, count of orders, and amount.
+///
+/// The amount is negative for asks.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+struct BookEntry(StringDecimal, u64, StringDecimal);
+
+impl BookEntry {
+    /// Which side of the book this entry applies to, and the level to apply there.
+    fn into_level(self) -> (Side, AnonymousLevel) {
+        let BookEntry(StringDecimal(price), count, StringDecimal(amount)) = self;
+        let side = if amount > Decimal::ZERO {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        // a count of 0 means delete, which for our local book is an amount of 0
+        let amount = if count == 0 {
+            Decimal::ZERO
+        } else {
+            amount.abs()
+        };
+        (side, AnonymousLevel { price, amount })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ChannelMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ChannelMessage;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("array of channel ID and payload")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let chan_id = seq
+                    .next_element::<u64>()?
+                    .ok_or_else(|| A::Error::missing_field("chan_id"))?;
+                let head = seq
+                    .next_element::<PayloadHead>()?
+                    .ok_or_else(|| A::Error::missing_field("payload"))?;
+
+                let payload = match head {
+                    PayloadHead::Keyword(keyword) if keyword == "hb" => Payload::Heartbeat,
+                    PayloadHead::Keyword(keyword) if keyword == "cs" => Payload::Checksum(
+                        seq.next_element()?
+                            .ok_or_else(|| A::Error::missing_field("checksum"))?,
+                    ),
+                    PayloadHead::Keyword(keyword) => {
+                        return Err(A::Error::unknown_variant(&keyword, &["hb", "cs"]))
+                    }
+                    PayloadHead::Snapshot(entries) => Payload::Snapshot(entries),
+                    PayloadHead::Update(entry) => Payload::Update(entry),
+                };
+
+                // additional elements, such as sequence numbers, may be present depending on configuration
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+
+                Ok(ChannelMessage { chan_id, payload })
+            }
+        }
+
+        deserializer.deserialize_seq(Visitor)
+    }
+}
+
+/// Manage a websocket connection to Bitfinex.
+#[derive(Debug, Clone)]
+pub struct BitfinexConnection {
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    depth: usize,
+    validate_checksums: bool,
+}
+
+impl Default for BitfinexConnection {
+    fn default() -> Self {
+        BitfinexConnection {
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            depth: DEFAULT_DEPTH,
+            validate_checksums: true,
+        }
+    }
+}
+
+impl BitfinexConnection {
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Subscribe to a different number of levels per side.
+    ///
+    /// Bitfinex accepts 1, 25, 100, and 250.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Enable or disable validation of the local book against Bitfinex's checksums.
+    ///
+    /// This is enabled by default.
+    pub fn with_checksum_validation(mut self, validate_checksums: bool) -> Self {
+        self.validate_checksums = validate_checksums;
+        self
+    }
+
+    fn subscription_request(&self, bitfinex_symbol: &str) -> TungsteniteMessage {
+        TungsteniteMessage::Text(
+            serde_json::json!({
+                "event": "subscribe",
+                "channel": "book",
+                "symbol": bitfinex_symbol,
+                "prec": "P0",
+                "freq": "F0",
+                "len": self.depth.to_string(),
+            })
+            .to_string(),
+        )
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for BitfinexConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    /// Bitfinex prefixes trading pairs with `t`, uses its own names for some currencies, and separates
+    /// the currencies with a colon when either is longer than three characters, e.g. `tDOGE:USD`.
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        let pair = pair.with_aliases(ALIASES.iter().copied());
+        let separator = if pair.base.len() > 3 || pair.quote.len() > 3 {
+            ":"
+        } else {
+            ""
+        };
+        format!("t{}", pair.join(separator).to_uppercase())
+    }
+
+    async fn connect(
+        &self,
+        bitfinex_symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {bitfinex_symbol}");
+
+        let (mut stream, _response) = connect_websocket(&self.websocket_endpoint, &self.proxy)
+            .await
+            .map_err(into_box)?;
+        let mut watchdog = Watchdog::new(self.liveness);
+        if self.validate_checksums {
+            let conf_request = serde_json::json!({ "event": "conf", "flags": OB_CHECKSUM });
+            stream
+                .send(TungsteniteMessage::Text(conf_request.to_string()))
+                .await
+                .map_err(into_box)?;
+        }
+        stream
+            .send(self.subscription_request(&bitfinex_symbol))
+            .await
+            .map_err(into_box)?;
+
+        let mut chan_id = None;
+        let mut book = LocalBook::default();
+        // we only apply updates once we've received the snapshot which they update
+        let mut have_snapshot = false;
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await.map_err(into_box)? {
+            let payload = match read_message::<Message, Error>(maybe_message) {
+                Ok(Message::Event(Event::Subscribed { chan_id: id })) => {
+                    chan_id = Some(id);
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Ok(Message::Event(Event::Error { msg, code })) => {
+                    let err = Error::Exchange(format!("{msg} ({})", code.unwrap_or_default()));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+                Ok(Message::Channel(message)) if Some(message.chan_id) == chan_id => {
+                    message.payload
+                }
+                Ok(_) | Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+            };
+
+            let update = match payload {
+                Payload::Heartbeat => continue,
+                Payload::Snapshot(entries) => {
+                    book.clear();
+                    for entry in entries {
+                        let (side, level) = entry.into_level();
+                        book.apply(side, level);
+                    }
+                    have_snapshot = true;
+                    ExchangeUpdate::Book(book.top(self.depth))
+                }
+                Payload::Update(entry) if have_snapshot => {
+                    let (side, level) = entry.into_level();
+                    book.apply(side, level);
+                    ExchangeUpdate::Book(book.top(self.depth))
+                }
+                Payload::Update(_) => continue,
+                Payload::Checksum(expected) => {
+                    if !self.validate_checksums || !have_snapshot {
+                        continue;
+                    }
+                    let computed = checksum(&book);
+                    if computed == expected {
+                        // the checksum doesn't change the book, so there's nothing new to send
+                        continue;
+                    }
+
+                    log::warn!(
+                        "[{EXCHANGE_NAME}] checksum mismatch (expected {expected}, computed {computed}); resubscribing"
+                    );
+                    book.clear();
+                    have_snapshot = false;
+                    let unsubscribe_request = serde_json::json!({
+                        "event": "unsubscribe",
+                        "chanId": chan_id.take(),
+                    });
+                    stream
+                        .send(TungsteniteMessage::Text(unsubscribe_request.to_string()))
+                        .await
+                        .map_err(into_box)?;
+                    stream
+                        .send(self.subscription_request(&bitfinex_symbol))
+                        .await
+                        .map_err(into_box)?;
+                    ExchangeUpdate::Gap(Gap::Checksum)
+                }
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, update)).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Box::new(Error::ConnectionDropped))
+    }
+}
+
+/// Compute Bitfinex's CRC32 checksum of the top of the book.
+///
+/// The top 25 bids and asks are interleaved, bid first, as `price:amount` with asks' amounts negative,
+/// and all are joined with colons. The checksum is interpreted as a signed integer.
+fn checksum(book: &LocalBook) -> i32 {
+    let mut bids = book.bids().take(CHECKSUM_LEN);
+    let mut asks = book.asks().take(CHECKSUM_LEN);
+    let mut fields = Vec::with_capacity(4 * CHECKSUM_LEN);
+    loop {
+        let bid = bids.next();
+        let ask = asks.next();
+        if bid.is_none() && ask.is_none() {
+            break;
+        }
+        if let Some(bid) = bid {
+            fields.push(js_number(bid.price));
+            fields.push(js_number(bid.amount));
+        }
+        if let Some(ask) = ask {
+            fields.push(js_number(ask.price));
+            fields.push(js_number(-ask.amount));
+        }
+    }
+    crc32fast::hash(fields.join(":").as_bytes()) as i32
+}
+
+/// Format a number the way JavaScript does, which is how Bitfinex formats numbers for its checksums.
+///
+/// Bitfinex sends numbers rather than strings, so they're formatted as the floats which it sent. Rust and
+/// JavaScript agree on the shortest representation which round-trips, except that JavaScript switches to
+/// exponential notation for very small and very large numbers, and always signs a large exponent.
+fn js_number(value: Decimal) -> String {
+    let value = value.to_f64().unwrap_or_default();
+    if value == 0.0 {
+        // including negative zero, which JavaScript prints unsigned
+        "0".to_string()
+    } else if value.abs() < 1e-6 {
+        format!("{value:e}")
+    } else if value.abs() >= 1e21 {
+        format!("{value:e}").replace('e', "e+")
+    } else {
+        format!("{value}")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("exchange reported an error: {0}")]
+    Exchange(String),
+    #[error("websocket problem")]
+    Tungstenite(#[from] TungsteniteError),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(levels: &[(&str, &str)]) -> Vec<AnonymousLevel> {
+        levels
+            .iter()
+            .map(|(price, amount)| AnonymousLevel {
+                price: price.parse().unwrap(),
+                amount: amount.parse().unwrap(),
+            })
+            .collect()
+    }
+
+    fn js(value: &str) -> String {
+        js_number(value.parse().unwrap())
+    }
+
+    #[test]
+    fn js_number_formats_like_javascript() {
+        assert_eq!(js("6.50000000"), "6.5");
+        assert_eq!(js("1.0"), "1");
+        assert_eq!(js("-0.05000"), "-0.05");
+        assert_eq!(js("0.000001"), "0.000001");
+        assert_eq!(js("0.0000001"), "1e-7");
+        assert_eq!(js("-0.00000015"), "-1.5e-7");
+        assert_eq!(js("123456789012345678901"), "123456789012345680000");
+        assert_eq!(js("1000000000000000000000"), "1e+21");
+        assert_eq!(js("-2500000000000000000000"), "-2.5e+21");
+        assert_eq!(js("0.00000000"), "0");
+        assert_eq!(js("-0"), "0");
+    }
+
+    /// As Bitfinex's guide describes: bids and asks interleaved, best first, as `price:amount` with asks' amounts
+    /// negative.
+    #[test]
+    fn checksum_follows_documented_format() {
+        let mut book = LocalBook::default();
+        book.replace(
+            levels(&[("0.07011", "1.26"), ("0.07012", "6.50000000")]),
+            levels(&[
+                ("0.07016", "1.6864241"),
+                ("0.07015", "0.05"),
+                ("0.07017", "0.0000001"),
+            ]),
+        );
+        let expected = "0.07012:6.5:0.07015:-0.05:0.07011:1.26:0.07016:-1.6864241:0.07017:-1e-7";
+        assert_eq!(checksum(&book), crc32fast::hash(expected.as_bytes()) as i32);
+    }
+
+    #[test]
+    fn checksum_covers_only_the_top_levels() {
+        let bids: Vec<_> = (0..30)
+            .map(|i| AnonymousLevel {
+                price: Decimal::new(7000 - i, 5),
+                amount: Decimal::ONE,
+            })
+            .collect();
+        let asks: Vec<_> = (0..30)
+            .map(|i| AnonymousLevel {
+                price: Decimal::new(7100 + i, 5),
+                amount: Decimal::ONE,
+            })
+            .collect();
+
+        let mut deep = LocalBook::default();
+        deep.replace(bids.clone(), asks.clone());
+        let mut shallow = LocalBook::default();
+        shallow.replace(
+            bids.into_iter().take(CHECKSUM_LEN),
+            asks.into_iter().take(CHECKSUM_LEN),
+        );
+        assert_eq!(checksum(&deep), checksum(&shallow));
+    }
+
+    /// The snapshot, update, and checksum in the module documentation.
+    #[test]
+    fn example_checksum() {
+        let mut book = LocalBook::default();
+        book.replace(
+            levels(&[("0.07012", "6.5"), ("0.07011", "1.26")]),
+            levels(&[("0.07015", "0.05"), ("0.07016", "1.6864241")]),
+        );
+        assert_eq!(checksum(&book), 176426331);
+        book.apply(
+            Side::Bid,
+            AnonymousLevel {
+                price: "0.07012".parse().unwrap(),
+                amount: Decimal::ZERO,
+            },
+        );
+        assert_eq!(checksum(&book), 858946642);
+    }
+}
@@ -2,10 +2,15 @@
 //!
 //! The bitsteamp websocket endpoint requires a two-step connection protocol:
 //! first connect to the general-purpose endpoint, then register for the desired
-//! stream(s).
+//! stream(s). Data only counts once Bitstamp has confirmed the subscription; any other
+//! messages which arrive before then are ignored, and a `bts:error` aborts the connection.
 //!
-//! This also means that we need to be able to handle, at least, messages whose
-//! `data` field is empty.
+//! Bitstamp occasionally sends `bts:request_reconnect` before closing a connection for maintenance.
+//! We then open a replacement connection alongside the old one, and switch over once the replacement's
+//! data overlaps the old connection's, so that nothing is missed in between.
+//!
+//! In [snapshot mode][BitstampMode::Snapshot], several symbols can share a connection: each `order_book` channel
+//! is subscribed to over the same websocket, and its data routed by the `channel` field of each message.
 //!
 //! Example data:
 //!
@@ -14,41 +19,350 @@
 //! {"data":{"timestamp":"1648041918","microtimestamp":"1648041918792209","bids":[["0.07010256","6.00000000"],["0.07008689","0.50000000"],["0.07008637","6.32274318"],["0.07007691","0.01531744"],["0.07007446","1.01185446"],["0.07007082","0.51407345"],["0.07006279","0.01514769"],["0.07006161","6.10000000"],["0.07005844","1.68586537"],["0.07005579","0.50000000"],["0.07005091","0.49978378"],["0.07004834","0.01513734"],["0.07004770","1.58000000"],["0.07003947","1.68592503"],["0.07002884","0.07953299"],["0.07002014","1.68650657"],["0.07002013","1.68642410"],["0.07001690","3.82000000"],["0.07000000","77.39020000"],["0.06998968","2.32992865"],["0.06998967","12.20000000"],["0.06998682","1.68590811"],["0.06990690","7.71000000"],["0.06987283","1.29680259"],["0.06987282","1.00000000"],["0.06986310","0.00289034"],["0.06984601","2.40889409"],["0.06984600","0.00289031"],["0.06982890","0.00289028"],["0.06981180","0.00289025"],["0.06979646","42.80000000"],["0.06979470","0.00289022"],["0.06978330","0.00289020"],["0.06977760","0.00289019"],["0.06976620","0.00290017"],["0.06976050","0.00290016"],["0.06974910","0.00290014"],["0.06974340","0.00290013"],["0.06973200","0.00290011"],["0.06972630","0.00290010"],["0.06971490","0.00290008"],["0.06970920","0.00290007"],["0.06969780","0.00290005"],["0.06969210","0.00290004"],["0.06968640","0.00290003"],["0.06968070","0.00290002"],["0.06967284","0.00291544"],["0.06966696","0.00291543"],["0.06966108","0.00291542"],["0.06965520","0.00291541"],["0.06964932","0.00291540"],["0.06964452","38.98000000"],["0.06964344","0.00291539"],["0.06964076","1.00000000"],["0.06963756","0.00291538"],["0.06963657","0.05004533"],["0.06963168","0.00291537"],["0.06962580","0.00291536"],["0.06961992","0.00291535"],["0.06961404","0.00291534"],["0.06960816","0.00291533"],["0.06960228","0.00291532"],["0.06959640","0.00291531"],["0.06959052","0.00291530"],["0.06958464","0.00291529"],["0.06957876","0.00291528"],["0.06957288","0.00291527"],["0.06956700","0.00291526"],["0.06956112","0.00291525"],["0.06955524","0.00291524"],["0.06954936","0.00291523"],["0.06954348","0.00291522"],["0.06953864","12.81225159"],["0.06953760","0.00291521"],["0.06953172","0.00292520"],["0.06952584","0.00114961"],["0.06951996","0.00292518"],["0.06951408","0.00292517"],["0.06950820","0.00292516"],["0.06950232","0.00292515"],["0.06950000","0.20258028"],["0.06949644","0.00292514"],["0.06949056","0.00292513"],["0.06948468","0.00292512"],["0.06947880","0.00292511"],["0.06947292","0.00292510"],["0.06946704","0.00292509"],["0.06946116","0.00292508"],["0.06945528","0.00292507"],["0.06944940","0.00292506"],["0.06944598","0.05018267"],["0.06944352","0.00292505"],["0.06943764","0.00292504"],["0.06943176","0.00292503"],["0.06942588","0.00292502"],["0.06942000","0.00292501"],["0.06941413","39.63691859"],["0.06941412","0.00292500"],["0.06940824","0.00292499"],["0.06940236","0.00292498"]],"asks":[["0.07015025","0.05000000"],["0.07015316","1.55000000"],["0.07015568","0.51323872"],["0.07015583","1.01162417"],["0.07016010","1.68642410"],["0.07016812","0.01536420"],["0.07016982","0.51070720"],["0.07017037","0.50000000"],["0.07018397","1.68588769"],["0.07018498","0.52158470"],["0.07018743","6.10000000"],["0.07018781","0.71315969"],["0.07020350","1.68599472"],["0.07020363","0.01490495"],["0.07020718","1.68652480"],["0.07021680","1.58000000"],["0.07022117","9.99004001"],["0.07022178","0.01559306"],["0.07023210","3.82000000"],["0.07024020","0.00289037"],["0.07025154","1.00000000"],["0.07025665","1.68563625"],["0.07025730","0.00289040"],["0.07027440","0.00289043"],["0.07027749","12.20000000"],["0.07029000","61.52120000"],["0.07029150","0.00289046"],["0.07030860","0.00289049"],["0.07032260","7.71000000"],["0.07032569","0.37035722"],["0.07032570","0.00289052"],["0.07034278","2.97712188"],["0.07034279","0.92605867"],["0.07034280","0.00289055"],["0.07035987","2.40771035"],["0.07035990","0.00289058"],["0.07037700","0.00289061"],["0.07039410","0.00288064"],["0.07040796","48.60000000"],["0.07041120","0.00288067"],["0.07042830","0.00288070"],["0.07043040","0.00289023"],["0.07044540","0.00288073"],["0.07044750","0.00289026"],["0.07045050","39.94000000"],["0.07046250","0.00288076"],["0.07046460","0.00289029"],["0.07047202","1.00000000"],["0.07047960","0.00288079"],["0.07048170","0.00289032"],["0.07049670","0.00288082"],["0.07049880","0.00289035"],["0.07051380","0.00288085"],["0.07051590","0.00289038"],["0.07053090","0.00288088"],["0.07053300","0.00289041"],["0.07054800","0.00288091"],["0.07055010","0.00289044"],["0.07056510","0.00288094"],["0.07056720","0.00289047"],["0.07056968","7.32613355"],["0.07058220","0.00288097"],["0.07058430","0.00289050"],["0.07059930","0.00288100"],["0.07060140","0.00289053"],["0.07061640","0.00288103"],["0.07061850","0.00289056"],["0.07063350","0.00287106"],["0.07063560","0.00289059"],["0.07065060","0.00287109"],["0.07065270","0.00288062"],["0.07066350","0.00290006"],["0.07066770","0.00287112"],["0.07066980","0.00288065"],["0.07068060","0.00290009"],["0.07068480","0.00287115"],["0.07068690","0.00288068"],["0.07069203","1.00000000"],["0.07069770","0.00290012"],["0.07070190","0.00287118"],["0.07070400","0.00288071"],["0.07071480","0.00290015"],["0.07071758","0.04990835"],["0.07071900","0.00287121"],["0.07072110","0.00288074"],["0.07073190","0.00290018"],["0.07073610","0.00287124"],["0.07073820","0.00288077"],["0.07074900","0.00289021"],["0.07075320","0.00287127"],["0.07075530","0.00288080"],["0.07076610","0.00289024"],["0.07077030","0.00287130"],["0.07077240","0.00288083"],["0.07078320","0.00289027"],["0.07078740","0.00287133"],["0.07078950","0.00288086"],["0.07080030","0.00289030"],["0.07080450","0.00287136"],["0.07080660","0.00288089"]]},"channel":"order_book_ethbtc","event":"data"}
 //! {"data":{"timestamp":"1648041919","microtimestamp":"1648041919391460","bids":[["0.07010256","6.00000000"],["0.07008637","6.32274318"],["0.07007763","0.50000000"],["0.07007691","0.01531744"],["0.07007446","1.01185446"],["0.07007082","0.51407345"],["0.07006279","0.01514769"],["0.07006161","6.10000000"],["0.07005844","1.68586537"],["0.07005579","0.50000000"],["0.07005091","0.49978378"],["0.07004834","0.01513734"],["0.07004770","1.58000000"],["0.07003947","1.68592503"],["0.07002884","0.07953299"],["0.07002014","1.68650657"],["0.07002013","1.68642410"],["0.07001690","3.82000000"],["0.07000000","77.39020000"],["0.06998968","2.32992865"],["0.06998967","12.20000000"],["0.06998682","1.68590811"],["0.06990690","7.71000000"],["0.06987283","1.29680259"],["0.06987282","1.00000000"],["0.06986310","0.00289034"],["0.06984601","2.40889409"],["0.06984600","0.00289031"],["0.06982890","0.00289028"],["0.06981180","0.00289025"],["0.06979646","42.80000000"],["0.06979470","0.00289022"],["0.06978330","0.00289020"],["0.06977760","0.00289019"],["0.06976620","0.00290017"],["0.06976050","0.00290016"],["0.06974910","0.00290014"],["0.06974340","0.00290013"],["0.06973200","0.00290011"],["0.06972630","0.00290010"],["0.06971490","0.00290008"],["0.06970920","0.00290007"],["0.06969780","0.00290005"],["0.06969210","0.00290004"],["0.06968640","0.00290003"],["0.06968070","0.00290002"],["0.06967284","0.00291544"],["0.06966696","0.00291543"],["0.06966108","0.00291542"],["0.06965520","0.00291541"],["0.06964932","0.00291540"],["0.06964452","38.98000000"],["0.06964344","0.00291539"],["0.06964076","1.00000000"],["0.06963756","0.00291538"],["0.06963657","0.05004533"],["0.06963168","0.00291537"],["0.06962580","0.00291536"],["0.06961992","0.00291535"],["0.06961404","0.00291534"],["0.06960816","0.00291533"],["0.06960228","0.00291532"],["0.06959640","0.00291531"],["0.06959052","0.00291530"],["0.06958464","0.00291529"],["0.06957876","0.00291528"],["0.06957288","0.00291527"],["0.06956700","0.00291526"],["0.06956112","0.00291525"],["0.06955524","0.00291524"],["0.06954936","0.00291523"],["0.06954348","0.00291522"],["0.06953864","12.81225159"],["0.06953760","0.00291521"],["0.06953172","0.00292520"],["0.06952584","0.00114961"],["0.06951996","0.00292518"],["0.06951408","0.00292517"],["0.06950820","0.00292516"],["0.06950232","0.00292515"],["0.06950000","0.20258028"],["0.06949644","0.00292514"],["0.06949056","0.00292513"],["0.06948468","0.00292512"],["0.06947880","0.00292511"],["0.06947292","0.00292510"],["0.06946704","0.00292509"],["0.06946116","0.00292508"],["0.06945528","0.00292507"],["0.06944940","0.00292506"],["0.06944598","0.05018267"],["0.06944352","0.00292505"],["0.06943764","0.00292504"],["0.06943176","0.00292503"],["0.06942588","0.00292502"],["0.06942000","0.00292501"],["0.06941413","39.63691859"],["0.06941412","0.00292500"],["0.06940824","0.00292499"],["0.06940236","0.00292498"]],"asks":[["0.07015025","0.05000000"],["0.07015316","1.55000000"],["0.07015568","0.51323872"],["0.07015583","1.01162417"],["0.07016010","1.68642410"],["0.07016110","0.50000000"],["0.07016812","0.01536420"],["0.07016982","0.51070720"],["0.07018397","1.68588769"],["0.07018498","0.52158470"],["0.07018743","6.10000000"],["0.07018781","0.71315969"],["0.07020350","1.68599472"],["0.07020363","0.01490495"],["0.07020718","1.68652480"],["0.07021680","1.58000000"],["0.07022117","9.99004001"],["0.07022178","0.01559306"],["0.07023210","3.82000000"],["0.07024020","0.00289037"],["0.07025154","1.00000000"],["0.07025665","1.68563625"],["0.07025730","0.00289040"],["0.07027440","0.00289043"],["0.07027749","12.20000000"],["0.07029000","61.52120000"],["0.07029150","0.00289046"],["0.07030860","0.00289049"],["0.07032260","7.71000000"],["0.07032569","0.37035722"],["0.07032570","0.00289052"],["0.07034278","2.97712188"],["0.07034279","0.92605867"],["0.07034280","0.00289055"],["0.07035987","2.40771035"],["0.07035990","0.00289058"],["0.07037700","0.00289061"],["0.07039410","0.00288064"],["0.07040796","48.60000000"],["0.07041120","0.00288067"],["0.07042830","0.00288070"],["0.07043040","0.00289023"],["0.07044540","0.00288073"],["0.07044750","0.00289026"],["0.07045050","39.94000000"],["0.07046250","0.00288076"],["0.07046460","0.00289029"],["0.07047202","1.00000000"],["0.07047960","0.00288079"],["0.07048170","0.00289032"],["0.07049670","0.00288082"],["0.07049880","0.00289035"],["0.07051380","0.00288085"],["0.07051590","0.00289038"],["0.07053090","0.00288088"],["0.07053300","0.00289041"],["0.07054800","0.00288091"],["0.07055010","0.00289044"],["0.07056510","0.00288094"],["0.07056720","0.00289047"],["0.07056968","7.32613355"],["0.07058220","0.00288097"],["0.07058430","0.00289050"],["0.07059930","0.00288100"],["0.07060140","0.00289053"],["0.07061640","0.00288103"],["0.07061850","0.00289056"],["0.07063350","0.00287106"],["0.07063560","0.00289059"],["0.07065060","0.00287109"],["0.07065270","0.00288062"],["0.07066350","0.00290006"],["0.07066770","0.00287112"],["0.07066980","0.00288065"],["0.07068060","0.00290009"],["0.07068480","0.00287115"],["0.07068690","0.00288068"],["0.07069203","1.00000000"],["0.07069770","0.00290012"],["0.07070190","0.00287118"],["0.07070400","0.00288071"],["0.07071480","0.00290015"],["0.07071758","0.04990835"],["0.07071900","0.00287121"],["0.07072110","0.00288074"],["0.07073190","0.00290018"],["0.07073610","0.00287124"],["0.07073820","0.00288077"],["0.07074900","0.00289021"],["0.07075320","0.00287127"],["0.07075530","0.00288080"],["0.07076610","0.00289024"],["0.07077030","0.00287130"],["0.07077240","0.00288083"],["0.07078320","0.00289027"],["0.07078740","0.00287133"],["0.07078950","0.00288086"],["0.07080030","0.00289030"],["0.07080450","0.00287136"],["0.07080660","0.00288089"]]},"channel":"order_book_ethbtc","event":"data"}
 //! ```
+//!
+//! In [incremental mode][BitstampMode::Incremental], we instead subscribe to the `diff_order_book` channel, which
+//! sends only the levels which have changed, and apply those changes to a local book initialized from a REST snapshot.
+//! Bitstamp diffs carry no sequence numbers, so `microtimestamp` is used to discard diffs which predate the snapshot,
+//! and to detect diffs arriving out of order, in which case the local book is resynchronized from a fresh snapshot.
+//!
+//! Example diff data:
+//!
+//! ```json
+//! {"data":{"timestamp":"1648041919","microtimestamp":"1648041919391460","bids":[["0.07007763","0.50000000"],["0.07008689","0.00000000"]],"asks":[["0.07016110","0.50000000"],["0.07018743","0.00000000"]]},"channel":"diff_order_book_ethbtc","event":"data"}
+//! ```
+
+use super::{
+    connect_each,
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Gap, Liveness, Route,
+    SequenceFilter, Silent, Watchdog,
+};
+use crate::{from_unix_micros, AnonymousLevel, LocalBook, Pair, Side, SimpleOrderBook};
+use futures::SinkExt;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::mpsc::Sender};
+use tokio_tungstenite::{
+    tungstenite::Message as TungsteniteMessage, MaybeTlsStream, WebSocketStream,
+};
+
+const EXCHANGE_NAME: &str = "bitstamp";
+
+/// Default websocket endpoint for Bitstamp.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://ws.bitstamp.net";
 
-use super::{read_book, ExchangeConnection};
-use crate::{AnonymousLevel, SimpleOrderBook};
-use futures::{SinkExt, StreamExt};
-use tokio::sync::mpsc::Sender;
+/// Default base URL for the Bitstamp REST API.
+pub const DEFAULT_REST_ENDPOINT: &str = "https://www.bitstamp.net";
 
-const EXCHANGE_NAME: &'static str = "bitstamp";
+/// In incremental mode, this many of the best levels on each side are sent to the aggregator by default.
+///
+/// This matches the depth of the `order_book` channel.
+pub const DEFAULT_INCREMENTAL_DEPTH_LEN: usize = 100;
 
-/// Message type for Bitstamp order book stream.
+/// How many channels we subscribe to over a single connection.
+///
+/// Bitstamp documents no limit, so this is a conservative choice.
+const MAX_CHANNELS_PER_CONNECTION: usize = 100;
+
+/// How long to wait before requesting another snapshot when the previous one was too old to use.
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How many diffs may be buffered while awaiting a snapshot.
+///
+/// Beyond this, the oldest diffs are dropped, and the snapshot being awaited is presumed too old to bridge the gap
+/// to those which remain.
+const MAX_BUFFERED_DIFFS: usize = 2048;
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Message type for Bitstamp's websocket API.
 #[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Message {
-    _event: Option<String>,
-    _channel: Option<String>,
-    data: Data,
+#[serde(tag = "event")]
+enum Message {
+    #[serde(rename = "data")]
+    Data { channel: String, data: Data },
+    #[serde(rename = "bts:subscription_succeeded")]
+    SubscriptionSucceeded { channel: String },
+    /// Bitstamp is about to close this connection, and asks that we reconnect.
+    #[serde(rename = "bts:request_reconnect")]
+    RequestReconnect,
+    #[serde(rename = "bts:error")]
+    Error { data: ErrorData },
+    #[serde(other)]
+    Other,
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct ErrorData {
+    code: Option<i64>,
+    message: String,
+}
+
+impl From<ErrorData> for Error {
+    fn from(ErrorData { code, message }: ErrorData) -> Self {
+        Error::Exchange { code, message }
+    }
+}
+
+/// The payload of both the `order_book` and `diff_order_book` channels.
+///
+/// The REST order book snapshot has the same shape.
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Data {
     _timestamp: String,
-    _microtimestamp: String,
+    #[serde(deserialize_with = "u64_from_str")]
+    microtimestamp: u64,
     bids: Vec<AnonymousLevel>,
     asks: Vec<AnonymousLevel>,
 }
 
-impl From<Message> for SimpleOrderBook {
-    fn from(bsm: Message) -> Self {
-        let Data { bids, asks, .. } = bsm.data;
-        SimpleOrderBook { bids, asks }
+impl From<Data> for SimpleOrderBook {
+    fn from(data: Data) -> Self {
+        let Data {
+            microtimestamp,
+            bids,
+            asks,
+            ..
+        } = data;
+        SimpleOrderBook::new(bids, asks).with_exchange_time(from_unix_micros(microtimestamp))
     }
 }
 
+/// Bitstamp sends its timestamps as strings.
+fn u64_from_str<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // not `&str`: messages are buffered by their `event` tag first, which may leave the strings owned
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// Which of Bitstamp's order book channels to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitstampMode {
+    /// Subscribe to the `order_book` channel, which repeatedly sends a complete 100-level snapshot.
+    Snapshot,
+    /// Maintain a local full-depth book from the `diff_order_book` channel and a REST snapshot.
+    Incremental,
+}
+
 /// Manage a websocket connection to Bitstamp.
-pub struct BitstampConnection;
+#[derive(Debug, Clone)]
+pub struct BitstampConnection {
+    mode: BitstampMode,
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    rest_endpoint: String,
+    incremental_depth_len: usize,
+}
+
+impl Default for BitstampConnection {
+    fn default() -> Self {
+        BitstampConnection {
+            mode: BitstampMode::Snapshot,
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            rest_endpoint: DEFAULT_REST_ENDPOINT.to_string(),
+            incremental_depth_len: DEFAULT_INCREMENTAL_DEPTH_LEN,
+        }
+    }
+}
+
+impl BitstampConnection {
+    /// Create a connection in the given mode, using Bitstamp's production endpoints.
+    pub fn new(mode: BitstampMode) -> Self {
+        BitstampConnection {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Fetch snapshots from a different REST API base URL, such as a local mock server.
+    pub fn with_rest_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.rest_endpoint = endpoint.into();
+        self
+    }
+
+    /// In incremental mode, send this many of the best levels on each side to the aggregator.
+    pub fn with_incremental_depth_len(mut self, len: usize) -> Self {
+        self.incremental_depth_len = len;
+        self
+    }
+
+    /// Subscribe to the named channels over a single connection.
+    async fn subscribe(&self, channels: Vec<String>) -> Result<Feed, Error> {
+        Feed::open(
+            self.websocket_endpoint.clone(),
+            channels,
+            self.liveness,
+            self.proxy.clone(),
+        )
+        .await
+    }
+
+    /// Follow the `order_book` channel of each symbol, forwarding each snapshot along its symbol's route
+    /// as it arrives.
+    async fn follow_snapshots(&self, routes: Vec<Route>) -> Result<(), Error> {
+        let channels: Vec<_> = routes
+            .iter()
+            .map(|route| format!("order_book_{}", route.symbol))
+            .collect();
+        let mut feed = self.subscribe(channels.clone()).await?;
+        for route in routes.iter() {
+            report_subscribed(&route.updates, EXCHANGE_NAME).await;
+        }
+        let mut sequences = vec![SequenceFilter::default(); routes.len()];
+
+        loop {
+            match feed.next().await {
+                Ok(Some(FeedEvent::Data { channel, data })) => {
+                    let index = match channels.iter().position(|name| *name == channel) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    if !sequences[index].is_fresh(data.microtimestamp) {
+                        log::debug!(
+                            "[{EXCHANGE_NAME}] discarding stale {} book as of {}",
+                            routes[index].symbol,
+                            data.microtimestamp
+                        );
+                        continue;
+                    }
+                    let book = ExchangeUpdate::Book(data.into());
+                    if let Err(_send_err) = routes[index].updates.send((EXCHANGE_NAME, book)).await
+                    {
+                        log::warn!("[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed");
+                        return Ok(());
+                    }
+                }
+                Ok(Some(FeedEvent::Gap)) => {
+                    // every message is a complete book, so there's nothing to resynchronize
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
+                }
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Error::ConnectionDropped)
+    }
+
+    /// Follow the `diff_order_book` channel, maintaining a local full-depth book.
+    async fn follow_diffs(
+        &self,
+        symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Error> {
+        let mut feed = self
+            .subscribe(vec![format!("diff_order_book_{symbol}")])
+            .await?;
+        report_subscribed(&updates, EXCHANGE_NAME).await;
+
+        let client = self
+            .proxy
+            .apply_to(reqwest::Client::builder())
+            .build()
+            .map_err(Error::HttpClient)?;
+        let snapshot_url = format!("{}/api/v2/order_book/{symbol}/", self.rest_endpoint);
+        let mut sync = DiffSync::default();
+
+        // We must already be buffering diffs before we request the snapshot,
+        // otherwise we can't be sure that the diffs will bridge the gap from it.
+        let mut snapshot_request = Some(Box::pin(fetch_snapshot(
+            &client,
+            &snapshot_url,
+            Duration::ZERO,
+        )));
+
+        loop {
+            let update = tokio::select! {
+                snapshot = async {
+                    snapshot_request.as_mut().expect("guarded by precondition").await
+                }, if snapshot_request.is_some() => {
+                    snapshot_request = None;
+                    let snapshot = snapshot?;
+                    log::debug!("[{EXCHANGE_NAME}] received snapshot as of {}", snapshot.microtimestamp);
+                    match sync.on_snapshot(snapshot) {
+                        SyncState::Synchronized => {}
+                        SyncState::Pending => continue,
+                        SyncState::Desynchronized => {
+                            log::warn!("[{EXCHANGE_NAME}] snapshot does not line up with diff stream; fetching another");
+                            snapshot_request = Some(Box::pin(fetch_snapshot(&client, &snapshot_url, SNAPSHOT_RETRY_DELAY)));
+                            continue;
+                        }
+                    }
+                    ExchangeUpdate::Book(sync.top(self.incremental_depth_len))
+                }
+                event = feed.next() => match event {
+                    Ok(Some(FeedEvent::Data { data: diff, .. })) => match sync.on_diff(diff) {
+                        SyncState::Synchronized => ExchangeUpdate::Book(sync.top(self.incremental_depth_len)),
+                        SyncState::Pending => continue,
+                        SyncState::Desynchronized => {
+                            log::warn!("[{EXCHANGE_NAME}] diffs no longer line up with the local book; resynchronizing");
+                            if snapshot_request.is_none() {
+                                snapshot_request = Some(Box::pin(fetch_snapshot(&client, &snapshot_url, Duration::ZERO)));
+                            }
+                            ExchangeUpdate::Gap(Gap::OutOfOrder)
+                        }
+                    },
+                    Ok(Some(FeedEvent::Gap)) => {
+                        log::warn!("[{EXCHANGE_NAME}] diffs may have been missed while reconnecting; resynchronizing");
+                        sync.reset();
+                        if snapshot_request.is_none() {
+                            snapshot_request = Some(Box::pin(fetch_snapshot(&client, &snapshot_url, Duration::ZERO)));
+                        }
+                        ExchangeUpdate::Gap(Gap::Reconnect)
+                    }
+                    Ok(None) => {
+                        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+                        return Err(Error::ConnectionDropped);
+                    }
+                    Err(err) => {
+                        log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                        return Err(err);
+                    }
+                },
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, update)).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl ExchangeConnection for BitstampConnection {
@@ -56,71 +370,470 @@ impl ExchangeConnection for BitstampConnection {
         EXCHANGE_NAME
     }
 
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("")
+    }
+
     async fn connect(
         &self,
         symbol: String,
-        updates: Sender<(&'static str, SimpleOrderBook)>,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
     ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
         log::trace!("[{EXCHANGE_NAME}] entered `connect` for {symbol}");
 
-        let endpoint = "wss://ws.bitstamp.net";
-        let (mut stream, _response) = tokio_tungstenite::connect_async(endpoint)
-            .await
-            .map_err(into_box)?;
-        let subscription_message = format!(
-            "{{\"event\":\"bts:subscribe\",\"data\":{{\"channel\":\"order_book_{symbol}\"}}}}"
-        );
-        stream
-            .send(tokio_tungstenite::tungstenite::Message::Text(
-                subscription_message,
-            ))
-            .await
-            .map_err(into_box)?;
+        let result = match self.mode {
+            BitstampMode::Snapshot => self.follow_snapshots(vec![Route { symbol, updates }]).await,
+            BitstampMode::Incremental => self.follow_diffs(symbol, updates).await,
+        };
+        result.map_err(into_box)
+    }
 
-        let confirmation_message = stream
-            .next()
-            .await
-            .ok_or(Error::NoConfirmation)
-            .map_err(into_box)?
-            .map_err(into_box)?;
-        let confirmation_text = confirmation_message.into_text().map_err(into_box)?;
-        if !confirmation_text.contains("bts:subscription_succeeded") {
-            return Err(into_box(Error::SubscriptionFailure(confirmation_text)));
-        }
-
-        while let Some(maybe_message) = stream.next().await {
-            match read_book::<Message, Error>(maybe_message) {
-                Ok(book) => {
-                    if let Err(_send_err) = updates.send((EXCHANGE_NAME, book)).await {
-                        log::warn!("[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed");
-                        return Ok(());
-                    }
+    fn max_symbols_per_connection(&self) -> usize {
+        match self.mode {
+            BitstampMode::Snapshot => MAX_CHANNELS_PER_CONNECTION,
+            // each symbol needs its own snapshots and synchronization, so keep them apart
+            BitstampMode::Incremental => 1,
+        }
+    }
+
+    async fn connect_many(
+        &self,
+        routes: Vec<Route>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        if routes.len() < 2 || self.mode != BitstampMode::Snapshot {
+            return connect_each(self, routes).await;
+        }
+        log::trace!(
+            "[{EXCHANGE_NAME}] entered `connect_many` for {} symbols",
+            routes.len()
+        );
+
+        self.follow_snapshots(routes).await.map_err(into_box)
+    }
+}
+
+/// Fetch a REST snapshot of the order book after waiting for `delay`.
+async fn fetch_snapshot(
+    client: &reqwest::Client,
+    url: &str,
+    delay: Duration,
+) -> Result<Data, Error> {
+    tokio::time::sleep(delay).await;
+    log::debug!("[{EXCHANGE_NAME}] requesting snapshot from {url}");
+    let snapshot = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(snapshot)
+}
+
+/// Whether the local book currently reflects the exchange's book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncState {
+    /// The local book is up to date.
+    Synchronized,
+    /// We are still waiting for a snapshot; diffs are being buffered.
+    Pending,
+    /// The diffs no longer line up with the local book; a fresh snapshot is required.
+    Desynchronized,
+}
+
+/// Keep a local book synchronized with the `diff_order_book` channel.
+#[derive(Debug, Default)]
+struct DiffSync {
+    book: LocalBook,
+    /// The microtimestamp reflected in `book`, or `None` if we're awaiting a snapshot.
+    microtimestamp: Option<u64>,
+    /// The latest diffs received while awaiting a snapshot, at most [`MAX_BUFFERED_DIFFS`] of them.
+    buffer: VecDeque<Data>,
+    /// Whether diffs have been dropped from the buffer since the last snapshot was applied.
+    overflowed: bool,
+}
+
+impl DiffSync {
+    /// Produce the best levels of the local book, as of the last diff applied.
+    fn top(&self, depth: usize) -> SimpleOrderBook {
+        let book = self.book.top(depth);
+        match self.microtimestamp {
+            Some(microtimestamp) => book.with_exchange_time(from_unix_micros(microtimestamp)),
+            None => book,
+        }
+    }
+
+    fn on_diff(&mut self, diff: Data) -> SyncState {
+        let microtimestamp = match self.microtimestamp {
+            Some(microtimestamp) => microtimestamp,
+            None => {
+                return match self.buffer_diff(diff) {
+                    true => SyncState::Desynchronized,
+                    false => SyncState::Pending,
+                };
+            }
+        };
+
+        if diff.microtimestamp <= microtimestamp {
+            self.book.clear();
+            self.microtimestamp = None;
+            self.buffer_diff(diff);
+            return SyncState::Desynchronized;
+        }
+
+        self.apply(diff);
+        SyncState::Synchronized
+    }
+
+    fn on_snapshot(&mut self, snapshot: Data) -> SyncState {
+        // if the snapshot is older than every diff we have, we may have missed diffs between it and them
+        if let Some(first) = self.buffer.front() {
+            if snapshot.microtimestamp < first.microtimestamp {
+                return SyncState::Desynchronized;
+            }
+        }
+
+        let Data {
+            microtimestamp,
+            bids,
+            asks,
+            ..
+        } = snapshot;
+        self.book.replace(bids, asks);
+        self.microtimestamp = Some(microtimestamp);
+        self.overflowed = false;
+
+        let mut outcome = SyncState::Synchronized;
+        for diff in std::mem::take(&mut self.buffer) {
+            if outcome == SyncState::Desynchronized {
+                // once desynchronized, the remaining diffs are simply buffered for the next snapshot
+                self.buffer.push_back(diff);
+            } else if diff.microtimestamp > microtimestamp {
+                outcome = self.on_diff(diff);
+            }
+            // otherwise the diff is already reflected in the snapshot
+        }
+        outcome
+    }
+
+    /// Discard the local book and any buffered diffs, so that the next snapshot starts afresh.
+    fn reset(&mut self) {
+        self.book.clear();
+        self.microtimestamp = None;
+        self.buffer.clear();
+        self.overflowed = false;
+    }
+
+    /// Hold on to a diff until a snapshot arrives, dropping the oldest diff if the buffer is full.
+    ///
+    /// Returns `true` when this is the first diff to be dropped since the last snapshot, in which case a fresh
+    /// snapshot is required.
+    fn buffer_diff(&mut self, diff: Data) -> bool {
+        let mut first_overflow = false;
+        if self.buffer.len() >= MAX_BUFFERED_DIFFS {
+            self.buffer.pop_front();
+            first_overflow = !self.overflowed;
+            self.overflowed = true;
+        }
+        self.buffer.push_back(diff);
+        first_overflow
+    }
+
+    fn apply(&mut self, diff: Data) {
+        self.book.apply_all(Side::Bid, diff.bids);
+        self.book.apply_all(Side::Ask, diff.asks);
+        self.microtimestamp = Some(diff.microtimestamp);
+    }
+}
+
+/// A subscription to one or more channels, over its own websocket connection.
+struct Subscription {
+    channels: Vec<String>,
+    stream: Stream,
+    watchdog: Watchdog,
+}
+
+/// What a confirmed subscription has received which matters to us.
+enum SubscriptionEvent {
+    Data { channel: String, data: Data },
+    RequestReconnect,
+}
+
+impl Subscription {
+    /// Connect, subscribe to the channels, and wait for Bitstamp to confirm each subscription.
+    ///
+    /// Messages which arrive before all the confirmations are ignored.
+    async fn open(
+        endpoint: String,
+        channels: Vec<String>,
+        liveness: Liveness,
+        proxy: ProxyConfig,
+    ) -> Result<Self, Error> {
+        let (mut stream, _response) = connect_websocket(&endpoint, &proxy).await?;
+        let watchdog = Watchdog::new(liveness);
+        for channel in channels.iter() {
+            let subscription_message = serde_json::json!({
+                "event": "bts:subscribe",
+                "data": { "channel": channel },
+            });
+            stream
+                .send(TungsteniteMessage::Text(subscription_message.to_string()))
+                .await?;
+        }
+
+        let mut unconfirmed = channels.clone();
+        let mut subscription = Subscription {
+            channels,
+            stream,
+            watchdog,
+        };
+        while !unconfirmed.is_empty() {
+            match subscription.read().await? {
+                Some(Message::SubscriptionSucceeded { channel })
+                    if unconfirmed.contains(&channel) =>
+                {
+                    log::debug!("[{EXCHANGE_NAME}] subscribed to {channel}");
+                    unconfirmed.retain(|name| *name != channel);
                 }
-                Err(Error::Irrelevant) => {
-                    // noop, we can just ignore that message
+                Some(Message::Error { data }) => return Err(data.into()),
+                Some(_) => {
+                    log::debug!("[{EXCHANGE_NAME}] ignoring message which preceded subscription confirmation");
                 }
-                Err(err) => {
-                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
-                    return Err(Box::new(err));
+                None => return Err(Error::NoConfirmation),
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Read the next relevant message, or `None` if the connection has closed.
+    async fn read(&mut self) -> Result<Option<Message>, Error> {
+        while let Some(maybe_message) = self.watchdog.next(&mut self.stream).await? {
+            match read_message::<Message, Error>(maybe_message) {
+                Ok(message) => return Ok(Some(message)),
+                Err(Error::Irrelevant) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for the next event on this subscription, or `None` if the connection has closed.
+    async fn next(&mut self) -> Result<Option<SubscriptionEvent>, Error> {
+        loop {
+            match self.read().await? {
+                Some(Message::Data { channel, data }) if self.channels.contains(&channel) => {
+                    return Ok(Some(SubscriptionEvent::Data { channel, data }))
                 }
+                Some(Message::RequestReconnect) => {
+                    return Ok(Some(SubscriptionEvent::RequestReconnect))
+                }
+                Some(Message::Error { data }) => return Err(data.into()),
+                Some(_) => continue,
+                None => return Ok(None),
             }
         }
+    }
+}
 
-        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
-        Err(Box::new(Error::ConnectionDropped))
+/// A connection which is to replace the current one, after Bitstamp asked us to reconnect.
+enum Replacement {
+    Connecting(Pin<Box<dyn Future<Output = Result<Subscription, Error>> + Send>>),
+    /// Data is buffered here until it overlaps what the current connection has delivered.
+    Subscribed {
+        subscription: Box<Subscription>,
+        buffer: Vec<(String, Data)>,
+    },
+}
+
+impl Replacement {
+    /// Wait for the next event on the replacement, connecting first if necessary.
+    ///
+    /// This is cancellation safe: an interrupted connection attempt resumes on the next call.
+    async fn next(&mut self) -> Result<Option<SubscriptionEvent>, Error> {
+        if let Replacement::Connecting(connecting) = self {
+            let subscription = connecting.await?;
+            *self = Replacement::Subscribed {
+                subscription: Box::new(subscription),
+                buffer: Vec::new(),
+            };
+        }
+        match self {
+            Replacement::Subscribed { subscription, .. } => subscription.next().await,
+            Replacement::Connecting(_) => unreachable!("connection completed above"),
+        }
     }
 }
 
+/// What a [`Feed`] delivers.
+enum FeedEvent {
+    Data {
+        channel: String,
+        data: Data,
+    },
+    /// The feed switched connections without being able to show that no data was missed in between.
+    Gap,
+}
+
+/// A subscription which survives Bitstamp's requests to reconnect.
+struct Feed {
+    endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    current: Subscription,
+    replacement: Option<Replacement>,
+    /// The microtimestamp of the latest data delivered on each channel.
+    latest: HashMap<String, u64>,
+    /// Data from the replacement, to be delivered after switching over to it.
+    pending: VecDeque<(String, Data)>,
+}
+
+impl Feed {
+    async fn open(
+        endpoint: String,
+        channels: Vec<String>,
+        liveness: Liveness,
+        proxy: ProxyConfig,
+    ) -> Result<Self, Error> {
+        let current =
+            Subscription::open(endpoint.clone(), channels, liveness, proxy.clone()).await?;
+        Ok(Feed {
+            endpoint,
+            liveness,
+            proxy,
+            current,
+            replacement: None,
+            latest: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Wait for the next event, or `None` once the connection has closed with no replacement.
+    async fn next(&mut self) -> Result<Option<FeedEvent>, Error> {
+        loop {
+            if let Some((channel, data)) = self.pending.pop_front() {
+                return Ok(Some(self.deliver(channel, data)));
+            }
+
+            let Feed {
+                current,
+                replacement,
+                ..
+            } = self;
+            tokio::select! {
+                event = current.next() => match event {
+                    Ok(Some(SubscriptionEvent::Data { channel, data })) => {
+                        let event = self.deliver(channel, data);
+                        if self.replacement_overlaps() {
+                            self.switch_over();
+                        }
+                        return Ok(Some(event));
+                    }
+                    Ok(Some(SubscriptionEvent::RequestReconnect)) => {
+                        if self.replacement.is_none() {
+                            log::info!("[{EXCHANGE_NAME}] reconnection requested; opening a replacement connection");
+                            let connecting = Subscription::open(self.endpoint.clone(), self.current.channels.clone(), self.liveness, self.proxy.clone());
+                            self.replacement = Some(Replacement::Connecting(Box::pin(connecting)));
+                        }
+                    }
+                    Ok(None) | Err(_) if matches!(self.replacement, Some(Replacement::Subscribed { .. })) => {
+                        log::warn!("[{EXCHANGE_NAME}] connection ended before its replacement caught up");
+                        self.switch_over();
+                        if !self.latest.is_empty() {
+                            return Ok(Some(FeedEvent::Gap));
+                        }
+                    }
+                    Ok(None) => return Ok(None),
+                    Err(err) => return Err(err),
+                },
+                event = async {
+                    replacement.as_mut().expect("guarded by precondition").next().await
+                }, if replacement.is_some() => match event {
+                    Ok(Some(SubscriptionEvent::Data { channel, data })) => {
+                        if let Some(Replacement::Subscribed { buffer, .. }) = &mut self.replacement {
+                            buffer.push((channel, data));
+                        }
+                    }
+                    Ok(Some(SubscriptionEvent::RequestReconnect)) => {}
+                    Ok(None) => {
+                        log::warn!("[{EXCHANGE_NAME}] replacement connection closed; continuing with the current one");
+                        self.replacement = None;
+                    }
+                    Err(err) => {
+                        log::warn!("[{EXCHANGE_NAME}] replacement connection failed ({err}); continuing with the current one");
+                        self.replacement = None;
+                    }
+                },
+            }
+        }
+    }
+
+    fn deliver(&mut self, channel: String, data: Data) -> FeedEvent {
+        self.latest.insert(channel.clone(), data.microtimestamp);
+        FeedEvent::Data { channel, data }
+    }
+
+    /// `true` when the replacement has subscribed, and its data [overlaps] what has been delivered.
+    fn replacement_overlaps(&self) -> bool {
+        match &self.replacement {
+            Some(Replacement::Subscribed { buffer, .. }) => overlaps(&self.latest, buffer),
+            _ => false,
+        }
+    }
+
+    /// Replace the current subscription with the replacement, queueing whatever of its data is new.
+    fn switch_over(&mut self) {
+        if let Some(Replacement::Subscribed {
+            subscription,
+            buffer,
+        }) = self.replacement.take()
+        {
+            log::info!("[{EXCHANGE_NAME}] switched over to replacement connection");
+            self.current = *subscription;
+            self.pending.extend(unseen(&self.latest, buffer));
+        }
+    }
+}
+
+/// `true` when, on every channel which has delivered data, the earliest buffered data is no newer than the latest
+/// data delivered, so that nothing can have been missed by switching over to the connection which buffered it.
+fn overlaps(latest: &HashMap<String, u64>, buffer: &[(String, Data)]) -> bool {
+    !latest.is_empty()
+        && latest.iter().all(|(channel, latest)| {
+            buffer
+                .iter()
+                .find(|(name, _)| name == channel)
+                .is_some_and(|(_, first)| first.microtimestamp <= *latest)
+        })
+}
+
+/// The buffered data which is newer than the latest delivered on its channel, in the order it was received.
+fn unseen(
+    latest: &HashMap<String, u64>,
+    buffer: Vec<(String, Data)>,
+) -> impl '_ + Iterator<Item = (String, Data)> {
+    buffer.into_iter().filter(|(channel, data)| {
+        latest
+            .get(channel)
+            .is_none_or(|latest| data.microtimestamp > *latest)
+    })
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("server did not send a textual confirmation message of stream registration")]
+    #[error("connection closed before the subscription was confirmed")]
     NoConfirmation,
-    #[error("subscription failure: {0}")]
-    SubscriptionFailure(String),
+    #[error("exchange reported an error{}: {message}", .code.map(|code| format!(" (code {code})")).unwrap_or_default())]
+    Exchange { code: Option<i64>, message: String },
     #[error("websocket problem")]
     Tungstenite(#[from] tokio_tungstenite::tungstenite::error::Error),
     #[error("failed to deserialize message")]
     Deserialization(#[from] serde_json::Error),
+    #[error("failed to build HTTP client")]
+    HttpClient(reqwest::Error),
+    #[error("failed to fetch order book snapshot")]
+    Snapshot(#[from] reqwest::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
     #[error("connection dropped by host")]
     ConnectionDropped,
     #[error("message was irrelevant")]
@@ -136,3 +849,397 @@ impl super::Error for Error {
 fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
     Box::new(err.into()) as _
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use rust_decimal::Decimal;
+    use tokio::net::TcpListener;
+
+    fn data(microtimestamp: u64, bids: &[(&str, &str)]) -> Data {
+        Data {
+            _timestamp: (microtimestamp / 1_000_000).to_string(),
+            microtimestamp,
+            bids: bids
+                .iter()
+                .map(|(price, amount)| AnonymousLevel {
+                    price: price.parse().unwrap(),
+                    amount: amount.parse().unwrap(),
+                })
+                .collect(),
+            asks: Vec::new(),
+        }
+    }
+
+    fn bids(sync: &DiffSync) -> Vec<(Decimal, Decimal)> {
+        sync.top(10)
+            .bids
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect()
+    }
+
+    fn entry(price: &str, amount: &str) -> (Decimal, Decimal) {
+        (price.parse().unwrap(), amount.parse().unwrap())
+    }
+
+    #[test]
+    fn diffs_in_order_are_applied() {
+        let mut sync = DiffSync::default();
+        assert_eq!(
+            sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            sync.on_diff(data(101, &[("0.07007763", "0.50000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            sync.on_diff(data(102, &[("0.07010256", "0.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(bids(&sync), vec![entry("0.07007763", "0.50000000")]);
+        assert_eq!(sync.microtimestamp, Some(102));
+    }
+
+    #[test]
+    fn diffs_are_buffered_until_the_snapshot_arrives() {
+        let mut sync = DiffSync::default();
+        assert_eq!(
+            sync.on_diff(data(99, &[("0.07008689", "1.00000000")])),
+            SyncState::Pending
+        );
+        assert_eq!(
+            sync.on_diff(data(101, &[("0.07007763", "0.50000000")])),
+            SyncState::Pending
+        );
+        assert_eq!(
+            sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")])),
+            SyncState::Synchronized
+        );
+        // the first diff is already reflected in the snapshot, so it isn't applied again
+        assert_eq!(
+            bids(&sync),
+            vec![
+                entry("0.07010256", "6.00000000"),
+                entry("0.07007763", "0.50000000")
+            ]
+        );
+        assert!(sync.buffer.is_empty());
+    }
+
+    #[test]
+    fn snapshot_older_than_the_buffered_diffs_is_rejected() {
+        let mut sync = DiffSync::default();
+        sync.on_diff(data(105, &[("0.07007763", "0.50000000")]));
+        assert_eq!(
+            sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(sync.microtimestamp, None);
+        assert_eq!(sync.buffer.len(), 1);
+
+        assert_eq!(
+            sync.on_snapshot(data(105, &[("0.07010256", "6.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(bids(&sync), vec![entry("0.07010256", "6.00000000")]);
+    }
+
+    #[test]
+    fn duplicate_diff_forces_a_resync() {
+        let mut sync = DiffSync::default();
+        sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")]));
+        sync.on_diff(data(101, &[("0.07007763", "0.50000000")]));
+        assert_eq!(
+            sync.on_diff(data(101, &[("0.07007763", "0.50000000")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(sync.microtimestamp, None);
+        assert!(bids(&sync).is_empty());
+    }
+
+    #[test]
+    fn out_of_order_diff_forces_a_resync() {
+        let mut sync = DiffSync::default();
+        sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")]));
+        sync.on_diff(data(103, &[("0.07007763", "0.50000000")]));
+        assert_eq!(
+            sync.on_diff(data(102, &[("0.07008689", "1.00000000")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(
+            sync.on_diff(data(104, &[("0.07007082", "2.00000000")])),
+            SyncState::Pending
+        );
+
+        // the fresh snapshot already reflects the diff which arrived late, but not the one after it
+        assert_eq!(
+            sync.on_snapshot(data(103, &[("0.07008689", "1.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            bids(&sync),
+            vec![
+                entry("0.07008689", "1.00000000"),
+                entry("0.07007082", "2.00000000")
+            ]
+        );
+        assert_eq!(sync.microtimestamp, Some(104));
+    }
+
+    #[test]
+    fn reset_discards_the_book_and_buffered_diffs() {
+        let mut sync = DiffSync::default();
+        sync.on_snapshot(data(100, &[("0.07010256", "6.00000000")]));
+        sync.on_diff(data(101, &[("0.07007763", "0.50000000")]));
+        sync.reset();
+        assert!(bids(&sync).is_empty());
+        assert_eq!(sync.microtimestamp, None);
+
+        // after a reconnection gap, diffs are buffered again until a fresh snapshot, which may already reflect some
+        assert_eq!(
+            sync.on_diff(data(110, &[("0.07007082", "2.00000000")])),
+            SyncState::Pending
+        );
+        assert_eq!(
+            sync.on_diff(data(111, &[("0.07007763", "0.50000000")])),
+            SyncState::Pending
+        );
+        assert_eq!(
+            sync.on_snapshot(data(110, &[("0.07010256", "6.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            bids(&sync),
+            vec![
+                entry("0.07010256", "6.00000000"),
+                entry("0.07007763", "0.50000000")
+            ]
+        );
+    }
+
+    #[test]
+    fn buffer_overflow_forces_a_resync() {
+        let mut sync = DiffSync::default();
+        for microtimestamp in 1..=MAX_BUFFERED_DIFFS as u64 {
+            assert_eq!(sync.on_diff(data(microtimestamp, &[])), SyncState::Pending);
+        }
+        let overflowing = MAX_BUFFERED_DIFFS as u64 + 1;
+        assert_eq!(
+            sync.on_diff(data(overflowing, &[("0.07007763", "0.50000000")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(sync.buffer.len(), MAX_BUFFERED_DIFFS);
+        assert_eq!(sync.buffer.front().unwrap().microtimestamp, 2);
+        // the resync has already been asked for
+        assert_eq!(sync.on_diff(data(overflowing + 1, &[])), SyncState::Pending);
+        assert_eq!(sync.buffer.front().unwrap().microtimestamp, 3);
+
+        // a snapshot from before the oldest remaining diff is too old
+        assert_eq!(
+            sync.on_snapshot(data(2, &[("0.07010256", "6.00000000")])),
+            SyncState::Desynchronized
+        );
+        assert_eq!(
+            sync.on_snapshot(data(3, &[("0.07010256", "6.00000000")])),
+            SyncState::Synchronized
+        );
+        assert_eq!(
+            bids(&sync),
+            vec![
+                entry("0.07010256", "6.00000000"),
+                entry("0.07007763", "0.50000000")
+            ]
+        );
+        assert!(!sync.overflowed);
+    }
+
+    fn buffered(entries: &[(&str, u64)]) -> Vec<(String, Data)> {
+        entries
+            .iter()
+            .map(|(channel, microtimestamp)| (channel.to_string(), data(*microtimestamp, &[])))
+            .collect()
+    }
+
+    fn latest(entries: &[(&str, u64)]) -> HashMap<String, u64> {
+        entries
+            .iter()
+            .map(|(channel, microtimestamp)| (channel.to_string(), *microtimestamp))
+            .collect()
+    }
+
+    #[test]
+    fn replacement_must_overlap_every_channel() {
+        // nothing delivered yet, so there's nothing to overlap
+        assert!(!overlaps(&latest(&[]), &buffered(&[("a", 1)])));
+
+        let delivered = latest(&[("a", 5)]);
+        assert!(!overlaps(&delivered, &buffered(&[])));
+        assert!(!overlaps(&delivered, &buffered(&[("a", 6)])));
+        assert!(overlaps(&delivered, &buffered(&[("a", 5), ("a", 6)])));
+        assert!(overlaps(&delivered, &buffered(&[("a", 4)])));
+        // channels which haven't delivered anything don't hold up the switch
+        assert!(overlaps(&delivered, &buffered(&[("b", 100), ("a", 5)])));
+
+        let delivered = latest(&[("a", 5), ("b", 7)]);
+        assert!(!overlaps(&delivered, &buffered(&[("a", 4)])));
+        // only the earliest data on each channel matters
+        assert!(!overlaps(
+            &delivered,
+            &buffered(&[("a", 4), ("b", 8), ("b", 7)])
+        ));
+        assert!(overlaps(
+            &delivered,
+            &buffered(&[("a", 4), ("b", 6), ("b", 8)])
+        ));
+    }
+
+    #[test]
+    fn only_unseen_data_is_replayed_in_order() {
+        let delivered = latest(&[("a", 5), ("b", 7)]);
+        let buffer = buffered(&[
+            ("a", 4),
+            ("b", 6),
+            ("a", 5),
+            ("b", 8),
+            ("a", 6),
+            ("c", 1),
+            ("b", 7),
+            ("a", 7),
+            ("b", 9),
+        ]);
+        let replayed: Vec<_> = unseen(&delivered, buffer)
+            .map(|(channel, data)| (channel, data.microtimestamp))
+            .collect();
+        assert_eq!(
+            replayed,
+            [("b", 8), ("a", 6), ("c", 1), ("a", 7), ("b", 9)]
+                .map(|(channel, microtimestamp)| (channel.to_string(), microtimestamp))
+        );
+    }
+
+    const CHANNEL: &str = "order_book_ethbtc";
+
+    /// Accept a connection on the stand-in server, and confirm its subscription.
+    async fn accept_subscriber(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let request = websocket.next().await.unwrap().unwrap();
+        assert!(request.to_text().unwrap().contains(CHANNEL));
+        let confirmation = format!(
+            r#"{{"event":"bts:subscription_succeeded","channel":"{CHANNEL}","data":{{}}}}"#
+        );
+        websocket
+            .send(TungsteniteMessage::Text(confirmation))
+            .await
+            .unwrap();
+        websocket
+    }
+
+    fn book(microtimestamp: u64) -> TungsteniteMessage {
+        TungsteniteMessage::Text(format!(
+            r#"{{"data":{{"timestamp":"{}","microtimestamp":"{microtimestamp}","bids":[],"asks":[]}},"channel":"{CHANNEL}","event":"data"}}"#,
+            microtimestamp / 1_000_000
+        ))
+    }
+
+    fn request_reconnect() -> TungsteniteMessage {
+        TungsteniteMessage::Text(
+            r#"{"event":"bts:request_reconnect","channel":"","data":{}}"#.to_string(),
+        )
+    }
+
+    async fn open_feed(endpoint: String) -> Feed {
+        Feed::open(
+            endpoint,
+            vec![CHANNEL.to_string()],
+            Liveness::default(),
+            ProxyConfig::Direct,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Collect the microtimestamps of the next `count` books which the feed delivers.
+    async fn delivered(feed: &mut Feed, count: usize) -> Vec<u64> {
+        let collect = async {
+            let mut delivered = Vec::new();
+            while delivered.len() < count {
+                match feed.next().await.unwrap() {
+                    Some(FeedEvent::Data { channel, data }) => {
+                        assert_eq!(channel, CHANNEL);
+                        delivered.push(data.microtimestamp);
+                    }
+                    Some(FeedEvent::Gap) => panic!("the connections overlapped"),
+                    None => panic!("the feed ended"),
+                }
+            }
+            delivered
+        };
+        tokio::time::timeout(Duration::from_secs(5), collect)
+            .await
+            .expect("the feed should keep delivering")
+    }
+
+    #[tokio::test]
+    async fn switches_over_once_the_replacement_overlaps() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut current = accept_subscriber(&listener).await;
+            for microtimestamp in 1..=3 {
+                current.send(book(microtimestamp)).await.unwrap();
+            }
+            current.send(request_reconnect()).await.unwrap();
+
+            let mut replacement = accept_subscriber(&listener).await;
+            for microtimestamp in 3..=6 {
+                replacement.send(book(microtimestamp)).await.unwrap();
+            }
+            // give the feed time to buffer the replacement's data, none of which it may deliver yet
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            current.send(book(4)).await.unwrap();
+            // by now the feed has switched over, so this is never read
+            let _ = current.send(book(99)).await;
+            for microtimestamp in 7..=8 {
+                replacement.send(book(microtimestamp)).await.unwrap();
+            }
+            // hold the replacement open until the test is done with it
+            futures::future::pending::<()>().await;
+        });
+        let mut feed = open_feed(endpoint).await;
+
+        assert!(feed.replacement.is_none());
+        assert_eq!(delivered(&mut feed, 8).await, (1..=8).collect::<Vec<_>>());
+        assert!(feed.replacement.is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_replacement_leaves_the_current_connection_running() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut current = accept_subscriber(&listener).await;
+            current.send(book(1)).await.unwrap();
+            current.send(request_reconnect()).await.unwrap();
+
+            // the replacement's websocket handshake fails
+            let (replacement, _) = listener.accept().await.unwrap();
+            drop(replacement);
+            // give the feed time to notice
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            for microtimestamp in 2..=3 {
+                current.send(book(microtimestamp)).await.unwrap();
+            }
+            futures::future::pending::<()>().await;
+        });
+        let mut feed = open_feed(endpoint).await;
+
+        // data sent after the replacement failed still arrives, with no error from the feed
+        assert_eq!(delivered(&mut feed, 3).await, [1, 2, 3]);
+        assert!(feed.replacement.is_none());
+    }
+}
@@ -0,0 +1,428 @@
+//! Connection implementation for Coinbase Exchange.
+//!
+//! After connecting, we subscribe to the `level2_batch` channel for the desired product. Coinbase then
+//! sends a single `snapshot` of the full book, followed only by `l2update` messages listing the levels
+//! which have changed. It never sends another snapshot, so we must maintain a local book.
+//!
+//! Coinbase identifies markets by product ID, e.g. `ETH-BTC`.
+//!
+//! Example data:
+//!
+//! ```json
+//! {"type":"subscriptions","channels":[{"name":"level2_batch","product_ids":["ETH-BTC"]}]}
+//! {"type":"snapshot","product_id":"ETH-BTC","bids":[["0.07010","6.00000000"],["0.07008","0.50000000"]],"asks":[["0.07015","0.05000000"],["0.07016","1.55000000"]]}
+//! {"type":"l2update","product_id":"ETH-BTC","changes":[["buy","0.07010","0.00000000"],["sell","0.07014","0.25000000"]],"time":"2022-03-24T20:40:57.123456Z"}
+//! ```
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Liveness, Silent,
+    Watchdog,
+};
+use crate::{AnonymousLevel, LocalBook, Pair, Side, SimpleOrderBook, StringDecimal};
+use futures::SinkExt;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+const EXCHANGE_NAME: &str = "coinbase";
+
+/// Default websocket endpoint for the Coinbase Exchange market data feed.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://ws-feed.exchange.coinbase.com";
+
+/// By default, this many of the best levels on each side are sent to the aggregator.
+pub const DEFAULT_DEPTH: usize = 100;
+
+/// Message type for the Coinbase Exchange websocket feed.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Snapshot {
+        product_id: String,
+        bids: Vec<AnonymousLevel>,
+        asks: Vec<AnonymousLevel>,
+    },
+    L2update {
+        product_id: String,
+        changes: Vec<Change>,
+    },
+    Error {
+        message: String,
+        reason: Option<String>,
+    },
+    /// Confirmation of our subscription.
+    Subscriptions,
+    /// Heartbeats and so on.
+    #[serde(other)]
+    Other,
+}
+
+/// A single changed level: side, price, and new size.
+#[derive(Debug, serde::Deserialize)]
+struct Change(ChangeSide, StringDecimal, StringDecimal);
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeSide {
+    Buy,
+    Sell,
+}
+
+impl From<ChangeSide> for Side {
+    fn from(side: ChangeSide) -> Self {
+        match side {
+            ChangeSide::Buy => Side::Bid,
+            ChangeSide::Sell => Side::Ask,
+        }
+    }
+}
+
+/// The local book for a single product.
+///
+/// Updates are only applied once we've received the snapshot which they update.
+#[derive(Debug)]
+struct ProductBook {
+    product_id: String,
+    book: LocalBook,
+    have_snapshot: bool,
+}
+
+impl ProductBook {
+    fn new(product_id: String) -> Self {
+        ProductBook {
+            product_id,
+            book: LocalBook::default(),
+            have_snapshot: false,
+        }
+    }
+
+    /// Apply a snapshot or `l2update` for our product, returning `true` if the book was affected.
+    ///
+    /// Any other message is ignored.
+    fn on_message(&mut self, message: Message) -> bool {
+        match message {
+            Message::Snapshot {
+                product_id,
+                bids,
+                asks,
+            } if product_id == self.product_id => {
+                self.book.replace(bids, asks);
+                self.have_snapshot = true;
+                true
+            }
+            Message::L2update {
+                product_id,
+                changes,
+            } if product_id == self.product_id && self.have_snapshot => {
+                for Change(side, price, amount) in changes {
+                    self.book.apply(
+                        side.into(),
+                        AnonymousLevel {
+                            price: price.into(),
+                            amount: amount.into(),
+                        },
+                    );
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn top(&self, depth: usize) -> SimpleOrderBook {
+        self.book.top(depth)
+    }
+}
+
+/// Manage a websocket connection to Coinbase Exchange.
+#[derive(Debug, Clone)]
+pub struct CoinbaseConnection {
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    depth: usize,
+}
+
+impl Default for CoinbaseConnection {
+    fn default() -> Self {
+        CoinbaseConnection {
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            depth: DEFAULT_DEPTH,
+        }
+    }
+}
+
+impl CoinbaseConnection {
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Send this many of the best levels on each side to the aggregator.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for CoinbaseConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("-").to_uppercase()
+    }
+
+    async fn connect(
+        &self,
+        product_id: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {product_id}");
+
+        let (mut stream, _response) = connect_websocket(&self.websocket_endpoint, &self.proxy)
+            .await
+            .map_err(into_box)?;
+        let mut watchdog = Watchdog::new(self.liveness);
+        let subscription_message = serde_json::json!({
+            "type": "subscribe",
+            "product_ids": [product_id],
+            "channels": ["level2_batch"],
+        });
+        stream
+            .send(TungsteniteMessage::Text(subscription_message.to_string()))
+            .await
+            .map_err(into_box)?;
+
+        let mut book = ProductBook::new(product_id);
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await.map_err(into_box)? {
+            match read_message::<Message, Error>(maybe_message) {
+                Ok(Message::Subscriptions) => {
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Ok(Message::Error { message, reason }) => {
+                    let err = Error::Exchange(format!("{message}: {}", reason.unwrap_or_default()));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+                Ok(message) => {
+                    if !book.on_message(message) {
+                        continue;
+                    }
+                }
+                Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+            }
+
+            if let Err(_send_err) = updates
+                .send((EXCHANGE_NAME, book.top(self.depth).into()))
+                .await
+            {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Box::new(Error::ConnectionDropped))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("exchange reported an error: {0}")]
+    Exchange(String),
+    #[error("websocket problem")]
+    Tungstenite(#[from] TungsteniteError),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    const SUBSCRIPTIONS: &str = r#"{"type":"subscriptions","channels":[{"name":"level2_batch","product_ids":["ETH-BTC"]}]}"#;
+    const SNAPSHOT: &str = r#"{"type":"snapshot","product_id":"ETH-BTC","bids":[["0.07010","6.00000000"],["0.07008","0.50000000"]],"asks":[["0.07015","0.05000000"],["0.07016","1.55000000"]]}"#;
+    const L2UPDATE: &str = r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["buy","0.07010","0.00000000"],["sell","0.07014","0.25000000"]],"time":"2022-03-24T20:40:57.123456Z"}"#;
+
+    fn message(json: &str) -> Message {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn levels(levels: &[AnonymousLevel]) -> Vec<(Decimal, Decimal)> {
+        levels
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect()
+    }
+
+    fn entry(price: &str, amount: &str) -> (Decimal, Decimal) {
+        (price.parse().unwrap(), amount.parse().unwrap())
+    }
+
+    #[test]
+    fn documented_messages() {
+        assert!(matches!(message(SUBSCRIPTIONS), Message::Subscriptions));
+        match message(SNAPSHOT) {
+            Message::Snapshot {
+                product_id,
+                bids,
+                asks,
+            } => {
+                assert_eq!(product_id, "ETH-BTC");
+                assert_eq!(
+                    levels(&bids),
+                    vec![
+                        entry("0.07010", "6.00000000"),
+                        entry("0.07008", "0.50000000")
+                    ]
+                );
+                assert_eq!(
+                    levels(&asks),
+                    vec![
+                        entry("0.07015", "0.05000000"),
+                        entry("0.07016", "1.55000000")
+                    ]
+                );
+            }
+            other => panic!("expected a snapshot, got {other:?}"),
+        }
+        match message(L2UPDATE) {
+            Message::L2update {
+                product_id,
+                changes,
+            } => {
+                assert_eq!(product_id, "ETH-BTC");
+                assert_eq!(changes.len(), 2);
+                assert!(matches!(changes[0].0, ChangeSide::Buy));
+                assert!(matches!(changes[1].0, ChangeSide::Sell));
+                assert_eq!(
+                    (changes[1].1.into(), changes[1].2.into()),
+                    entry("0.07014", "0.25000000")
+                );
+            }
+            other => panic!("expected an l2update, got {other:?}"),
+        }
+        assert!(matches!(
+            message(r#"{"type":"heartbeat","sequence":90,"product_id":"ETH-BTC"}"#),
+            Message::Other
+        ));
+    }
+
+    #[test]
+    fn updates_before_the_snapshot_are_ignored() {
+        let mut book = ProductBook::new("ETH-BTC".to_string());
+        assert!(!book.on_message(message(L2UPDATE)));
+        assert!(book.top(10).asks.is_empty());
+
+        assert!(book.on_message(message(SNAPSHOT)));
+        assert_eq!(
+            levels(&book.top(10).asks),
+            vec![
+                entry("0.07015", "0.05000000"),
+                entry("0.07016", "1.55000000")
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_apply_to_the_snapshot() {
+        let mut book = ProductBook::new("ETH-BTC".to_string());
+        book.on_message(message(SNAPSHOT));
+        assert!(book.on_message(message(L2UPDATE)));
+
+        let top = book.top(10);
+        // the zero amount removed the best bid
+        assert_eq!(levels(&top.bids), vec![entry("0.07008", "0.50000000")]);
+        assert_eq!(
+            levels(&top.asks),
+            vec![
+                entry("0.07014", "0.25000000"),
+                entry("0.07015", "0.05000000"),
+                entry("0.07016", "1.55000000")
+            ]
+        );
+    }
+
+    #[test]
+    fn other_products_are_ignored() {
+        let mut book = ProductBook::new("ETH-BTC".to_string());
+        assert!(!book.on_message(message(&SNAPSHOT.replace("ETH-BTC", "ETH-USD"))));
+        book.on_message(message(SNAPSHOT));
+        assert!(!book.on_message(message(&L2UPDATE.replace("ETH-BTC", "ETH-USD"))));
+        assert_eq!(
+            levels(&book.top(10).bids),
+            vec![
+                entry("0.07010", "6.00000000"),
+                entry("0.07008", "0.50000000")
+            ]
+        );
+        assert!(!book.on_message(message(SUBSCRIPTIONS)));
+    }
+
+    #[test]
+    fn top_is_best_first() {
+        let mut book = ProductBook::new("ETH-BTC".to_string());
+        book.on_message(message(SNAPSHOT));
+        book.on_message(message(L2UPDATE));
+        book.on_message(message(
+            r#"{"type":"l2update","product_id":"ETH-BTC","changes":[["buy","0.07009","1.0"],["buy","0.07001","2.0"],["sell","0.07020","3.0"]]}"#,
+        ));
+
+        let top = book.top(2);
+        assert_eq!(
+            levels(&top.bids),
+            vec![entry("0.07009", "1.0"), entry("0.07008", "0.50000000")]
+        );
+        assert_eq!(
+            levels(&top.asks),
+            vec![
+                entry("0.07014", "0.25000000"),
+                entry("0.07015", "0.05000000")
+            ]
+        );
+    }
+}
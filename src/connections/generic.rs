@@ -0,0 +1,522 @@
+//! A connection to any exchange which publishes snapshots of its order book as JSON, configured at runtime.
+//!
+//! Rather than writing a dedicated module, a simple snapshot-style exchange can be described by a JSON
+//! configuration file:
+//!
+//! ```json
+//! {
+//!     "name": "example",
+//!     "symbol": "{BASE}-{QUOTE}",
+//!     "aliases": {"usdt": "ust"},
+//!     "endpoint": "wss://stream.example.com/ws/{symbol}",
+//!     "subscribe": {"op": "subscribe", "channel": "book.{SYMBOL}"},
+//!     "filter": [{"pointer": "/channel", "equals": "book"}],
+//!     "bids": "/data/bids",
+//!     "asks": "/data/asks",
+//!     "price_index": 0,
+//!     "amount_index": 1
+//! }
+//! ```
+//!
+//! - `symbol` describes how the exchange names markets. `{base}` and `{quote}` are replaced by the lowercase
+//!   currencies, and `{BASE}` and `{QUOTE}` by their uppercase forms. It defaults to `{base}{quote}`.
+//! - `aliases` is optional, and maps canonical currency names to the exchange's own names for them.
+//! - `endpoint` and every string within `subscribe` may contain `{symbol}`, which is replaced by the exchange's
+//!   symbol for the market, and `{SYMBOL}`, which is replaced by its uppercase form.
+//! - `subscribe` is optional; when present, it is sent once immediately after connecting.
+//! - `filter` is optional; when present, only messages for which the value at every [JSON pointer][pointer]
+//!   equals the given value are considered. Other messages are ignored.
+//! - `bids` and `asks` are JSON pointers to arrays of levels. Messages which lack either are ignored.
+//! - Each level is an array; `price_index` and `amount_index` locate the price and amount within it, and
+//!   default to `0` and `1`. Prices and amounts may be numbers or strings.
+//! - `compression` is optional, and may be `"gzip"` or `"deflate"` for exchanges which compress binary frames.
+//!
+//! Each message which passes the filter must contain a complete snapshot of the book.
+//!
+//! [pointer]: https://datatracker.ietf.org/doc/html/rfc6901
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    report_subscribed, Compression, ExchangeConnection, ExchangeUpdate, Frame, FrameDecoder,
+    Liveness, Silent, Watchdog,
+};
+use crate::{AnonymousLevel, Pair, SimpleOrderBook, StringDecimal};
+use futures::SinkExt;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, path::Path};
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+/// Description of a snapshot-style exchange.
+///
+/// See the [module documentation][self] for the file format.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct GenericJsonConfig {
+    pub name: String,
+    #[serde(default = "default_symbol")]
+    pub symbol: String,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    pub endpoint: String,
+    #[serde(default)]
+    pub subscribe: Option<Value>,
+    #[serde(default)]
+    pub filter: Vec<Condition>,
+    pub bids: String,
+    pub asks: String,
+    #[serde(default)]
+    pub price_index: usize,
+    #[serde(default = "default_amount_index")]
+    pub amount_index: usize,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+fn default_symbol() -> String {
+    "{base}{quote}".to_string()
+}
+
+fn default_amount_index() -> usize {
+    1
+}
+
+/// A message is only considered when the value at `pointer` equals `equals`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Condition {
+    pub pointer: String,
+    pub equals: Value,
+}
+
+/// Replace `{symbol}` and `{SYMBOL}` placeholders in a template.
+fn fill_template(template: &str, symbol: &str) -> String {
+    template
+        .replace("{symbol}", symbol)
+        .replace("{SYMBOL}", &symbol.to_uppercase())
+}
+
+/// Replace placeholders in every string within a JSON value.
+fn fill_value_template(template: &Value, symbol: &str) -> Value {
+    match template {
+        Value::String(s) => Value::String(fill_template(s, symbol)),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| fill_value_template(value, symbol))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), fill_value_template(value, symbol)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Manage a websocket connection to an exchange described by a [`GenericJsonConfig`].
+#[derive(Debug, Clone)]
+pub struct GenericJsonConnection {
+    name: &'static str,
+    config: GenericJsonConfig,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+}
+
+impl GenericJsonConnection {
+    /// Create a connection from a configuration.
+    ///
+    /// The name of the exchange must live for the remainder of the program, so it is leaked.
+    pub fn new(config: GenericJsonConfig) -> Self {
+        let name = Box::leak(config.name.clone().into_boxed_str());
+        GenericJsonConnection {
+            name,
+            config,
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+        }
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Read a configuration file and create a connection from it.
+    pub fn from_config_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|err| ConfigError::Io {
+            path: path.display().to_string(),
+            err,
+        })?;
+        let config = serde_json::from_reader(std::io::BufReader::new(file)).map_err(|err| {
+            ConfigError::Parse {
+                path: path.display().to_string(),
+                err,
+            }
+        })?;
+        Ok(Self::new(config))
+    }
+
+    /// `true` when the message satisfies every condition of the filter.
+    fn is_relevant(&self, message: &Value) -> bool {
+        self.config
+            .filter
+            .iter()
+            .all(|condition| message.pointer(&condition.pointer) == Some(&condition.equals))
+    }
+
+    /// Extract the levels at the given pointer.
+    ///
+    /// `Ok(None)` indicates that the message doesn't contain this side of the book at all.
+    fn levels(&self, message: &Value, pointer: &str) -> Result<Option<Vec<AnonymousLevel>>, Error> {
+        let levels = match message.pointer(pointer) {
+            Some(Value::Array(levels)) => levels,
+            Some(_) => return Err(Error::InvalidLevels(pointer.to_string())),
+            None => return Ok(None),
+        };
+
+        let element = |level: &Value, index: usize| -> Result<Decimal, Error> {
+            let value = level
+                .get(index)
+                .ok_or_else(|| Error::InvalidLevel(level.to_string()))?;
+            StringDecimal::deserialize(value)
+                .map(Into::into)
+                .map_err(|_| Error::InvalidLevel(level.to_string()))
+        };
+
+        levels
+            .iter()
+            .map(|level| {
+                Ok(AnonymousLevel {
+                    price: element(level, self.config.price_index)?,
+                    amount: element(level, self.config.amount_index)?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Extract an order book from the message, if it is relevant.
+    fn read_book(&self, message: Value) -> Result<SimpleOrderBook, Error> {
+        if !self.is_relevant(&message) {
+            return Err(Error::Irrelevant);
+        }
+        let bids = self.levels(&message, &self.config.bids)?;
+        let asks = self.levels(&message, &self.config.asks)?;
+        match (bids, asks) {
+            (Some(bids), Some(asks)) => Ok(SimpleOrderBook::new(bids, asks)),
+            _ => Err(Error::Irrelevant),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for GenericJsonConnection {
+    fn exchange_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        let pair = pair.with_aliases(
+            self.config
+                .aliases
+                .iter()
+                .map(|(canonical, alias)| (canonical.as_str(), alias.as_str())),
+        );
+        self.config
+            .symbol
+            .replace("{base}", &pair.base)
+            .replace("{quote}", &pair.quote)
+            .replace("{BASE}", &pair.base.to_uppercase())
+            .replace("{QUOTE}", &pair.quote.to_uppercase())
+    }
+
+    async fn connect(
+        &self,
+        symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        let name = self.name;
+        log::trace!("[{name}] entered `connect` for {symbol}");
+
+        let endpoint = fill_template(&self.config.endpoint, &symbol);
+        let decoder = FrameDecoder::new(self.config.compression);
+
+        let (mut stream, _response) = connect_websocket(&endpoint, &self.proxy)
+            .await
+            .map_err(into_box)?;
+        let mut watchdog = Watchdog::new(self.liveness);
+        if let Some(subscribe) = &self.config.subscribe {
+            let subscription_message = fill_value_template(subscribe, &symbol);
+            stream
+                .send(TungsteniteMessage::Text(subscription_message.to_string()))
+                .await
+                .map_err(into_box)?;
+        }
+        // we can't know how the exchange confirms a subscription, if it does at all
+        report_subscribed(&updates, name).await;
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await.map_err(into_box)? {
+            let book = match decoder
+                .decode::<Value, Error>(maybe_message)
+                .and_then(|frame| match frame {
+                    Frame::Message(message) => self.read_book(message),
+                    Frame::Reply(_) => unreachable!("generic decoder has no ping handler"),
+                }) {
+                Ok(book) => book,
+                Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{name}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+            };
+
+            if let Err(_send_err) = updates.send((name, book.into())).await {
+                log::warn!("[{name}] terminating due to send failure indicating receiver closed");
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{name}] websocket connection terminated");
+        Err(Box::new(Error::ConnectionDropped))
+    }
+}
+
+/// Problems loading a [`GenericJsonConfig`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read exchange configuration {path}")]
+    Io {
+        path: String,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("failed to parse exchange configuration {path}")]
+    Parse {
+        path: String,
+        #[source]
+        err: serde_json::Error,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("value at {0} is not an array of levels")]
+    InvalidLevels(String),
+    #[error("level does not contain a price and amount at the configured positions: {0}")]
+    InvalidLevel(String),
+    // boxed, because the tungstenite error is much larger than the others
+    #[error("websocket problem")]
+    Tungstenite(#[source] Box<TungsteniteError>),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl From<TungsteniteError> for Error {
+    fn from(err: TungsteniteError) -> Self {
+        Error::Tungstenite(Box::new(err))
+    }
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn connection(config: Value) -> GenericJsonConnection {
+        GenericJsonConnection::new(serde_json::from_value(config).unwrap())
+    }
+
+    fn example() -> GenericJsonConnection {
+        connection(json!({
+            "name": "example",
+            "symbol": "{BASE}-{QUOTE}",
+            "aliases": {"usdt": "ust"},
+            "endpoint": "wss://stream.example.com/ws/{symbol}",
+            "subscribe": {"op": "subscribe", "channel": "book.{SYMBOL}"},
+            "filter": [{"pointer": "/channel", "equals": "book"}],
+            "bids": "/data/bids",
+            "asks": "/data/asks",
+        }))
+    }
+
+    fn levels(levels: &[AnonymousLevel]) -> Vec<(Decimal, Decimal)> {
+        levels
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect()
+    }
+
+    fn entry(price: &str, amount: &str) -> (Decimal, Decimal) {
+        (price.parse().unwrap(), amount.parse().unwrap())
+    }
+
+    #[test]
+    fn defaults() {
+        let connection = connection(json!({
+            "name": "minimal",
+            "endpoint": "wss://stream.example.com/ws",
+            "bids": "/bids",
+            "asks": "/asks",
+        }));
+        assert_eq!(connection.exchange_name(), "minimal");
+        assert_eq!(
+            connection.exchange_symbol(&"eth/btc".parse().unwrap()),
+            "ethbtc"
+        );
+        assert_eq!(connection.config.price_index, 0);
+        assert_eq!(connection.config.amount_index, 1);
+        assert_eq!(connection.config.compression, Compression::None);
+        assert!(connection.config.subscribe.is_none());
+        // with no filter, everything is relevant
+        assert!(connection.is_relevant(&json!({"anything": "at all"})));
+    }
+
+    #[test]
+    fn symbols_use_aliases_and_case() {
+        assert_eq!(
+            example().exchange_symbol(&"btc/usdt".parse().unwrap()),
+            "BTC-UST"
+        );
+    }
+
+    #[test]
+    fn templates_are_filled_throughout() {
+        assert_eq!(
+            fill_template("wss://stream.example.com/ws/{symbol}", "eth-btc"),
+            "wss://stream.example.com/ws/eth-btc"
+        );
+        let template = json!({
+            "op": "subscribe",
+            "args": [{"channel": "book.{SYMBOL}", "instId": "{symbol}", "depth": 20}, "{symbol}@depth"],
+            "nested": {"deeper": ["{symbol}", true, null]},
+            "{symbol}": "keys are left alone",
+        });
+        assert_eq!(
+            fill_value_template(&template, "eth-btc"),
+            json!({
+                "op": "subscribe",
+                "args": [{"channel": "book.ETH-BTC", "instId": "eth-btc", "depth": 20}, "eth-btc@depth"],
+                "nested": {"deeper": ["eth-btc", true, null]},
+                "{symbol}": "keys are left alone",
+            })
+        );
+    }
+
+    #[test]
+    fn filtered_messages_are_irrelevant() {
+        let connection = example();
+        let message = json!({"channel": "trades", "data": {"bids": [], "asks": []}});
+        assert!(!connection.is_relevant(&message));
+        assert!(matches!(
+            connection.read_book(message),
+            Err(Error::Irrelevant)
+        ));
+        // a missing value doesn't satisfy the filter either
+        assert!(!connection.is_relevant(&json!({"data": {"bids": [], "asks": []}})));
+    }
+
+    #[test]
+    fn levels_may_be_strings_or_numbers() {
+        let book = example()
+            .read_book(json!({
+                "channel": "book",
+                "data": {
+                    "bids": [["0.07012", "6.5"], ["0.07011", "1.26"]],
+                    "asks": [[0.07015, 0.05], [0.07016, 1.6864241]],
+                },
+            }))
+            .unwrap();
+        assert_eq!(
+            levels(&book.bids),
+            vec![entry("0.07012", "6.5"), entry("0.07011", "1.26")]
+        );
+        assert_eq!(
+            levels(&book.asks),
+            vec![entry("0.07015", "0.05"), entry("0.07016", "1.6864241")]
+        );
+    }
+
+    #[test]
+    fn levels_are_found_by_index() {
+        let connection = connection(json!({
+            "name": "indexed",
+            "endpoint": "wss://stream.example.com/ws",
+            "bids": "/b",
+            "asks": "/a",
+            "price_index": 1,
+            "amount_index": 2,
+        }));
+        let book = connection
+            .read_book(json!({"b": [[1, "0.07012", "6.5"]], "a": [[1, "0.07015", "0.05"]]}))
+            .unwrap();
+        assert_eq!(levels(&book.bids), vec![entry("0.07012", "6.5")]);
+        assert_eq!(levels(&book.asks), vec![entry("0.07015", "0.05")]);
+
+        assert!(matches!(
+            connection.levels(&json!({"b": [["0.07012", "6.5"]]}), "/b"),
+            Err(Error::InvalidLevel(_))
+        ));
+    }
+
+    #[test]
+    fn missing_and_invalid_levels() {
+        let connection = example();
+        // a pointer which matches nothing means this side is absent, which isn't an error
+        assert!(matches!(
+            connection.levels(&json!({"data": {}}), "/data/bids"),
+            Ok(None)
+        ));
+        assert!(matches!(
+            connection.read_book(json!({"channel": "book", "data": {"bids": []}})),
+            Err(Error::Irrelevant)
+        ));
+        // but one which matches something other than an array is
+        assert!(matches!(
+            connection.levels(&json!({"data": {"bids": {}}}), "/data/bids"),
+            Err(Error::InvalidLevels(pointer)) if pointer == "/data/bids"
+        ));
+        assert!(matches!(
+            connection.levels(
+                &json!({"data": {"bids": [["0.07012", "lots"]]}}),
+                "/data/bids"
+            ),
+            Err(Error::InvalidLevel(_))
+        ));
+        assert!(matches!(
+            connection.levels(&json!({"data": {"bids": [["0.07012"]]}}), "/data/bids"),
+            Err(Error::InvalidLevel(_))
+        ));
+        assert!(matches!(
+            connection.levels(&json!({"data": {"bids": []}}), "/data/bids"),
+            Ok(Some(levels)) if levels.is_empty()
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+//! Connection implementation for HTX, formerly Huobi.
+//!
+//! HTX sends every message as a gzip-compressed binary frame. It also sends application-level pings,
+//! `{"ping": n}`, to which clients must reply `{"pong": n}` or be disconnected.
+//!
+//! After connecting, we subscribe to the `depth.step0` topic for the desired symbol, which repeatedly sends
+//! a snapshot of the top 150 levels at full price precision.
+//!
+//! Example data, after decompression:
+//!
+//! ```json
+//! {"ping":1648153256301}
+//! {"id":"spreadget","status":"ok","subbed":"market.ethbtc.depth.step0","ts":1648153256312}
+//! {"ch":"market.ethbtc.depth.step0","ts":1648153256402,"tick":{"bids":[[0.07012,6.5],[0.07011,1.26]],"asks":[[0.07015,0.05],[0.07016,1.6864241]],"version":158823730195,"ts":1648153256400}}
+//! ```
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    report_subscribed, Compression, ExchangeConnection, ExchangeUpdate, Frame, FrameDecoder,
+    Liveness, SequenceFilter, Silent, Watchdog,
+};
+use crate::{from_unix_millis, AnonymousLevel, Pair, SimpleOrderBook};
+use futures::SinkExt;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+const EXCHANGE_NAME: &str = "htx";
+
+/// Default websocket endpoint for HTX market data.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://api.huobi.pro/ws";
+
+/// Message type for HTX market data.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Tick { ch: String, tick: Tick },
+    Response(Response),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Tick {
+    bids: Vec<AnonymousLevel>,
+    asks: Vec<AnonymousLevel>,
+    version: u64,
+    /// Milliseconds since the Unix epoch.
+    ts: u64,
+}
+
+/// Response to a subscription request.
+#[derive(Debug, serde::Deserialize)]
+struct Response {
+    status: String,
+    #[serde(rename = "err-code")]
+    err_code: Option<String>,
+    #[serde(rename = "err-msg")]
+    err_msg: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Ping {
+    ping: u64,
+}
+
+/// Reply to HTX's application-level pings.
+fn pong(payload: &[u8]) -> Option<TungsteniteMessage> {
+    let Ping { ping } = serde_json::from_slice(payload).ok()?;
+    Some(TungsteniteMessage::Text(format!("{{\"pong\":{ping}}}")))
+}
+
+/// Manage a websocket connection to HTX.
+#[derive(Debug, Clone)]
+pub struct HtxConnection {
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+}
+
+impl Default for HtxConnection {
+    fn default() -> Self {
+        HtxConnection {
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+        }
+    }
+}
+
+impl HtxConnection {
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for HtxConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("")
+    }
+
+    async fn connect(
+        &self,
+        symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {symbol}");
+
+        let topic = format!("market.{symbol}.depth.step0");
+        let decoder = FrameDecoder::new(Compression::Gzip).with_ping_handler(pong);
+
+        let (mut stream, _response) = connect_websocket(&self.websocket_endpoint, &self.proxy)
+            .await
+            .map_err(into_box)?;
+        let mut watchdog = Watchdog::new(self.liveness);
+        let subscription_message = serde_json::json!({ "sub": topic, "id": "spreadget" });
+        stream
+            .send(TungsteniteMessage::Text(subscription_message.to_string()))
+            .await
+            .map_err(into_box)?;
+
+        let mut sequence = SequenceFilter::default();
+        while let Some(maybe_message) = watchdog.next(&mut stream).await.map_err(into_box)? {
+            let book = match decoder.decode::<Message, Error>(maybe_message) {
+                Ok(Frame::Reply(reply)) => {
+                    stream.send(reply).await.map_err(into_box)?;
+                    continue;
+                }
+                Ok(Frame::Message(Message::Tick { ch, tick })) if ch == topic => {
+                    if !sequence.is_fresh(tick.version) {
+                        log::debug!(
+                            "[{EXCHANGE_NAME}] discarding stale book at version {}",
+                            tick.version
+                        );
+                        continue;
+                    }
+                    SimpleOrderBook::new(tick.bids, tick.asks)
+                        .with_exchange_time(from_unix_millis(tick.ts))
+                }
+                Ok(Frame::Message(Message::Response(response))) if response.status != "ok" => {
+                    let err = Error::SubscriptionFailure(format!(
+                        "{}: {}",
+                        response.err_code.unwrap_or_default(),
+                        response.err_msg.unwrap_or_default()
+                    ));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+                Ok(Frame::Message(Message::Response(_))) => {
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Ok(Frame::Message(_)) | Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, book.into())).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Box::new(Error::ConnectionDropped))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("subscription failure: {0}")]
+    SubscriptionFailure(String),
+    #[error("websocket problem")]
+    Tungstenite(#[from] TungsteniteError),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &str) -> TungsteniteMessage {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        TungsteniteMessage::Binary(encoder.finish().unwrap())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn decode(message: TungsteniteMessage) -> Result<Frame<Message>, Error> {
+        FrameDecoder::new(Compression::Gzip)
+            .with_ping_handler(pong)
+            .decode(Ok(message))
+    }
+
+    #[test]
+    fn pings_get_pongs() {
+        match pong(br#"{"ping":1648153256301}"#) {
+            Some(TungsteniteMessage::Text(reply)) => assert_eq!(reply, r#"{"pong":1648153256301}"#),
+            other => panic!("expected a pong, got {other:?}"),
+        }
+        assert!(pong(br#"{"id":"spreadget","status":"ok"}"#).is_none());
+        assert!(pong(b"not json").is_none());
+    }
+
+    #[test]
+    fn compressed_pings_are_answered() {
+        match decode(gzip(r#"{"ping":1648153256301}"#)) {
+            Ok(Frame::Reply(TungsteniteMessage::Text(reply))) => {
+                assert_eq!(reply, r#"{"pong":1648153256301}"#)
+            }
+            other => panic!("expected a pong, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn documented_messages() {
+        match decode(gzip(
+            r#"{"id":"spreadget","status":"ok","subbed":"market.ethbtc.depth.step0","ts":1648153256312}"#,
+        )) {
+            Ok(Frame::Message(Message::Response(response))) => assert_eq!(response.status, "ok"),
+            other => panic!("expected a response, got {other:?}"),
+        }
+        match decode(gzip(
+            r#"{"ch":"market.ethbtc.depth.step0","ts":1648153256402,"tick":{"bids":[[0.07012,6.5],[0.07011,1.26]],"asks":[[0.07015,0.05],[0.07016,1.6864241]],"version":158823730195,"ts":1648153256400}}"#,
+        )) {
+            Ok(Frame::Message(Message::Tick { ch, tick })) => {
+                assert_eq!(ch, "market.ethbtc.depth.step0");
+                assert_eq!(tick.version, 158823730195);
+                assert_eq!(tick.ts, 1648153256400);
+                assert_eq!(tick.bids.len(), 2);
+                assert_eq!(tick.asks[1].amount, "1.6864241".parse().unwrap());
+            }
+            other => panic!("expected a tick, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn corrupt_frames_are_errors() {
+        let TungsteniteMessage::Binary(mut data) = gzip(r#"{"ping":1648153256301}"#) else {
+            unreachable!()
+        };
+        data.truncate(data.len() - 4);
+        assert!(matches!(
+            decode(TungsteniteMessage::Binary(data)),
+            Err(Error::Deserialization(_))
+        ));
+    }
+}
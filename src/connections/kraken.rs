@@ -0,0 +1,505 @@
+//! Connection implementation for Kraken.
+//!
+//! Kraken's v2 websocket API requires us to subscribe to the `book` channel after connecting. It then
+//! sends a snapshot of the book followed by incremental updates, which we apply to a local book.
+//!
+//! Every snapshot and update carries a CRC32 checksum of the top 10 levels on each side of the book,
+//! which we use to validate the local book. When the checksum doesn't match, we resubscribe to get a
+//! fresh snapshot. Computing the checksum requires formatting prices and quantities at the instrument's
+//! precision, so before subscribing to the book we learn that from the `instrument` channel.
+//!
+//! Example data:
+//!
+//! ```json
+//! {"method":"subscribe","result":{"channel":"book","depth":10,"snapshot":true,"symbol":"ETH/BTC"},"success":true,"time_in":"2022-03-24T20:40:56.301843Z","time_out":"2022-03-24T20:40:56.301923Z"}
+//! {"channel":"book","type":"snapshot","data":[{"symbol":"ETH/BTC","bids":[{"price":0.07012,"qty":6.5},{"price":0.07011,"qty":1.26}],"asks":[{"price":0.07015,"qty":0.05},{"price":0.07016,"qty":1.6864241}],"checksum":3364728420}]}
+//! {"channel":"book","type":"update","data":[{"symbol":"ETH/BTC","bids":[{"price":0.07012,"qty":0.0}],"asks":[],"checksum":4008261058,"timestamp":"2022-03-24T20:40:57.123456Z"}]}
+//! ```
+//!
+//! See <https://docs.kraken.com/api/docs/guides/spot-ws-book-v2> for details of the checksum.
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Gap, Liveness, Silent,
+    Watchdog,
+};
+use crate::{AnonymousLevel, LocalBook, Pair, Side, StringDecimal};
+use futures::SinkExt;
+use rust_decimal::Decimal;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+const EXCHANGE_NAME: &str = "kraken";
+
+/// Default websocket endpoint for Kraken's v2 API.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://ws.kraken.com/v2";
+
+/// The default book depth to which we subscribe.
+pub const DEFAULT_DEPTH: usize = 10;
+
+/// The checksum covers this many levels on each side of the book.
+const CHECKSUM_LEN: usize = 10;
+
+/// Message type for Kraken's v2 websocket API.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Channel(ChannelMessage),
+    Response(MethodResponse),
+}
+
+/// Data published on a channel.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+enum ChannelMessage {
+    Book {
+        #[serde(rename = "type")]
+        kind: UpdateKind,
+        data: Vec<BookData>,
+    },
+    Instrument {
+        data: InstrumentData,
+    },
+    /// Heartbeats, status updates, and so on.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UpdateKind {
+    Snapshot,
+    Update,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BookData {
+    symbol: String,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+    checksum: u32,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+struct Level {
+    price: StringDecimal,
+    qty: StringDecimal,
+}
+
+impl From<Level> for AnonymousLevel {
+    fn from(level: Level) -> Self {
+        AnonymousLevel {
+            price: level.price.into(),
+            amount: level.qty.into(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InstrumentData {
+    pairs: Vec<InstrumentPair>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InstrumentPair {
+    symbol: String,
+    price_precision: usize,
+    qty_precision: usize,
+}
+
+/// Response to a request such as `subscribe`.
+#[derive(Debug, serde::Deserialize)]
+struct MethodResponse {
+    method: String,
+    success: Option<bool>,
+    error: Option<String>,
+}
+
+/// The precision at which an instrument's prices and quantities are formatted.
+#[derive(Debug, Clone, Copy)]
+struct Precision {
+    price: usize,
+    qty: usize,
+}
+
+/// Manage a websocket connection to Kraken.
+#[derive(Debug, Clone)]
+pub struct KrakenConnection {
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    depth: usize,
+}
+
+impl Default for KrakenConnection {
+    fn default() -> Self {
+        KrakenConnection {
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            depth: DEFAULT_DEPTH,
+        }
+    }
+}
+
+impl KrakenConnection {
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Subscribe to a different book depth.
+    ///
+    /// Kraken accepts depths of 10, 25, 100, 500, and 1000.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    fn book_request(&self, method: &str, kraken_symbol: &str) -> TungsteniteMessage {
+        TungsteniteMessage::Text(
+            serde_json::json!({
+                "method": method,
+                "params": {
+                    "channel": "book",
+                    "symbol": [kraken_symbol],
+                    "depth": self.depth,
+                },
+            })
+            .to_string(),
+        )
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for KrakenConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("/").to_uppercase()
+    }
+
+    async fn connect(
+        &self,
+        kraken_symbol: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {kraken_symbol}");
+
+        let (mut stream, _response) = connect_websocket(&self.websocket_endpoint, &self.proxy)
+            .await
+            .map_err(into_box)?;
+        let mut watchdog = Watchdog::new(self.liveness);
+        let instrument_request = serde_json::json!({
+            "method": "subscribe",
+            "params": { "channel": "instrument" },
+        });
+        stream
+            .send(TungsteniteMessage::Text(instrument_request.to_string()))
+            .await
+            .map_err(into_box)?;
+
+        let mut precision = None;
+        let mut book = LocalBook::default();
+        // we only apply updates once we've received the snapshot which they update
+        let mut have_snapshot = false;
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await.map_err(into_box)? {
+            let message = match read_message::<Message, Error>(maybe_message) {
+                Ok(message) => message,
+                Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+            };
+
+            let (kind, data) = match message {
+                Message::Response(MethodResponse {
+                    method,
+                    success: Some(false),
+                    error,
+                }) => {
+                    let err = Error::SubscriptionFailure(format!(
+                        "{method}: {}",
+                        error.unwrap_or_default()
+                    ));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(into_box(err));
+                }
+                // the instrument channel is confirmed before we know enough to subscribe to the book
+                Message::Response(MethodResponse {
+                    method,
+                    success: Some(true),
+                    ..
+                }) if method == "subscribe" && precision.is_some() => {
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Message::Response(_) | Message::Channel(ChannelMessage::Other) => continue,
+                Message::Channel(ChannelMessage::Instrument { data }) => {
+                    let pair = data
+                        .pairs
+                        .into_iter()
+                        .find(|pair| pair.symbol == kraken_symbol);
+                    if let Some(pair) = pair {
+                        let is_first = precision.is_none();
+                        precision = Some(Precision {
+                            price: pair.price_precision,
+                            qty: pair.qty_precision,
+                        });
+                        if is_first {
+                            stream
+                                .send(self.book_request("subscribe", &kraken_symbol))
+                                .await
+                                .map_err(into_box)?;
+                        }
+                    } else if precision.is_none() {
+                        return Err(into_box(Error::UnsupportedSymbol(kraken_symbol)));
+                    }
+                    continue;
+                }
+                Message::Channel(ChannelMessage::Book { kind, data }) => (kind, data),
+            };
+
+            let precision = match precision {
+                Some(precision) => precision,
+                None => continue,
+            };
+
+            let mut is_valid = true;
+            for data in data.into_iter().filter(|data| data.symbol == kraken_symbol) {
+                match kind {
+                    UpdateKind::Snapshot => {
+                        book.replace(
+                            data.bids.into_iter().map(Into::into),
+                            data.asks.into_iter().map(Into::into),
+                        );
+                        have_snapshot = true;
+                    }
+                    UpdateKind::Update if have_snapshot => {
+                        book.apply_all(Side::Bid, data.bids.into_iter().map(Into::into));
+                        book.apply_all(Side::Ask, data.asks.into_iter().map(Into::into));
+                        book.truncate(self.depth);
+                    }
+                    UpdateKind::Update => continue,
+                }
+
+                let checksum = checksum(&book, precision);
+                if checksum != data.checksum {
+                    log::warn!(
+                        "[{EXCHANGE_NAME}] checksum mismatch (expected {}, computed {checksum}); resubscribing",
+                        data.checksum
+                    );
+                    is_valid = false;
+                }
+            }
+
+            if !have_snapshot {
+                continue;
+            }
+
+            let update = if is_valid {
+                ExchangeUpdate::Book(book.top(self.depth))
+            } else {
+                book.clear();
+                have_snapshot = false;
+                for method in ["unsubscribe", "subscribe"] {
+                    stream
+                        .send(self.book_request(method, &kraken_symbol))
+                        .await
+                        .map_err(into_box)?;
+                }
+                ExchangeUpdate::Gap(Gap::Checksum)
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, update)).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Box::new(Error::ConnectionDropped))
+    }
+}
+
+/// Compute Kraken's CRC32 checksum of the top of the book.
+///
+/// For each of the top 10 asks, then each of the top 10 bids, the price and quantity are formatted at the
+/// instrument's precision, the decimal point and any leading zeros removed, and the results concatenated.
+fn checksum(book: &LocalBook, precision: Precision) -> u32 {
+    fn push_formatted(buffer: &mut String, value: Decimal, precision: usize) {
+        let formatted = format!("{value:.precision$}").replace('.', "");
+        buffer.push_str(formatted.trim_start_matches('0'));
+    }
+
+    let mut buffer = String::new();
+    for level in book
+        .asks()
+        .take(CHECKSUM_LEN)
+        .chain(book.bids().take(CHECKSUM_LEN))
+    {
+        push_formatted(&mut buffer, level.price, precision.price);
+        push_formatted(&mut buffer, level.amount, precision.qty);
+    }
+    crc32fast::hash(buffer.as_bytes())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("symbol is not supported by {EXCHANGE_NAME}: {0}")]
+    UnsupportedSymbol(String),
+    #[error("subscription failure: {0}")]
+    SubscriptionFailure(String),
+    #[error("websocket problem")]
+    Tungstenite(#[from] TungsteniteError),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(levels: &[(&str, &str)]) -> Vec<AnonymousLevel> {
+        levels
+            .iter()
+            .map(|(price, amount)| AnonymousLevel {
+                price: price.parse().unwrap(),
+                amount: amount.parse().unwrap(),
+            })
+            .collect()
+    }
+
+    /// Levels from the example in Kraken's guide, formatted as it describes: asks then bids, best first, with
+    /// the decimal point and leading zeros removed.
+    #[test]
+    fn checksum_follows_documented_format() {
+        let bids = levels(&[
+            ("45283.5", "0.10000000"),
+            ("45283.4", "1.54582015"),
+            ("45282.1", "0.1"),
+            ("45281.0", "0.10000000"),
+        ]);
+        let asks = levels(&[
+            ("45285.2", "0.00100000"),
+            ("45286.4", "1.54582015"),
+            ("45287.0", "1"),
+        ]);
+        let mut book = LocalBook::default();
+        // the order in which levels arrive makes no difference
+        book.replace(bids.into_iter().rev(), asks.into_iter().rev());
+
+        let expected = concat!(
+            "452852",
+            "100000",
+            "452864",
+            "154582015",
+            "452870",
+            "100000000",
+            "452835",
+            "10000000",
+            "452834",
+            "154582015",
+            "452821",
+            "10000000",
+            "452810",
+            "10000000",
+        );
+        let precision = Precision { price: 1, qty: 8 };
+        assert_eq!(
+            checksum(&book, precision),
+            crc32fast::hash(expected.as_bytes())
+        );
+    }
+
+    #[test]
+    fn checksum_covers_only_the_top_levels() {
+        let bids: Vec<_> = (0..15)
+            .map(|i| AnonymousLevel {
+                price: Decimal::new(4500 - i, 1),
+                amount: Decimal::ONE,
+            })
+            .collect();
+        let asks: Vec<_> = (0..15)
+            .map(|i| AnonymousLevel {
+                price: Decimal::new(4600 + i, 1),
+                amount: Decimal::ONE,
+            })
+            .collect();
+        let precision = Precision { price: 1, qty: 8 };
+
+        let mut deep = LocalBook::default();
+        deep.replace(bids.clone(), asks.clone());
+        let mut shallow = LocalBook::default();
+        shallow.replace(
+            bids.into_iter().take(CHECKSUM_LEN),
+            asks.into_iter().take(CHECKSUM_LEN),
+        );
+        assert_eq!(checksum(&deep, precision), checksum(&shallow, precision));
+    }
+
+    /// The snapshot and update in the module documentation.
+    #[test]
+    fn example_checksums() {
+        let precision = Precision { price: 5, qty: 8 };
+        let mut book = LocalBook::default();
+        let messages = [
+            r#"{"channel":"book","type":"snapshot","data":[{"symbol":"ETH/BTC","bids":[{"price":0.07012,"qty":6.5},{"price":0.07011,"qty":1.26}],"asks":[{"price":0.07015,"qty":0.05},{"price":0.07016,"qty":1.6864241}],"checksum":3364728420}]}"#,
+            r#"{"channel":"book","type":"update","data":[{"symbol":"ETH/BTC","bids":[{"price":0.07012,"qty":0.0}],"asks":[],"checksum":4008261058,"timestamp":"2022-03-24T20:40:57.123456Z"}]}"#,
+        ];
+        for message in messages {
+            let (kind, mut data) = match serde_json::from_str(message).unwrap() {
+                Message::Channel(ChannelMessage::Book { kind, data }) => (kind, data),
+                other => panic!("unexpected message: {other:?}"),
+            };
+            let data = data.pop().unwrap();
+            let bids = data.bids.into_iter().map(Into::into);
+            let asks = data.asks.into_iter().map(Into::into);
+            match kind {
+                UpdateKind::Snapshot => book.replace(bids, asks),
+                UpdateKind::Update => {
+                    book.apply_all(Side::Bid, bids);
+                    book.apply_all(Side::Ask, asks);
+                }
+            }
+            assert_eq!(checksum(&book, precision), data.checksum);
+        }
+    }
+}
@@ -1,9 +1,18 @@
 pub mod binance;
+pub mod bitfinex;
 pub mod bitstamp;
+pub mod coinbase;
+pub mod generic;
+pub mod htx;
+pub mod kraken;
+pub mod okx;
+pub mod proxy;
 
-use crate::SimpleOrderBook;
+use crate::{Pair, SimpleOrderBook};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use serde::de::DeserializeOwned;
-use tokio::sync::mpsc::Sender;
+use std::{io::Read, time::Duration};
+use tokio::{sync::mpsc::Sender, time::Instant};
 use tokio_tungstenite::tungstenite::{
     error::Error as TungsteniteError, Message as TungsteniteMessage,
 };
@@ -15,36 +24,400 @@ pub trait ExchangeConnection {
     /// It will be used to identify the exchange in the order book.
     fn exchange_name(&self) -> &'static str;
 
+    /// Format a market as this exchange names it.
+    ///
+    /// The result is passed to [`connect`][Self::connect] as its `symbol`.
+    fn exchange_symbol(&self, pair: &Pair) -> String;
+
     /// Establish a websocket connection for the desired symbol, producing an async stream of order books
     /// for this connection.
     async fn connect(
         &self,
         symbol: String,
-        updates: Sender<(&'static str, SimpleOrderBook)>,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
     ) -> Result<(), Box<dyn 'static + std::error::Error + Send>>;
+
+    /// How many symbols this exchange can follow over a single connection.
+    fn max_symbols_per_connection(&self) -> usize {
+        1
+    }
+
+    /// Follow several symbols, sending each symbol's updates along its own route.
+    ///
+    /// This is never given more than [`max_symbols_per_connection`][Self::max_symbols_per_connection] routes.
+    /// Exchanges which can follow several symbols over one websocket connection do so; by default, each symbol
+    /// gets a connection of its own.
+    async fn connect_many(
+        &self,
+        routes: Vec<Route>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        connect_each(self, routes).await
+    }
+}
+
+/// Where the updates for one of a connection's symbols go.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// The exchange's symbol for the market.
+    pub symbol: String,
+    pub updates: Sender<(&'static str, ExchangeUpdate)>,
+}
+
+/// Follow each symbol over a connection of its own, failing as soon as any one of them fails.
+pub(crate) async fn connect_each<C: ExchangeConnection + Sync + ?Sized>(
+    connection: &C,
+    routes: Vec<Route>,
+) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+    futures::future::try_join_all(
+        routes
+            .into_iter()
+            .map(|route| connection.connect(route.symbol, route.updates)),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// What a connection reports to the aggregator.
+pub enum ExchangeUpdate {
+    /// The current state of the exchange's book.
+    Book(SimpleOrderBook),
+    /// The connection discovered that it had missed or misapplied data, so the last book it reported
+    /// can no longer be trusted.
+    ///
+    /// The connection resynchronizes by itself, and reports a fresh book once it has done so.
+    Gap(Gap),
+    /// The connection's lifecycle has moved on.
+    ///
+    /// Connections themselves only report [`Subscribed`][ConnectionState::Subscribed]; their supervisor
+    /// reports when they are connecting and when they have disconnected, and the aggregator infers the rest
+    /// from the books and gaps which they report.
+    State(ConnectionState),
+}
+
+impl From<SimpleOrderBook> for ExchangeUpdate {
+    fn from(book: SimpleOrderBook) -> Self {
+        ExchangeUpdate::Book(book)
+    }
+}
+
+/// Where a connection is in its lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is being established.
+    Connecting,
+    /// The exchange has accepted our subscription, but has yet to send a book.
+    Subscribed,
+    /// Books are arriving.
+    Streaming,
+    /// The connection is up, but its book can't currently be trusted, for the given reason.
+    Degraded(String),
+    /// The connection has ended, for the given reason.
+    Disconnected(String),
 }
 
-/// Convert a potential tungstenite message into a `SimpleOrderBook`.
+/// Tell the aggregator that the exchange has accepted our subscription.
+///
+/// A closed receiver is ignored here; the connection notices it when it next sends a book.
+pub(crate) async fn report_subscribed(
+    updates: &Sender<(&'static str, ExchangeUpdate)>,
+    exchange: &'static str,
+) {
+    let _ = updates
+        .send((exchange, ExchangeUpdate::State(ConnectionState::Subscribed)))
+        .await;
+}
+
+/// How a connection discovered that it had missed or misapplied data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gap {
+    /// A message's sequence number did not follow on from that of the previous message.
+    Sequence,
+    /// A message arrived out of order, and had to be discarded.
+    OutOfOrder,
+    /// The local book did not match the checksum published by the exchange.
+    Checksum,
+    /// The connection was replaced, and data may have been missed in between.
+    Reconnect,
+}
+
+impl std::fmt::Display for Gap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Gap::Sequence => "sequence gap",
+            Gap::OutOfOrder => "out-of-order message",
+            Gap::Checksum => "checksum mismatch",
+            Gap::Reconnect => "reconnection gap",
+        })
+    }
+}
+
+/// Track the sequence markers of a stream whose messages each contain a complete book.
+///
+/// Such streams can't have gaps in any meaningful sense, but messages which are duplicated or arrive
+/// out of order must be discarded, lest they replace a newer book with an older one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SequenceFilter<T> {
+    last: Option<T>,
+}
+
+impl<T> Default for SequenceFilter<T> {
+    fn default() -> Self {
+        SequenceFilter { last: None }
+    }
+}
+
+impl<T: PartialOrd + Copy> SequenceFilter<T> {
+    /// `true` when `marker` is newer than every marker seen so far, in which case it becomes the latest.
+    pub(crate) fn is_fresh(&mut self, marker: T) -> bool {
+        if self.last.is_some_and(|last| marker <= last) {
+            return false;
+        }
+        self.last = Some(marker);
+        true
+    }
+}
+
+/// Deserialize a potential tungstenite message.
 ///
 /// This function mainly exists to simplify the error-handling story.
 ///
 /// Certain messages are irrelevant and will be marked as such with the
 /// [`Error::irrelevant`] method. These messages should be discarded by
 /// the caller without breaking the message loop.
-pub(crate) fn read_book<Message, Err>(
+///
+/// Binary frames are accepted, but must not be compressed. For compressed streams, or those which
+/// require replies to application-level pings, use a [`FrameDecoder`].
+pub(crate) fn read_message<Message, Err>(
     event: Result<tokio_tungstenite::tungstenite::Message, TungsteniteError>,
-) -> Result<SimpleOrderBook, Err>
+) -> Result<Message, Err>
 where
-    Message: Into<SimpleOrderBook> + DeserializeOwned,
+    Message: DeserializeOwned,
     Err: Error + From<TungsteniteError> + From<serde_json::Error>,
 {
-    let message = event?;
-    if let TungsteniteMessage::Ping(_) | TungsteniteMessage::Pong(_) = &message {
-        return Err(Error::irrelevant());
+    match FrameDecoder::default().decode::<Message, Err>(event)? {
+        Frame::Message(message) => Ok(message),
+        Frame::Reply(_) => unreachable!("default decoder has no ping handler"),
+    }
+}
+
+/// How the payloads of binary frames are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Binary frames contain plain JSON.
+    #[default]
+    None,
+    /// Binary frames are gzip-compressed, as sent by HTX.
+    Gzip,
+    /// Binary frames are raw deflate streams, without zlib headers.
+    Deflate,
+}
+
+impl Compression {
+    fn decompress(self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Compression::None => return Ok(data),
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?
+            }
+            Compression::Deflate => {
+                flate2::read::DeflateDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?
+            }
+        };
+        Ok(decompressed)
+    }
+}
+
+/// Inspect a decoded payload; if it is an application-level ping, produce the reply to send.
+pub(crate) type PingHandler = fn(&[u8]) -> Option<TungsteniteMessage>;
+
+/// Either a deserialized message, or a reply which must be sent to keep the connection alive.
+#[derive(Debug)]
+pub(crate) enum Frame<Message> {
+    Message(Message),
+    Reply(TungsteniteMessage),
+}
+
+/// Decode websocket frames, decompressing binary payloads and answering application-level pings.
+///
+/// Websocket-level pings are answered automatically by tungstenite, but some exchanges send their own
+/// pings as ordinary data, and disconnect clients which don't reply.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FrameDecoder {
+    compression: Compression,
+    ping_handler: Option<PingHandler>,
+}
+
+impl FrameDecoder {
+    pub(crate) fn new(compression: Compression) -> Self {
+        FrameDecoder {
+            compression,
+            ping_handler: None,
+        }
+    }
+
+    pub(crate) fn with_ping_handler(mut self, ping_handler: PingHandler) -> Self {
+        self.ping_handler = Some(ping_handler);
+        self
+    }
+
+    /// Decode a potential tungstenite message.
+    ///
+    /// As with [`read_message`], irrelevant messages are marked with [`Error::irrelevant`].
+    /// Decompression failures are reported as deserialization errors.
+    pub(crate) fn decode<Message, Err>(
+        &self,
+        event: Result<tokio_tungstenite::tungstenite::Message, TungsteniteError>,
+    ) -> Result<Frame<Message>, Err>
+    where
+        Message: DeserializeOwned,
+        Err: Error + From<TungsteniteError> + From<serde_json::Error>,
+    {
+        let payload = match event? {
+            TungsteniteMessage::Ping(_) | TungsteniteMessage::Pong(_) => {
+                return Err(Error::irrelevant())
+            }
+            TungsteniteMessage::Binary(data) => self
+                .compression
+                .decompress(data)
+                .map_err(serde_json::Error::io)?,
+            message => message.into_data(),
+        };
+        log::debug!("{}", String::from_utf8_lossy(&payload));
+
+        if let Some(reply) = self.ping_handler.and_then(|handler| handler(&payload)) {
+            return Ok(Frame::Reply(reply));
+        }
+
+        let message: Message = serde_json::from_slice(&payload)?;
+        Ok(Frame::Message(message))
+    }
+}
+
+/// How to detect a connection which has silently stopped delivering data, such as a half-open TCP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Liveness {
+    /// If nothing at all arrives for this long, the connection is considered dead.
+    pub idle_timeout: Duration,
+    /// If nothing arrives for this long, the connection is probed with a ping, whose reply counts as activity.
+    pub ping_interval: Duration,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness::new(Duration::from_secs(15))
+    }
+}
+
+impl Liveness {
+    /// Consider a connection dead after `idle_timeout`, pinging it three times along the way.
+    pub fn new(idle_timeout: Duration) -> Self {
+        Liveness {
+            idle_timeout,
+            ping_interval: idle_timeout / 3,
+        }
+    }
+}
+
+/// A connection received nothing, not even a reply to a ping, within its idle timeout.
+///
+/// Connections report this as a distinct variant of their own error types, with this as its source,
+/// so that supervision can tell a silent connection apart from one which the exchange closed or rejected.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("nothing received for {0:?}")]
+pub struct Silent(pub Duration);
+
+impl Silent {
+    /// `true` when `err`, or any error in its chain of sources, is a [`Silent`].
+    pub fn is_cause_of(mut err: &(dyn 'static + std::error::Error)) -> bool {
+        loop {
+            if err.is::<Silent>() {
+                return true;
+            }
+            match err.source() {
+                Some(source) => err = source,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Enforce a [`Liveness`] policy on a websocket stream.
+///
+/// Call [`next`][Self::next] in place of the stream's own `next`.
+#[derive(Debug, Clone)]
+pub(crate) struct Watchdog {
+    liveness: Liveness,
+    /// An application-level ping, and the exchange's reply to it; websocket pings are used if `None`.
+    application_ping: Option<(&'static str, &'static str)>,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+impl Watchdog {
+    pub(crate) fn new(liveness: Liveness) -> Self {
+        let now = Instant::now();
+        Watchdog {
+            liveness,
+            application_ping: None,
+            last_received: now,
+            last_ping: now,
+        }
+    }
+
+    /// Probe the connection by sending `ping` as a text frame, to which the exchange replies `pong`.
+    ///
+    /// Replies are consumed by the watchdog rather than returned from [`next`][Self::next].
+    pub(crate) fn with_application_ping(mut self, ping: &'static str, pong: &'static str) -> Self {
+        self.application_ping = Some((ping, pong));
+        self
+    }
+
+    /// Wait for the next message from the stream, pinging it while it is quiet.
+    ///
+    /// Returns [`Silent`] if nothing arrives within the idle timeout. Failures to send a ping are
+    /// returned as though the stream had produced them.
+    pub(crate) async fn next<S>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Option<Result<TungsteniteMessage, TungsteniteError>>, Silent>
+    where
+        S: Stream<Item = Result<TungsteniteMessage, TungsteniteError>>
+            + Sink<TungsteniteMessage, Error = TungsteniteError>
+            + Unpin,
+    {
+        loop {
+            let deadline = self.last_received + self.liveness.idle_timeout;
+            let ping_at = self.last_received.max(self.last_ping) + self.liveness.ping_interval;
+
+            tokio::select! {
+                biased;
+
+                maybe_message = stream.next() => {
+                    self.last_received = Instant::now();
+                    match (maybe_message, self.application_ping) {
+                        (Some(Ok(TungsteniteMessage::Text(text))), Some((_, pong))) if text == pong => continue,
+                        (maybe_message, _) => return Ok(maybe_message),
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    return Err(Silent(self.liveness.idle_timeout));
+                }
+                _ = tokio::time::sleep_until(ping_at) => {}
+            }
+
+            log::trace!("probing quiet connection with a ping");
+            self.last_ping = Instant::now();
+            let ping = match self.application_ping {
+                Some((ping, _)) => TungsteniteMessage::Text(ping.to_string()),
+                None => TungsteniteMessage::Ping(Vec::new()),
+            };
+            if let Err(err) = stream.send(ping).await {
+                return Ok(Some(Err(err)));
+            }
+        }
     }
-    log::debug!("{message:?}");
-    let message: Message = serde_json::from_str(&message.into_text()?)?;
-    Ok(message.into())
 }
 
 pub trait Error {
@@ -54,3 +427,381 @@ pub trait Error {
     /// the underlying tungstenite library.
     fn irrelevant() -> Self;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[derive(Debug, thiserror::Error)]
+    enum TestError {
+        #[error("websocket problem")]
+        Tungstenite(#[from] TungsteniteError),
+        #[error("failed to deserialize message")]
+        Deserialization(#[from] serde_json::Error),
+        #[error("message was irrelevant")]
+        Irrelevant,
+    }
+
+    impl Error for TestError {
+        fn irrelevant() -> Self {
+            TestError::Irrelevant
+        }
+    }
+
+    const PAYLOAD: &str = r#"{"bids":[["0.07012","6.5"]],"asks":[["0.07015","0.05"]]}"#;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn decode(
+        decoder: FrameDecoder,
+        message: TungsteniteMessage,
+    ) -> Result<Frame<serde_json::Value>, TestError> {
+        decoder.decode(Ok(message))
+    }
+
+    fn reply_to_hello(payload: &[u8]) -> Option<TungsteniteMessage> {
+        (payload == b"hello").then(|| TungsteniteMessage::Text("goodbye".to_string()))
+    }
+
+    #[test]
+    fn decompression_round_trips() {
+        let payload = PAYLOAD.as_bytes();
+        assert_eq!(
+            Compression::None.decompress(payload.to_vec()).unwrap(),
+            payload
+        );
+        assert_eq!(
+            Compression::Gzip.decompress(gzip(payload)).unwrap(),
+            payload
+        );
+        assert_eq!(
+            Compression::Deflate.decompress(deflate(payload)).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn binary_frames_are_decompressed() {
+        let expected: serde_json::Value = serde_json::from_str(PAYLOAD).unwrap();
+        for (compression, data) in [
+            (Compression::None, PAYLOAD.as_bytes().to_vec()),
+            (Compression::Gzip, gzip(PAYLOAD.as_bytes())),
+            (Compression::Deflate, deflate(PAYLOAD.as_bytes())),
+        ] {
+            match decode(
+                FrameDecoder::new(compression),
+                TungsteniteMessage::Binary(data),
+            ) {
+                Ok(Frame::Message(message)) => assert_eq!(message, expected, "{compression:?}"),
+                other => panic!("{compression:?}: expected a message, got {other:?}"),
+            }
+        }
+        // text frames are never compressed
+        assert!(matches!(
+            decode(
+                FrameDecoder::new(Compression::Gzip),
+                TungsteniteMessage::Text(PAYLOAD.to_string())
+            ),
+            Ok(Frame::Message(_))
+        ));
+    }
+
+    #[test]
+    fn corrupt_frames_are_errors() {
+        for compression in [Compression::Gzip, Compression::Deflate] {
+            let mut data = match compression {
+                Compression::Gzip => gzip(PAYLOAD.as_bytes()),
+                _ => deflate(PAYLOAD.as_bytes()),
+            };
+            data.truncate(data.len() / 2);
+            assert!(
+                matches!(
+                    decode(
+                        FrameDecoder::new(compression),
+                        TungsteniteMessage::Binary(data)
+                    ),
+                    Err(TestError::Deserialization(_))
+                ),
+                "{compression:?}"
+            );
+        }
+        assert!(matches!(
+            decode(
+                FrameDecoder::new(Compression::Gzip),
+                TungsteniteMessage::Binary(b"not gzip at all".to_vec())
+            ),
+            Err(TestError::Deserialization(_))
+        ));
+        assert!(matches!(
+            decode(
+                FrameDecoder::default(),
+                TungsteniteMessage::Text("{\"bids\":".to_string())
+            ),
+            Err(TestError::Deserialization(_))
+        ));
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let decoder = FrameDecoder::new(Compression::Gzip).with_ping_handler(reply_to_hello);
+        match decode(decoder, TungsteniteMessage::Binary(gzip(b"hello"))) {
+            Ok(Frame::Reply(TungsteniteMessage::Text(reply))) => assert_eq!(reply, "goodbye"),
+            other => panic!("expected a reply, got {other:?}"),
+        }
+        assert!(matches!(
+            decode(
+                decoder,
+                TungsteniteMessage::Binary(gzip(PAYLOAD.as_bytes()))
+            ),
+            Ok(Frame::Message(_))
+        ));
+        // websocket-level pings are left to tungstenite
+        assert!(matches!(
+            decode(decoder, TungsteniteMessage::Ping(b"hello".to_vec())),
+            Err(TestError::Irrelevant)
+        ));
+    }
+
+    type Incoming =
+        tokio::sync::mpsc::UnboundedSender<Result<TungsteniteMessage, TungsteniteError>>;
+
+    /// An in-memory stand-in for a websocket, recording whatever is sent to it.
+    struct Loopback {
+        incoming:
+            tokio::sync::mpsc::UnboundedReceiver<Result<TungsteniteMessage, TungsteniteError>>,
+        sent: Vec<TungsteniteMessage>,
+    }
+
+    impl Loopback {
+        fn new() -> (Incoming, Self) {
+            let (sender, incoming) = tokio::sync::mpsc::unbounded_channel();
+            let loopback = Loopback {
+                incoming,
+                sent: Vec::new(),
+            };
+            (sender, loopback)
+        }
+    }
+
+    impl Stream for Loopback {
+        type Item = Result<TungsteniteMessage, TungsteniteError>;
+
+        fn poll_next(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            self.get_mut().incoming.poll_recv(cx)
+        }
+    }
+
+    impl Sink<TungsteniteMessage> for Loopback {
+        type Error = TungsteniteError;
+
+        fn poll_ready(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: std::pin::Pin<&mut Self>,
+            item: TungsteniteMessage,
+        ) -> Result<(), Self::Error> {
+            self.get_mut().sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    fn liveness() -> Liveness {
+        Liveness::new(Duration::from_secs(15))
+    }
+
+    /// Deliver `message` once `delay` has passed.
+    fn deliver_after(incoming: &Incoming, delay: Duration, message: TungsteniteMessage) {
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            // the test may have finished with the stream already
+            let _ = incoming.send(Ok(message));
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_connections_are_pinged() {
+        let (_incoming, mut stream) = Loopback::new();
+        let mut watchdog = Watchdog::new(liveness());
+
+        let waited = tokio::time::timeout(Duration::from_secs(4), watchdog.next(&mut stream)).await;
+        assert!(waited.is_err());
+        assert!(stream.sent.is_empty());
+
+        let waited = tokio::time::timeout(Duration::from_secs(2), watchdog.next(&mut stream)).await;
+        assert!(waited.is_err());
+        assert_eq!(stream.sent, [TungsteniteMessage::Ping(Vec::new())]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_after_the_idle_timeout() {
+        let (_incoming, mut stream) = Loopback::new();
+        let mut watchdog = Watchdog::new(liveness());
+        let start = Instant::now();
+
+        match watchdog.next(&mut stream).await {
+            Err(Silent(timeout)) => assert_eq!(timeout, Duration::from_secs(15)),
+            other => panic!("expected silence, got {other:?}"),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        // pinged at 5s and 10s; the deadline takes precedence over the ping due at 15s
+        assert_eq!(stream.sent.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn any_frame_resets_the_timer() {
+        let (incoming, mut stream) = Loopback::new();
+        let mut watchdog = Watchdog::new(liveness());
+        let start = Instant::now();
+
+        deliver_after(
+            &incoming,
+            Duration::from_secs(12),
+            TungsteniteMessage::Pong(Vec::new()),
+        );
+        assert!(matches!(
+            watchdog.next(&mut stream).await,
+            Ok(Some(Ok(TungsteniteMessage::Pong(_))))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(12));
+
+        deliver_after(
+            &incoming,
+            Duration::from_secs(14),
+            TungsteniteMessage::Text("{}".to_string()),
+        );
+        assert!(matches!(
+            watchdog.next(&mut stream).await,
+            Ok(Some(Ok(TungsteniteMessage::Text(_))))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(26));
+
+        assert!(watchdog.next(&mut stream).await.is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(41));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn application_pongs_are_consumed() {
+        let (incoming, mut stream) = Loopback::new();
+        let mut watchdog = Watchdog::new(liveness()).with_application_ping("ping", "pong");
+        let start = Instant::now();
+
+        deliver_after(
+            &incoming,
+            Duration::from_secs(6),
+            TungsteniteMessage::Text("pong".to_string()),
+        );
+        deliver_after(
+            &incoming,
+            Duration::from_secs(20),
+            TungsteniteMessage::Text("{}".to_string()),
+        );
+        // the pong kept the connection alive past the original deadline, but wasn't returned
+        match watchdog.next(&mut stream).await {
+            Ok(Some(Ok(TungsteniteMessage::Text(text)))) => assert_eq!(text, "{}"),
+            other => panic!("expected a message, got {other:?}"),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+        assert_eq!(stream.sent[0], TungsteniteMessage::Text("ping".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_streams_end() {
+        let (incoming, mut stream) = Loopback::new();
+        let mut watchdog = Watchdog::new(liveness());
+        drop(incoming);
+        assert!(matches!(watchdog.next(&mut stream).await, Ok(None)));
+    }
+
+    fn fresh(filter: &mut SequenceFilter<u64>, markers: &[u64]) -> Vec<bool> {
+        markers
+            .iter()
+            .map(|&marker| filter.is_fresh(marker))
+            .collect()
+    }
+
+    #[test]
+    fn in_order_markers_are_fresh() {
+        let mut filter = SequenceFilter::default();
+        assert_eq!(fresh(&mut filter, &[1, 2, 3]), [true, true, true]);
+    }
+
+    #[test]
+    fn duplicates_are_stale() {
+        let mut filter = SequenceFilter::default();
+        assert_eq!(
+            fresh(&mut filter, &[1, 1, 2, 2]),
+            [true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn out_of_order_markers_are_stale() {
+        let mut filter = SequenceFilter::default();
+        // a late marker doesn't wind the filter back, so the one after it is still compared with the newest
+        assert_eq!(
+            fresh(&mut filter, &[1, 3, 2, 3, 4]),
+            [true, true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn gaps_are_fresh() {
+        // each message is a complete book, so nothing is lost by skipping ahead
+        let mut filter = SequenceFilter::default();
+        assert_eq!(fresh(&mut filter, &[1, 5, 100]), [true, true, true]);
+    }
+
+    #[test]
+    fn new_filter_accepts_any_marker() {
+        // a reconnection starts a new filter, since the exchange may have restarted its sequence
+        let mut filter = SequenceFilter::default();
+        assert_eq!(fresh(&mut filter, &[100]), [true]);
+        let mut filter = SequenceFilter::default();
+        assert_eq!(fresh(&mut filter, &[1, 2]), [true, true]);
+    }
+
+    #[test]
+    fn markers_may_be_signed() {
+        // OKX's `seqId` is signed
+        let mut filter = SequenceFilter::default();
+        assert!(filter.is_fresh(-1_i64));
+        assert!(filter.is_fresh(0));
+        assert!(!filter.is_fresh(-1));
+    }
+}
@@ -0,0 +1,658 @@
+//! Connection implementation for OKX.
+//!
+//! After connecting to OKX's public websocket endpoint, we subscribe to one of its order book channels for
+//! the desired instrument. OKX identifies instruments like `ETH-BTC`, and sends each level as four strings:
+//! price, size, a deprecated field, and the number of orders.
+//!
+//! In [snapshot mode][OkxMode::Snapshot], we follow the `books5` channel, which repeatedly sends the top 5 levels.
+//!
+//! In [incremental mode][OkxMode::Incremental], we follow the `books` channel, which sends a 400-level snapshot
+//! followed by updates listing the levels which have changed; a size of `"0"` removes the level. Each message
+//! carries a `seqId`, and each update the `prevSeqId` of the message before it, so that we can detect gaps.
+//! Each message also carries a CRC32 checksum of the top 25 levels on each side of the book. Whenever there
+//! is a gap or the checksum doesn't match, we resubscribe to get a fresh snapshot.
+//!
+//! Example data:
+//!
+//! ```json
+//! {"event":"subscribe","arg":{"channel":"books","instId":"ETH-BTC"},"connId":"a4d3ae55"}
+//! {"arg":{"channel":"books","instId":"ETH-BTC"},"action":"snapshot","data":[{"asks":[["0.07015","0.05","0","1"],["0.07016","1.6864241","0","3"]],"bids":[["0.07012","6.5","0","2"],["0.07011","1.26","0","1"]],"ts":"1648153256301","checksum":807411960,"prevSeqId":-1,"seqId":123456}]}
+//! {"arg":{"channel":"books","instId":"ETH-BTC"},"action":"update","data":[{"asks":[],"bids":[["0.07012","0","0","0"]],"ts":"1648153256402","checksum":-1113122564,"prevSeqId":123456,"seqId":123457}]}
+//! ```
+//!
+//! See <https://www.okx.com/docs-v5/en/#order-book-trading-market-data-ws-order-book-channel> for details of
+//! the checksum.
+
+use super::{
+    proxy::{connect_websocket, ProxyConfig},
+    read_message, report_subscribed, ExchangeConnection, ExchangeUpdate, Gap, Liveness,
+    SequenceFilter, Silent, Watchdog,
+};
+use crate::{from_unix_millis, AnonymousLevel, Pair, Side, SimpleOrderBook};
+use futures::SinkExt;
+use rust_decimal::Decimal;
+use serde::{
+    de::{Error as _, IgnoredAny, SeqAccess},
+    Deserialize,
+};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::{
+    error::Error as TungsteniteError, Message as TungsteniteMessage,
+};
+
+const EXCHANGE_NAME: &str = "okx";
+
+/// Default websocket endpoint for OKX's public v5 API.
+pub const DEFAULT_WEBSOCKET_ENDPOINT: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+/// In incremental mode, this many of the best levels on each side are sent to the aggregator by default.
+pub const DEFAULT_INCREMENTAL_DEPTH_LEN: usize = 100;
+
+/// The checksum covers this many levels on each side of the book.
+const CHECKSUM_LEN: usize = 25;
+
+/// Message type for OKX's v5 websocket API.
+///
+/// `L` is the type of the levels: in snapshot mode we don't need anything but their values, but in incremental
+/// mode the checksum is computed over the original strings.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Message<L> {
+    Event(Event),
+    Data(DataMessage<L>),
+}
+
+/// Responses to subscription requests, and errors.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Error {
+        code: String,
+        msg: String,
+    },
+    /// Confirmation of a subscription.
+    Subscribe,
+    /// Confirmations of unsubscription, and so on.
+    #[serde(other)]
+    Other,
+}
+
+/// Data published on a channel.
+#[derive(Debug, serde::Deserialize)]
+struct DataMessage<L> {
+    arg: Arg,
+    action: Option<Action>,
+    data: Vec<BookData<L>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Arg {
+    inst_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Snapshot,
+    Update,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BookData<L> {
+    asks: Vec<L>,
+    bids: Vec<L>,
+    /// Milliseconds since the Unix epoch.
+    #[serde(deserialize_with = "u64_from_str")]
+    ts: u64,
+    checksum: Option<i32>,
+    seq_id: Option<i64>,
+    prev_seq_id: Option<i64>,
+}
+
+/// OKX sends its timestamps as strings.
+fn u64_from_str<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // not `&str`: the untagged `Message` buffers its content first, which may leave the strings owned
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// A level which retains the strings from which its price and size were parsed.
+#[derive(Debug, Clone)]
+struct RawLevel {
+    price: String,
+    size: String,
+    level: AnonymousLevel,
+}
+
+impl<'de> serde::Deserialize<'de> for RawLevel {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = RawLevel;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("list of at least two numeric strings")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut expect_element = |field_name: &'static str| {
+                    let value = seq
+                        .next_element::<String>()?
+                        .ok_or_else(|| A::Error::missing_field(field_name))?;
+                    let parsed = value.parse::<Decimal>().map_err(A::Error::custom)?;
+                    Ok::<_, A::Error>((value, parsed))
+                };
+
+                let (price, price_value) = expect_element("price")?;
+                let (size, size_value) = expect_element("size")?;
+
+                // the deprecated field and the order count
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+
+                Ok(RawLevel {
+                    price,
+                    size,
+                    level: AnonymousLevel {
+                        price: price_value,
+                        amount: size_value,
+                    },
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(Visitor)
+    }
+}
+
+/// A local book which retains the original strings of each level, so that we can compute checksums.
+#[derive(Debug, Default)]
+struct RawBook {
+    bids: BTreeMap<Decimal, RawLevel>,
+    asks: BTreeMap<Decimal, RawLevel>,
+}
+
+impl RawBook {
+    fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    fn apply_all(&mut self, side: Side, levels: impl IntoIterator<Item = RawLevel>) {
+        let book_side = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        for level in levels {
+            if level.level.amount.is_zero() {
+                book_side.remove(&level.level.price);
+            } else {
+                book_side.insert(level.level.price, level);
+            }
+        }
+    }
+
+    /// Iterate over the bids, best (highest) first.
+    fn bids(&self) -> impl '_ + Iterator<Item = &RawLevel> {
+        self.bids.values().rev()
+    }
+
+    /// Iterate over the asks, best (lowest) first.
+    fn asks(&self) -> impl '_ + Iterator<Item = &RawLevel> {
+        self.asks.values()
+    }
+
+    fn top(&self, depth: usize) -> SimpleOrderBook {
+        SimpleOrderBook::new(
+            self.bids().take(depth).map(|level| level.level).collect(),
+            self.asks().take(depth).map(|level| level.level).collect(),
+        )
+    }
+
+    /// Compute OKX's CRC32 checksum of the top of the book.
+    ///
+    /// The top 25 bids and asks are interleaved, bid first, as `price:size` using the original strings,
+    /// and all are joined with colons. The checksum is interpreted as a signed integer.
+    fn checksum(&self) -> i32 {
+        let mut bids = self.bids().take(CHECKSUM_LEN);
+        let mut asks = self.asks().take(CHECKSUM_LEN);
+        let mut fields = Vec::with_capacity(4 * CHECKSUM_LEN);
+        loop {
+            let bid = bids.next();
+            let ask = asks.next();
+            if bid.is_none() && ask.is_none() {
+                break;
+            }
+            for level in bid.into_iter().chain(ask) {
+                fields.push(level.price.as_str());
+                fields.push(level.size.as_str());
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+}
+
+/// Which of OKX's order book channels to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OkxMode {
+    /// Subscribe to the `books5` channel, which repeatedly sends the top 5 levels.
+    Snapshot,
+    /// Maintain a local book from the `books` channel, validated by sequence numbers and checksums.
+    Incremental,
+}
+
+/// Manage a websocket connection to OKX.
+#[derive(Debug, Clone)]
+pub struct OkxConnection {
+    mode: OkxMode,
+    websocket_endpoint: String,
+    liveness: Liveness,
+    proxy: ProxyConfig,
+    incremental_depth_len: usize,
+}
+
+impl Default for OkxConnection {
+    fn default() -> Self {
+        OkxConnection {
+            mode: OkxMode::Snapshot,
+            websocket_endpoint: DEFAULT_WEBSOCKET_ENDPOINT.to_string(),
+            liveness: Liveness::default(),
+            proxy: ProxyConfig::default(),
+            incremental_depth_len: DEFAULT_INCREMENTAL_DEPTH_LEN,
+        }
+    }
+}
+
+impl OkxConnection {
+    /// Create a connection in the given mode, using OKX's production endpoint.
+    pub fn new(mode: OkxMode) -> Self {
+        OkxConnection {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Connect to a different websocket endpoint, such as a local mock server.
+    pub fn with_websocket_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.websocket_endpoint = endpoint.into();
+        self
+    }
+
+    /// Detect a silent connection according to these settings instead of the defaults.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Reach the exchange according to this proxy configuration instead of the environment's.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// In incremental mode, send this many of the best levels on each side to the aggregator.
+    pub fn with_incremental_depth_len(mut self, len: usize) -> Self {
+        self.incremental_depth_len = len;
+        self
+    }
+
+    fn channel(&self) -> &'static str {
+        match self.mode {
+            OkxMode::Snapshot => "books5",
+            OkxMode::Incremental => "books",
+        }
+    }
+
+    fn request(&self, op: &str, inst_id: &str) -> TungsteniteMessage {
+        TungsteniteMessage::Text(
+            serde_json::json!({
+                "op": op,
+                "args": [{ "channel": self.channel(), "instId": inst_id }],
+            })
+            .to_string(),
+        )
+    }
+
+    /// OKX expects clients to keep quiet connections alive with a plain-text `ping`, to which it replies `pong`.
+    fn watchdog(&self) -> Watchdog {
+        Watchdog::new(self.liveness).with_application_ping("ping", "pong")
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeConnection for OkxConnection {
+    fn exchange_name(&self) -> &'static str {
+        EXCHANGE_NAME
+    }
+
+    fn exchange_symbol(&self, pair: &Pair) -> String {
+        pair.join("-").to_uppercase()
+    }
+
+    async fn connect(
+        &self,
+        inst_id: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+        log::trace!("[{EXCHANGE_NAME}] entered `connect` for {inst_id}");
+
+        let result = match self.mode {
+            OkxMode::Snapshot => self.follow_snapshots(inst_id, updates).await,
+            OkxMode::Incremental => self.follow_updates(inst_id, updates).await,
+        };
+        result.map_err(into_box)
+    }
+}
+
+impl OkxConnection {
+    /// Follow the `books5` channel, forwarding each snapshot as it arrives.
+    async fn follow_snapshots(
+        &self,
+        inst_id: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Error> {
+        let (mut stream, _response) =
+            connect_websocket(&self.websocket_endpoint, &self.proxy).await?;
+        let mut watchdog = self.watchdog();
+        stream.send(self.request("subscribe", &inst_id)).await?;
+        let mut sequence = SequenceFilter::default();
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await? {
+            let data = match read_message::<Message<AnonymousLevel>, Error>(maybe_message) {
+                Ok(Message::Data(message)) if message.arg.inst_id == inst_id => message.data,
+                Ok(Message::Event(Event::Subscribe)) => {
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Ok(Message::Event(Event::Error { code, msg })) => {
+                    let err = Error::Exchange(format!("{msg} ({code})"));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
+                }
+                Ok(_) | Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
+                }
+            };
+
+            for BookData {
+                bids,
+                asks,
+                ts,
+                seq_id,
+                ..
+            } in data
+            {
+                if let Some(seq_id) = seq_id {
+                    if !sequence.is_fresh(seq_id) {
+                        log::debug!("[{EXCHANGE_NAME}] discarding stale book at seqId {seq_id}");
+                        continue;
+                    }
+                }
+                if let Err(_send_err) = updates
+                    .send((
+                        EXCHANGE_NAME,
+                        SimpleOrderBook::new(bids, asks)
+                            .with_exchange_time(from_unix_millis(ts))
+                            .into(),
+                    ))
+                    .await
+                {
+                    log::warn!(
+                        "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Error::ConnectionDropped)
+    }
+
+    /// Follow the `books` channel, maintaining a local book.
+    async fn follow_updates(
+        &self,
+        inst_id: String,
+        updates: Sender<(&'static str, ExchangeUpdate)>,
+    ) -> Result<(), Error> {
+        let (mut stream, _response) =
+            connect_websocket(&self.websocket_endpoint, &self.proxy).await?;
+        let mut watchdog = self.watchdog();
+        stream.send(self.request("subscribe", &inst_id)).await?;
+
+        let mut book = RawBook::default();
+        // the `seqId` of the last message applied; `None` until we've received a snapshot
+        let mut last_seq_id = None;
+        // the `ts` of the last message applied; meaningful only once `last_seq_id` is set
+        let mut last_ts = 0;
+
+        while let Some(maybe_message) = watchdog.next(&mut stream).await? {
+            let (action, data) = match read_message::<Message<RawLevel>, Error>(maybe_message) {
+                Ok(Message::Data(DataMessage {
+                    arg,
+                    action: Some(action),
+                    data,
+                })) if arg.inst_id == inst_id => (action, data),
+                Ok(Message::Event(Event::Subscribe)) => {
+                    report_subscribed(&updates, EXCHANGE_NAME).await;
+                    continue;
+                }
+                Ok(Message::Event(Event::Error { code, msg })) => {
+                    let err = Error::Exchange(format!("{msg} ({code})"));
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
+                }
+                Ok(_) | Err(Error::Irrelevant) => continue,
+                Err(err) => {
+                    log::error!("[{EXCHANGE_NAME}] terminating due to error: {err}");
+                    return Err(err);
+                }
+            };
+
+            let mut gap = None;
+            for data in data {
+                match action {
+                    Action::Snapshot => {
+                        book.clear();
+                    }
+                    Action::Update if last_seq_id.is_some() => {
+                        if data.prev_seq_id == last_seq_id {
+                            // the update follows directly from the last one applied
+                        } else if data.seq_id <= last_seq_id {
+                            log::debug!(
+                                "[{EXCHANGE_NAME}] discarding stale update at seqId {:?}",
+                                data.seq_id
+                            );
+                            continue;
+                        } else {
+                            log::warn!(
+                                "[{EXCHANGE_NAME}] sequence gap (expected prevSeqId {:?}, got {:?}); resubscribing",
+                                last_seq_id,
+                                data.prev_seq_id,
+                            );
+                            gap = Some(Gap::Sequence);
+                            break;
+                        }
+                    }
+                    Action::Update => continue,
+                }
+
+                book.apply_all(Side::Bid, data.bids);
+                book.apply_all(Side::Ask, data.asks);
+                last_seq_id = data.seq_id;
+                last_ts = data.ts;
+
+                if let Some(expected) = data.checksum {
+                    let computed = book.checksum();
+                    if computed != expected {
+                        log::warn!(
+                            "[{EXCHANGE_NAME}] checksum mismatch (expected {expected}, computed {computed}); resubscribing"
+                        );
+                        gap = Some(Gap::Checksum);
+                        break;
+                    }
+                }
+            }
+
+            let update = match gap {
+                Some(gap) => {
+                    book.clear();
+                    last_seq_id = None;
+                    for op in ["unsubscribe", "subscribe"] {
+                        stream.send(self.request(op, &inst_id)).await?;
+                    }
+                    ExchangeUpdate::Gap(gap)
+                }
+                None if last_seq_id.is_none() => continue,
+                None => ExchangeUpdate::Book(
+                    book.top(self.incremental_depth_len)
+                        .with_exchange_time(from_unix_millis(last_ts)),
+                ),
+            };
+
+            if let Err(_send_err) = updates.send((EXCHANGE_NAME, update)).await {
+                log::warn!(
+                    "[{EXCHANGE_NAME}] terminating due to send failure indicating receiver closed"
+                );
+                return Ok(());
+            }
+        }
+
+        log::warn!("[{EXCHANGE_NAME}] websocket connection terminated");
+        Err(Error::ConnectionDropped)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("exchange reported an error: {0}")]
+    Exchange(String),
+    #[error("websocket problem")]
+    Tungstenite(#[from] TungsteniteError),
+    #[error("failed to deserialize message")]
+    Deserialization(#[from] serde_json::Error),
+    #[error("connection went silent")]
+    Silent(#[from] Silent),
+    #[error("connection dropped by host")]
+    ConnectionDropped,
+    #[error("message was irrelevant")]
+    Irrelevant,
+}
+
+impl super::Error for Error {
+    fn irrelevant() -> Self {
+        Error::Irrelevant
+    }
+}
+
+fn into_box(err: impl Into<Error>) -> Box<dyn 'static + std::error::Error + Send> {
+    Box::new(err.into()) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_levels(levels: &(impl serde::Serialize + ?Sized)) -> Vec<RawLevel> {
+        let json = serde_json::to_string(levels).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn raw_book(
+        bids: &(impl serde::Serialize + ?Sized),
+        asks: &(impl serde::Serialize + ?Sized),
+    ) -> RawBook {
+        let mut book = RawBook::default();
+        book.apply_all(Side::Bid, raw_levels(bids));
+        book.apply_all(Side::Ask, raw_levels(asks));
+        book
+    }
+
+    /// The examples in OKX's documentation of the checksum, which give the string to be hashed.
+    #[test]
+    fn documented_checksums() {
+        let book = raw_book(
+            &[["3366.1", "7", "0", "3"], ["3366", "6", "3", "4"]],
+            &[["3366.8", "9", "10", "3"], ["3368", "8", "3", "4"]],
+        );
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"3366.1:7:3366.8:9:3366:6:3368:8") as i32
+        );
+
+        // when one side is shorter, the other's remaining levels follow on
+        let book = raw_book(
+            &[["3366.1", "7", "0", "3"]],
+            &[
+                ["3366.8", "9", "10", "3"],
+                ["3368", "8", "3", "4"],
+                ["3372", "8", "3", "4"],
+            ],
+        );
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"3366.1:7:3366.8:9:3368:8:3372:8") as i32
+        );
+    }
+
+    #[test]
+    fn checksum_uses_the_original_strings() {
+        let book = raw_book(
+            &[["0.070120", "6.50", "0", "2"]],
+            &[["0.07015", "0.050", "0", "1"]],
+        );
+        assert_eq!(
+            book.checksum(),
+            crc32fast::hash(b"0.070120:6.50:0.07015:0.050") as i32
+        );
+    }
+
+    #[test]
+    fn checksum_covers_only_the_top_levels() {
+        let level = |price: usize| {
+            [
+                price.to_string(),
+                "1".to_string(),
+                "0".to_string(),
+                "1".to_string(),
+            ]
+        };
+        let bids: Vec<_> = (0..30).map(|i| level(1000 - i)).collect();
+        let asks: Vec<_> = (0..30).map(|i| level(2000 + i)).collect();
+        let deep = raw_book(&bids, &asks);
+        let shallow = raw_book(&bids[..CHECKSUM_LEN], &asks[..CHECKSUM_LEN]);
+        assert_eq!(deep.checksum(), shallow.checksum());
+    }
+
+    /// The snapshot and update in the module documentation.
+    #[test]
+    fn example_checksums() {
+        let messages = [
+            r#"{"arg":{"channel":"books","instId":"ETH-BTC"},"action":"snapshot","data":[{"asks":[["0.07015","0.05","0","1"],["0.07016","1.6864241","0","3"]],"bids":[["0.07012","6.5","0","2"],["0.07011","1.26","0","1"]],"ts":"1648153256301","checksum":807411960,"prevSeqId":-1,"seqId":123456}]}"#,
+            r#"{"arg":{"channel":"books","instId":"ETH-BTC"},"action":"update","data":[{"asks":[],"bids":[["0.07012","0","0","0"]],"ts":"1648153256402","checksum":-1113122564,"prevSeqId":123456,"seqId":123457}]}"#,
+        ];
+        let mut book = RawBook::default();
+        for message in messages {
+            let mut data = match serde_json::from_str::<Message<RawLevel>>(message).unwrap() {
+                Message::Data(message) => message.data,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            let data = data.pop().unwrap();
+            book.apply_all(Side::Bid, data.bids);
+            book.apply_all(Side::Ask, data.asks);
+            assert_eq!(Some(book.checksum()), data.checksum);
+        }
+        assert_eq!(book.top(1).bids[0].price, "0.07011".parse().unwrap());
+    }
+}
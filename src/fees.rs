@@ -0,0 +1,117 @@
+//! Accounting for what each exchange charges to trade, so that its prices can be compared with others'.
+
+use crate::{Liquidity, Side};
+use rust_decimal::Decimal;
+
+/// An exchange's trading fees, as fractions of the value traded.
+///
+/// A negative fee is a rebate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fees {
+    /// Paid for orders which join the book.
+    pub maker: Decimal,
+    /// Paid for orders which trade against the book.
+    pub taker: Decimal,
+}
+
+impl Fees {
+    /// Fees given in basis points, as exchanges usually quote them.
+    pub fn from_basis_points(maker: Decimal, taker: Decimal) -> Self {
+        let basis_point = Decimal::new(1, 4);
+        Fees {
+            maker: maker * basis_point,
+            taker: taker * basis_point,
+        }
+    }
+
+    /// What a unit at this price on this side of the book is worth once the fee is paid.
+    ///
+    /// Taking a bid sells, and taking an ask buys; joining a bid buys, and joining an ask sells. Buying costs more
+    /// than the price, and selling brings in less.
+    pub fn net_price(&self, liquidity: Liquidity, side: Side, price: Decimal) -> Decimal {
+        let (fee, is_buying) = match liquidity {
+            Liquidity::Taker => (self.taker, side == Side::Ask),
+            Liquidity::Maker => (self.maker, side == Side::Bid),
+        };
+        let net = if is_buying {
+            price * (Decimal::ONE + fee)
+        } else {
+            price * (Decimal::ONE - fee)
+        };
+        net.normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn from_basis_points() {
+        let fees = Fees::from_basis_points(decimal("2.5"), decimal("10"));
+        assert_eq!(fees.maker, decimal("0.00025"));
+        assert_eq!(fees.taker, decimal("0.001"));
+    }
+
+    #[test]
+    fn net_price_for_each_liquidity_and_side() {
+        // 10bp to make, 20bp to take
+        let fees = Fees::from_basis_points(decimal("10"), decimal("20"));
+        let price = decimal("100");
+
+        // taking an ask buys, at a higher cost
+        assert_eq!(
+            fees.net_price(Liquidity::Taker, Side::Ask, price),
+            decimal("100.2")
+        );
+        // taking a bid sells, for less
+        assert_eq!(
+            fees.net_price(Liquidity::Taker, Side::Bid, price),
+            decimal("99.8")
+        );
+        // joining the bids buys, at a higher cost
+        assert_eq!(
+            fees.net_price(Liquidity::Maker, Side::Bid, price),
+            decimal("100.1")
+        );
+        // joining the asks sells, for less
+        assert_eq!(
+            fees.net_price(Liquidity::Maker, Side::Ask, price),
+            decimal("99.9")
+        );
+    }
+
+    #[test]
+    fn rebates_improve_the_price() {
+        let fees = Fees::from_basis_points(decimal("-2"), decimal("5"));
+        let price = decimal("0.07012");
+        // a maker buying is paid a rebate, so it costs less than the price
+        assert_eq!(
+            fees.net_price(Liquidity::Maker, Side::Bid, price),
+            decimal("0.070105976")
+        );
+        // and a maker selling brings in more
+        assert_eq!(
+            fees.net_price(Liquidity::Maker, Side::Ask, price),
+            decimal("0.070134024")
+        );
+    }
+
+    #[test]
+    fn no_fees() {
+        let fees = Fees::default();
+        let price = decimal("0.07012000");
+        for liquidity in [Liquidity::Taker, Liquidity::Maker] {
+            for side in [Side::Bid, Side::Ask] {
+                let net = fees.net_price(liquidity, side, price);
+                assert_eq!(net, price);
+                // trailing zeros are dropped
+                assert_eq!(net.to_string(), "0.07012");
+            }
+        }
+    }
+}
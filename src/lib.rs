@@ -4,30 +4,203 @@
 
 pub mod connections;
 
+mod aggregated_book;
+pub use aggregated_book::{AggregatedBook, Crossing};
+
 mod anonymous_level;
 pub use anonymous_level::AnonymousLevel;
+pub(crate) use anonymous_level::StringDecimal;
+
+mod fees;
+pub use fees::Fees;
+
+mod local_book;
+pub use local_book::{LocalBook, Side};
+
+mod opportunities;
+use opportunities::Opportunities;
+
+mod pair;
+pub use pair::{Pair, UnrecognizedPair};
 
-use connections::ExchangeConnection;
-use float_ord::FloatOrd;
+pub mod supervisor;
+
+use connections::{ConnectionState, ExchangeConnection, ExchangeUpdate, Route};
 use futures::{stream::FuturesUnordered, Stream, StreamExt};
 use orderbook_aggregator_server::OrderbookAggregatorServer;
-use std::{net::SocketAddr, pin::Pin};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::{Duration, SystemTime},
+};
+use supervisor::{supervise, Supervisor};
 use tokio::{
     sync::{mpsc, oneshot, watch},
     task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream, WatchStream},
+    StreamMap,
 };
-use tokio_stream::wrappers::WatchStream;
 use tonic::{transport::Server, Request, Response, Status};
 
-tonic::include_proto!("orderbook");
+/// The messages and services of the gRPC interface.
+///
+/// Most are re-exported at the crate root; the wire form of a [`Level`] stays here.
+pub mod proto {
+    tonic::include_proto!("orderbook");
+}
+pub use proto::{
+    orderbook_aggregator_client, orderbook_aggregator_server, AsOf, ConnectionStatus,
+    ConsolidatedRequest, ConsolidatedSummary, ExchangeState, ExchangeStates, FeeAdjustedRequest,
+    FeeAdjustedSummary, Liquidity, MarketRequest, OpportunityEvent, OpportunityEventKind, Summary,
+};
+
+/// By default, a summary keeps track of only the best 10 bids/asks, as the instructions specify.
+pub const DEFAULT_SUMMARY_DEPTH: usize = 10;
+
+/// The most bids/asks which a client may ask a summary to keep track of.
+pub const MAX_SUMMARY_DEPTH: usize = 1000;
 
-/// The instructions specify that the summary keeps track of only the best 10 bids/asks.
-const SUMMARY_BID_ASK_LEN: usize = 10;
+/// By default, an exchange which sends no order book for this long has its levels evicted from the summary.
+pub const DEFAULT_FRESHNESS_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How often the aggregator checks whether any exchange's data has gone stale.
+const FRESHNESS_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 /// The simplest representation of an exchange's order book.
 pub struct SimpleOrderBook {
     pub bids: Vec<AnonymousLevel>,
     pub asks: Vec<AnonymousLevel>,
+    /// When the exchange says the book reached this state, if it says.
+    pub exchange_time: Option<SystemTime>,
+    /// When we received the data from which this book was built.
+    pub received_at: SystemTime,
+}
+
+impl SimpleOrderBook {
+    /// Create a book from data received just now.
+    pub fn new(bids: Vec<AnonymousLevel>, asks: Vec<AnonymousLevel>) -> Self {
+        SimpleOrderBook {
+            bids,
+            asks,
+            exchange_time: None,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    /// Record when the exchange says the book reached this state.
+    pub fn with_exchange_time(mut self, exchange_time: SystemTime) -> Self {
+        self.exchange_time = Some(exchange_time);
+        self
+    }
+}
+
+/// An offer to buy or sell something in a given price and quantity on a particular exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Level {
+    pub exchange: String,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+impl From<&Level> for proto::Level {
+    fn from(level: &Level) -> Self {
+        proto::Level {
+            exchange: level.exchange.clone(),
+            price: level.price.to_f64().unwrap_or_default(),
+            amount: level.amount.to_f64().unwrap_or_default(),
+            price_exact: level.price.to_string(),
+            amount_exact: level.amount.to_string(),
+        }
+    }
+}
+
+/// Every exchange's offers at a price, or within a tick of it, combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsolidatedLevel {
+    pub price: Decimal,
+    /// The total amount offered by every exchange.
+    pub amount: Decimal,
+    /// How much each exchange offers.
+    pub contributions: Vec<Contribution>,
+}
+
+/// How much one exchange offers towards a [`ConsolidatedLevel`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    pub exchange: String,
+    pub amount: Decimal,
+}
+
+impl ConsolidatedLevel {
+    pub(crate) fn new(price: Decimal, exchange: &str, amount: Decimal) -> Self {
+        ConsolidatedLevel {
+            price,
+            amount,
+            contributions: vec![Contribution {
+                exchange: exchange.to_string(),
+                amount,
+            }],
+        }
+    }
+
+    /// Add an exchange's offer at this price.
+    pub(crate) fn add(&mut self, exchange: &str, amount: Decimal) {
+        self.amount += amount;
+        match self
+            .contributions
+            .iter_mut()
+            .find(|contribution| contribution.exchange == exchange)
+        {
+            Some(contribution) => contribution.amount += amount,
+            None => self.contributions.push(Contribution {
+                exchange: exchange.to_string(),
+                amount,
+            }),
+        }
+    }
+}
+
+impl From<&ConsolidatedLevel> for proto::ConsolidatedLevel {
+    fn from(level: &ConsolidatedLevel) -> Self {
+        proto::ConsolidatedLevel {
+            price: level.price.to_f64().unwrap_or_default(),
+            amount: level.amount.to_f64().unwrap_or_default(),
+            price_exact: level.price.to_string(),
+            amount_exact: level.amount.to_string(),
+            contributions: level
+                .contributions
+                .iter()
+                .map(|contribution| proto::Contribution {
+                    exchange: contribution.exchange.clone(),
+                    amount: contribution.amount.to_f64().unwrap_or_default(),
+                    amount_exact: contribution.amount.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Microseconds since the Unix epoch, as published in the [`Summary`].
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Interpret an exchange timestamp in microseconds since the Unix epoch.
+pub(crate) fn from_unix_micros(micros: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_micros(micros)
+}
+
+/// Interpret an exchange timestamp in milliseconds since the Unix epoch.
+pub(crate) fn from_unix_millis(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
 }
 
 /// Concatenate an error and all its sources into a colon-separated string.
@@ -47,6 +220,8 @@ pub fn concatenate_errors(mut err: &dyn std::error::Error) -> String {
 ///
 /// Note that this does not take any particular action if a task concludes with an `Ok` result.
 /// If a task exits in this way, other tasks will continue running.
+///
+/// Supervised connection tasks only conclude with an error under [`RestartPolicy::FailAll`][supervisor::RestartPolicy::FailAll].
 async fn handle_join_handles(
     mut join_handles: FuturesUnordered<
         JoinHandle<Result<(), Box<dyn 'static + std::error::Error + Send>>>,
@@ -75,9 +250,9 @@ async fn handle_join_handles(
                 );
                 log::error!("{message}");
 
-                // If we've exited with an error condition, clean up all the other tasks instead of letting
-                // the system run with an incomplete set of connections. This forces a restart of the entire
-                // system by some external user.
+                // If we've exited with an error condition, the supervision policy has asked us to clean up
+                // all the other tasks instead of letting the system run with an incomplete set of connections.
+                // This forces a restart of the entire system by some external user.
                 for handle in join_handles.iter() {
                     handle.abort();
                 }
@@ -94,161 +269,991 @@ async fn handle_join_handles(
 /// Aggregate the order books of several exchanges into the best bids and asks from each combined.
 ///
 /// In general, the order of operations will be to create the instance with `new`, launch a grpc service (if desired)
-/// with `launch_grpc_service`, and then begin aggregation with `aggregate_orderbooks`.
+/// with `launch_grpc_service`, and then begin aggregation with `aggregate_orderbooks` or `aggregate_markets`.
 #[derive(Debug)]
 pub struct OrderbookAggregator {
-    summary: Summary,
-    summary_sender: watch::Sender<Summary>,
-    summary_receiver: watch::Receiver<Summary>,
+    feeds: MarketFeeds,
+    supervisor: Supervisor,
+    freshness_deadline: Duration,
+    freshness_overrides: HashMap<String, Duration>,
+    summary_depth: usize,
+    fees: HashMap<String, Fees>,
+}
+
+/// Where the gRPC service finds each market's summaries and connection states.
+///
+/// Markets are appended when aggregation begins.
+type MarketFeeds = Arc<RwLock<Vec<MarketFeed>>>;
+
+#[derive(Debug, Clone)]
+struct MarketFeed {
+    pair: Pair,
+    summary: watch::Receiver<Summary>,
+    states: watch::Receiver<ExchangeStates>,
+    subscriptions: Arc<Subscriptions>,
+    opportunities: Arc<Mutex<Opportunities>>,
+}
+
+/// What gRPC clients are streaming from a market.
+///
+/// The market keeps as many levels as the deepest request, or its default depth if that is deeper, and publishes
+/// each consolidated or fee-adjusted view which clients have asked for.
+#[derive(Debug)]
+struct Subscriptions {
+    default_depth: usize,
+    state: Mutex<SubscriptionState>,
+    /// Tells the market whenever a client subscribes or unsubscribes.
+    changed: watch::Sender<()>,
+}
+
+#[derive(Debug, Default)]
+struct SubscriptionState {
+    /// How many clients have asked for each depth.
+    depths: BTreeMap<usize, usize>,
+    /// The consolidated views which clients are streaming, by tick.
+    consolidated: BTreeMap<Decimal, View<ConsolidatedSummary>>,
+    /// The fee-adjusted views which clients are streaming, by whose fees they account for.
+    fee_adjusted: BTreeMap<Liquidity, View<FeeAdjustedSummary>>,
+}
+
+/// A view of a market which the market publishes only while clients are streaming it.
+#[derive(Debug)]
+struct View<T> {
+    clients: usize,
+    sender: watch::Sender<T>,
+    /// Whether the market has published this view since a client first asked for it.
+    published: bool,
+}
+
+impl<T: Default> View<T> {
+    /// Stream the view with this key, which the market starts publishing if nobody was streaming it.
+    fn join<K: Ord>(views: &mut BTreeMap<K, View<T>>, key: K) -> watch::Receiver<T> {
+        let view = views.entry(key).or_insert_with(|| View {
+            clients: 0,
+            sender: watch::channel(T::default()).0,
+            published: false,
+        });
+        view.clients += 1;
+        view.sender.subscribe()
+    }
+
+    /// Stop streaming the view with this key, which the market stops publishing if nobody else is streaming it.
+    fn leave<K: Ord>(views: &mut BTreeMap<K, View<T>>, key: K) {
+        if let Entry::Occupied(mut entry) = views.entry(key) {
+            entry.get_mut().clients -= 1;
+            if entry.get().clients == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Publish every view, or only those which clients have yet to see.
+    fn publish_all<K>(
+        views: &mut BTreeMap<K, View<T>>,
+        everything: bool,
+        summarize: impl Fn(&K) -> T,
+    ) {
+        for (key, view) in views.iter_mut() {
+            if view.published && !everything {
+                continue;
+            }
+            // clients which have just left may have taken the last receiver with them
+            let _ = view.sender.send(summarize(key));
+            view.published = true;
+        }
+    }
+}
+
+/// Which view a client is streaming.
+#[derive(Debug, Clone, Copy)]
+enum ViewKey {
+    Consolidated(Decimal),
+    FeeAdjusted(Liquidity),
+}
+
+impl Subscriptions {
+    fn new(default_depth: usize) -> (Arc<Self>, watch::Receiver<()>) {
+        let (changed, receiver) = watch::channel(());
+        let subscriptions = Subscriptions {
+            default_depth,
+            state: Mutex::default(),
+            changed,
+        };
+        (Arc::new(subscriptions), receiver)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SubscriptionState> {
+        self.state
+            .lock()
+            .expect("no thread panics while holding the lock")
+    }
+
+    /// Stream summaries this deep for as long as the returned subscription lives.
+    fn subscribe(self: &Arc<Self>, depth: usize) -> Subscription {
+        self.subscription(depth, None)
+    }
+
+    /// Stream consolidated summaries this deep, grouped into buckets of this tick, for as long as the returned
+    /// subscription lives.
+    fn subscribe_consolidated(
+        self: &Arc<Self>,
+        depth: usize,
+        tick: Decimal,
+    ) -> (Subscription, watch::Receiver<ConsolidatedSummary>) {
+        let receiver = View::join(&mut self.lock().consolidated, tick);
+        (
+            self.subscription(depth, Some(ViewKey::Consolidated(tick))),
+            receiver,
+        )
+    }
+
+    /// Stream fee-adjusted summaries this deep, accounting for fees paid for this liquidity, for as long as the
+    /// returned subscription lives.
+    fn subscribe_fee_adjusted(
+        self: &Arc<Self>,
+        depth: usize,
+        liquidity: Liquidity,
+    ) -> (Subscription, watch::Receiver<FeeAdjustedSummary>) {
+        let receiver = View::join(&mut self.lock().fee_adjusted, liquidity);
+        (
+            self.subscription(depth, Some(ViewKey::FeeAdjusted(liquidity))),
+            receiver,
+        )
+    }
+
+    fn subscription(self: &Arc<Self>, depth: usize, view: Option<ViewKey>) -> Subscription {
+        *self.lock().depths.entry(depth).or_default() += 1;
+        self.notify();
+        Subscription {
+            subscriptions: self.clone(),
+            depth,
+            view,
+        }
+    }
+
+    fn unsubscribe(&self, subscription: &Subscription) {
+        {
+            let mut state = self.lock();
+            if let Entry::Occupied(mut entry) = state.depths.entry(subscription.depth) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+            match subscription.view {
+                Some(ViewKey::Consolidated(tick)) => View::leave(&mut state.consolidated, tick),
+                Some(ViewKey::FeeAdjusted(liquidity)) => {
+                    View::leave(&mut state.fee_adjusted, liquidity)
+                }
+                None => {}
+            }
+        }
+        self.notify();
+    }
+
+    /// How many levels the market must keep for the deepest request.
+    fn deepest(&self) -> usize {
+        self.lock()
+            .depths
+            .keys()
+            .next_back()
+            .map_or(self.default_depth, |deepest| {
+                (*deepest).max(self.default_depth)
+            })
+    }
+
+    fn notify(&self) {
+        // the receiver only goes away once the market is no longer aggregated, when nobody needs to know
+        let _ = self.changed.send(());
+    }
+}
+
+/// A client's stream from a market, withdrawn when dropped.
+#[derive(Debug)]
+struct Subscription {
+    subscriptions: Arc<Subscriptions>,
+    depth: usize,
+    /// The view which the client is streaming, if not the summary itself.
+    view: Option<ViewKey>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscriptions.unsubscribe(self);
+    }
+}
+
+impl Default for OrderbookAggregator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OrderbookAggregator {
     /// Create an orderbook aggregator.
     pub fn new() -> Self {
-        let summary = Summary::default();
-        let (summary_sender, summary_receiver) = watch::channel(summary.clone());
         Self {
-            summary,
-            summary_sender,
-            summary_receiver,
+            feeds: MarketFeeds::default(),
+            supervisor: Supervisor::default(),
+            freshness_deadline: DEFAULT_FRESHNESS_DEADLINE,
+            freshness_overrides: HashMap::new(),
+            summary_depth: DEFAULT_SUMMARY_DEPTH,
+            fees: HashMap::new(),
         }
     }
 
+    /// Set how exchange connections are restarted when they fail.
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = supervisor;
+        self
+    }
+
+    /// Set how long any exchange may go without sending an order book before its levels are evicted.
+    pub fn with_freshness_deadline(mut self, deadline: Duration) -> Self {
+        self.freshness_deadline = deadline;
+        self
+    }
+
+    /// Set how long the named exchange may go without sending an order book before its levels are evicted.
+    ///
+    /// This overrides the general freshness deadline for that exchange.
+    pub fn with_exchange_freshness_deadline(
+        mut self,
+        exchange: impl Into<String>,
+        deadline: Duration,
+    ) -> Self {
+        self.freshness_overrides.insert(exchange.into(), deadline);
+        self
+    }
+
+    /// Set how many of the best bids and asks a summary keeps track of when a client doesn't ask for a depth.
+    pub fn with_summary_depth(mut self, depth: usize) -> Self {
+        self.summary_depth = depth;
+        self
+    }
+
+    /// Set the fees which the named exchange charges, for fee-adjusted summaries.
+    ///
+    /// Exchanges whose fees aren't set are taken to charge none.
+    pub fn with_exchange_fees(mut self, exchange: impl Into<String>, fees: Fees) -> Self {
+        self.fees.insert(exchange.into(), fees);
+        self
+    }
+
     /// Spawn a new task listening on the specified address and serving gRPC requests, returning immediately.
     pub fn launch_grpc_service(&self, address: SocketAddr) {
-        let summary_receiver = self.summary_receiver.clone();
-        let service =
-            OrderbookAggregatorServer::new(OrderbookAggregatorService { summary_receiver });
+        let service = OrderbookAggregatorServer::new(OrderbookAggregatorService {
+            feeds: self.feeds.clone(),
+        });
         tokio::spawn(async move {
             log::info!("Listening for gRPC connections on {}", address);
             Server::builder().add_service(service).serve(address).await
         });
     }
 
-    /// Begin aggregating order books.
+    /// Begin aggregating order books for a single market.
     ///
-    /// This continuously updates a merged order book owned by `self`. It spawns a task per connection.
-    ///
-    /// `symbol` is the general market symbol we are interested in.
+    /// See [`aggregate_markets`][Self::aggregate_markets].
     pub async fn aggregate_orderbooks(
         &mut self,
-        symbol: &str,
+        pair: &Pair,
         connections: impl IntoIterator<Item = Box<dyn ExchangeConnection + Sync + Send>>,
     ) {
-        log::trace!("entered `aggregate_orderbooks` for {symbol}");
+        self.aggregate_markets(std::slice::from_ref(pair), connections)
+            .await
+    }
 
-        let (orderbook_sender, mut orderbook_receiver) = mpsc::channel(16);
+    /// Begin aggregating order books for several markets at once, each into a summary of its own.
+    ///
+    /// This continuously updates a merged order book per market. It spawns supervised tasks for each connection,
+    /// each of which follows as many markets as its exchange permits over a single websocket.
+    ///
+    /// An exchange's levels are evicted from a market's merged book whenever its connection ends, when it reports a
+    /// gap in its data, or when it has not sent an order book within its freshness deadline.
+    ///
+    /// Each connection's lifecycle is tracked alongside, and published as [`ExchangeStates`].
+    ///
+    /// `pairs` are the markets we are interested in; each connection is given its own exchange's symbols for them.
+    pub async fn aggregate_markets(
+        &mut self,
+        pairs: &[Pair],
+        connections: impl IntoIterator<Item = Box<dyn ExchangeConnection + Sync + Send>>,
+    ) {
+        log::trace!("entered `aggregate_markets` for {} markets", pairs.len());
+
+        let mut markets: Vec<_> = pairs
+            .iter()
+            .map(|pair| Market::new(pair.clone(), self.summary_depth, self.fees.clone()))
+            .collect();
+        self.feeds
+            .write()
+            .expect("no thread panics while holding the lock")
+            .extend(markets.iter().map(Market::feed));
+
+        // each market has its own channel, so that connections needn't say which market an update is for
+        let mut senders = Vec::with_capacity(markets.len());
+        let mut updates = StreamMap::new();
+        for index in 0..markets.len() {
+            let (sender, receiver) = mpsc::channel(16);
+            senders.push(sender);
+            updates.insert(index, ReceiverStream::new(receiver));
+        }
+
+        // clients can ask for deeper or consolidated summaries at any time
+        let mut subscriptions = StreamMap::new();
+        for (index, market) in markets.iter().enumerate() {
+            subscriptions.insert(index, WatchStream::new(market.subscriptions.clone()));
+        }
 
         let join_handles = FuturesUnordered::new();
 
         for connection in connections.into_iter() {
-            let symbol = symbol.to_string();
-            let sender = orderbook_sender.clone();
+            let connection: Arc<dyn ExchangeConnection + Sync + Send> = connection.into();
+            let supervision = self.supervisor.supervision_for(connection.exchange_name());
+            let routes: Vec<_> = pairs
+                .iter()
+                .zip(senders.iter())
+                .map(|(pair, sender)| Route {
+                    symbol: connection.exchange_symbol(pair),
+                    updates: sender.clone(),
+                })
+                .collect();
 
-            join_handles.push(tokio::spawn(async move {
-                connection.connect(symbol, sender).await
-            }));
+            for routes in routes.chunks(connection.max_symbols_per_connection().max(1)) {
+                join_handles.push(tokio::spawn(supervise(
+                    connection.clone(),
+                    routes.to_vec(),
+                    supervision,
+                )));
+            }
         }
 
         let mut is_shutting_down = false;
         let (joined_tx, mut joined_rx) = oneshot::channel();
         tokio::spawn(handle_join_handles(join_handles, joined_tx));
 
-        // now pull all the simple order books from the channel and merge them into the aggregate summary.
+        let mut freshness_check = tokio::time::interval(FRESHNESS_CHECK_INTERVAL);
+        freshness_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // now pull all the updates from the channels and merge them into each market's summary.
         //
-        // this is a loop-select-match construct instead of just `while let Some(...) = orderbook_receiver.recv().await` because
-        // we need the join handle monitor to be able to notify us that it's time to shut down.
+        // this is a loop-select-match construct instead of just `while let Some(...) = updates.next().await` because
+        // we need the join handle monitor to be able to notify us that it's time to shut down, and we need to
+        // evict exchanges whose data is no longer trustworthy.
         loop {
             tokio::select! {
                 // if the join handle monitor indicates that all channels have closed, then we can close the
-                // orderbook receiver for a graceful shutdown.
+                // update receivers for a graceful shutdown.
                 _ = &mut joined_rx, if !is_shutting_down => {
-                    orderbook_receiver.close();
+                    for (_, receiver) in updates.iter_mut() {
+                        receiver.close();
+                    }
                     is_shutting_down = true;
                 },
-                // otherwise we're going to wait for the next orderbook
-                maybe_orderbook = orderbook_receiver.recv() => {
-                    match maybe_orderbook {
+                // otherwise we're going to wait for the next update
+                maybe_update = updates.next() => {
+                    match maybe_update {
                         None => break,
-                        Some((name, new_data)) => {
-                            log::info!("aggregator received new order book data from {name}");
-
-                            // All this vector manipulation is relatively inefficient from a theoretical point of view,
-                            // but it's my contention that for the number of levels we actually have to keep track of,
-                            // we're gaining as much from keeping the cache as we're losing by using linear memory patterns.
-
-                            // for both bids and asks, we simply clear out the old data and add the new data.
-                            for (summary, new_data) in [
-                                (&mut self.summary.bids, new_data.bids),
-                                (&mut self.summary.asks, new_data.asks),
-                            ] {
-                                summary.retain(|level| level.exchange != name);
-                                summary.extend(
-                                    new_data
-                                        .into_iter()
-                                        .map(|anonymous_level| anonymous_level.associate(name.to_string())),
-                                );
-                            }
-
-                            // bids get reverse-sorted because the highest bid is the best
-                            // both bids and asks sort primarily by price, but secondarily by larger quantity
-                            self.summary.bids.sort_unstable_by(|left, right|
-                                FloatOrd(left.price).cmp(&FloatOrd(right.price))
-                                    .reverse()
-                                    .then_with(|| FloatOrd(left.amount).cmp(&FloatOrd(right.amount)).reverse()));
-                            self.summary.asks.sort_unstable_by(|left, right|
-                                FloatOrd(left.price).cmp(&FloatOrd(right.price))
-                                    .then_with(|| FloatOrd(left.amount).cmp(&FloatOrd(right.amount)).reverse()));
-
-                            if self.summary.bids.is_empty() || self.summary.asks.is_empty() {
-                                self.summary.spread = 0.0;
-                            } else {
-                                self.summary.spread = self.summary.asks[0].price - self.summary.bids[0].price;
-                            }
-                            log::debug!(
-                                "computed new spread: {:.10} ({:?} - {:?})",
-                                self.summary.spread,
-                                self.summary.asks[0],
-                                self.summary.bids[0],
-                            );
-
-                            for summary_list in [&mut self.summary.bids, &mut self.summary.asks] {
-                                summary_list.truncate(SUMMARY_BID_ASK_LEN);
-                            }
-
-                            // This technically returns a result, but we know it will never return an error because
-                            // `self.summary_receiver` ensures that there always exists at least one receiver.
-                            self.summary_sender
-                                .send(self.summary.clone())
-                                .expect("there is always at least one receiver");
-                        }
+                        Some((index, (name, update))) => markets[index].on_update(name, update),
+                    }
+                }
+                Some((index, ())) = subscriptions.next() => markets[index].on_subscriptions_changed(),
+                // periodically check whether any exchange has gone quiet for too long
+                _ = freshness_check.tick() => {
+                    for market in markets.iter_mut() {
+                        market.evict_stale(|name| self.freshness_deadline_for(name));
                     }
                 }
             }
         }
 
-        log::debug!("`aggregate_orderbooks` going down; no more orderbooks are coming in");
+        log::debug!("`aggregate_markets` going down; no more orderbooks are coming in");
+    }
+
+    /// How long the named exchange may go without sending an order book before its levels are evicted.
+    fn freshness_deadline_for(&self, exchange: &str) -> Duration {
+        self.freshness_overrides
+            .get(exchange)
+            .copied()
+            .unwrap_or(self.freshness_deadline)
+    }
+}
+
+/// The aggregated state of a single market.
+#[derive(Debug)]
+struct Market {
+    pair: Pair,
+    book: AggregatedBook,
+    /// When each exchange's levels were current, ordered by exchange.
+    as_of: Vec<AsOf>,
+    summary_sender: watch::Sender<Summary>,
+    states: ExchangeStates,
+    states_sender: watch::Sender<ExchangeStates>,
+    feed: MarketFeed,
+    /// Tells the market when clients subscribe or unsubscribe.
+    subscriptions: watch::Receiver<()>,
+    /// The fees which each exchange charges, where configured.
+    fees: HashMap<String, Fees>,
+    /// When each exchange last sent an order book for this market.
+    last_updated: HashMap<&'static str, Instant>,
+    gap_counts: HashMap<&'static str, u64>,
+}
+
+impl Market {
+    fn new(pair: Pair, depth: usize, fees: HashMap<String, Fees>) -> Self {
+        let (summary_sender, summary_receiver) = watch::channel(Summary::default());
+        let states = ExchangeStates::default();
+        let (states_sender, states_receiver) = watch::channel(states.clone());
+        let (subscriptions, subscriptions_receiver) = Subscriptions::new(depth);
+        let feed = MarketFeed {
+            pair: pair.clone(),
+            summary: summary_receiver,
+            states: states_receiver,
+            subscriptions,
+            opportunities: Arc::default(),
+        };
+        Market {
+            pair,
+            book: AggregatedBook::new(depth),
+            as_of: Vec::new(),
+            summary_sender,
+            states,
+            states_sender,
+            feed,
+            subscriptions: subscriptions_receiver,
+            fees,
+            last_updated: HashMap::new(),
+            gap_counts: HashMap::new(),
+        }
+    }
+
+    /// Where the gRPC service can find this market's summaries and connection states.
+    fn feed(&self) -> MarketFeed {
+        self.feed.clone()
+    }
+
+    /// Merge an update from the named exchange into this market.
+    fn on_update(&mut self, name: &'static str, update: ExchangeUpdate) {
+        let pair = self.pair.clone();
+        match update {
+            ExchangeUpdate::Book(new_data) => {
+                log::info!("aggregator received new order book data from {name} for {pair}");
+                self.last_updated.insert(name, Instant::now());
+                self.replace_levels(name, new_data);
+                self.publish();
+                self.set_state(name, ConnectionState::Streaming);
+            }
+            // the exchange's book can't be trusted until it has resynchronized
+            ExchangeUpdate::Gap(gap) => {
+                let count = self.gap_counts.entry(name).or_default();
+                *count += 1;
+                log::warn!("{name} reported a {gap} for {pair} ({count} so far); evicting its levels until it resynchronizes");
+                self.last_updated.remove(name);
+                if self.evict(name) {
+                    self.publish();
+                }
+                self.set_state(
+                    name,
+                    ConnectionState::Degraded(format!("{gap}; resynchronizing")),
+                );
+            }
+            // The supervisor tells us when a connection has ended, even if it's about to be restarted.
+            // It does so on the same channel as the connection's books, so its final book can't
+            // reintroduce levels after we've evicted them.
+            ExchangeUpdate::State(state) => {
+                if let ConnectionState::Disconnected(reason) = &state {
+                    self.last_updated.remove(name);
+                    if self.evict(name) {
+                        log::info!("evicted levels from {name} for {pair} because its connection ended: {reason}");
+                        self.publish();
+                    }
+                }
+                self.set_state(name, state);
+            }
+        }
+    }
+
+    /// Keep as many levels as the deepest summary which clients need, and publish any view which they've yet to see.
+    fn on_subscriptions_changed(&mut self) {
+        let depth = self.feed.subscriptions.deepest();
+        if depth != self.book.depth() {
+            log::debug!("keeping the best {depth} levels for {}", self.pair);
+            self.book.set_depth(depth);
+            self.publish();
+        } else {
+            self.publish_views(false);
+        }
+    }
+
+    /// Evict every exchange which has gone quiet for longer than its freshness deadline.
+    fn evict_stale(&mut self, freshness_deadline_for: impl Fn(&str) -> Duration) {
+        let stale: Vec<_> = self
+            .last_updated
+            .iter()
+            .filter(|(name, updated)| updated.elapsed() > freshness_deadline_for(name))
+            .map(|(name, _)| *name)
+            .collect();
+        for name in stale.iter() {
+            self.last_updated.remove(name);
+            if self.evict(name) {
+                log::warn!(
+                    "evicted levels from {name} for {} because its data is stale",
+                    self.pair
+                );
+            }
+            let deadline = freshness_deadline_for(name);
+            self.set_state(
+                name,
+                ConnectionState::Degraded(format!("no order book for {deadline:?}")),
+            );
+        }
+        if !stale.is_empty() {
+            self.publish();
+        }
+    }
+
+    /// Replace all levels from the named exchange with those in `new_data`, and record when they were current.
+    fn replace_levels(&mut self, name: &'static str, new_data: SimpleOrderBook) {
+        self.book.replace(name, new_data.bids, new_data.asks);
+
+        self.as_of.retain(|as_of| as_of.exchange != name);
+        self.as_of.push(AsOf {
+            exchange: name.to_string(),
+            exchange_timestamp_micros: new_data.exchange_time.map(unix_micros).unwrap_or_default(),
+            received_timestamp_micros: unix_micros(new_data.received_at),
+        });
+        self.as_of
+            .sort_unstable_by(|left, right| left.exchange.cmp(&right.exchange));
+    }
+
+    /// Remove all levels from the named exchange.
+    ///
+    /// Returns `true` if any levels were removed.
+    fn evict(&mut self, name: &str) -> bool {
+        let prior_len = self.as_of.len();
+        self.as_of.retain(|as_of| as_of.exchange != name);
+        self.book.evict(name) || prior_len != self.as_of.len()
+    }
+
+    /// Record and publish the state of the named exchange's connection, if it has changed.
+    fn set_state(&mut self, name: &'static str, state: ConnectionState) {
+        let (status, reason) = match state {
+            ConnectionState::Connecting => (ConnectionStatus::Connecting, String::new()),
+            ConnectionState::Subscribed => (ConnectionStatus::Subscribed, String::new()),
+            ConnectionState::Streaming => (ConnectionStatus::Streaming, String::new()),
+            ConnectionState::Degraded(reason) => (ConnectionStatus::Degraded, reason),
+            ConnectionState::Disconnected(reason) => (ConnectionStatus::Disconnected, reason),
+        };
+        let states = &mut self.states.exchanges;
+        if states.iter().any(|state| {
+            state.exchange == name && state.status == status as i32 && state.reason == reason
+        }) {
+            return;
+        }
+
+        log::debug!("{name} is now {status:?} for {}", self.pair);
+        states.retain(|state| state.exchange != name);
+        states.push(ExchangeState {
+            exchange: name.to_string(),
+            status: status as i32,
+            reason,
+            since_timestamp_micros: unix_micros(SystemTime::now()),
+        });
+        states.sort_unstable_by(|left, right| left.exchange.cmp(&right.exchange));
+
+        // as with the summary, `self.feed` ensures that there's always a receiver
+        self.states_sender
+            .send(self.states.clone())
+            .expect("there is always at least one receiver");
+    }
+
+    /// Recompute the spread and publish the current summary.
+    fn publish(&mut self) {
+        let bids = self.book.bids();
+        let asks = self.book.asks();
+        let spread = spread(&bids, &asks);
+        log::debug!("computed new spread: {spread}");
+        let summary = Summary {
+            spread: spread.to_f64().unwrap_or_default(),
+            spread_exact: spread.to_string(),
+            bids: bids.iter().map(Into::into).collect(),
+            asks: asks.iter().map(Into::into).collect(),
+            as_of: self.as_of.clone(),
+        };
+
+        // This technically returns a result, but we know it will never return an error because
+        // `self.feed` ensures that there always exists at least one receiver.
+        self.summary_sender
+            .send(summary)
+            .expect("there is always at least one receiver");
+        self.publish_views(true);
+
+        self.feed
+            .opportunities
+            .lock()
+            .expect("no thread panics while holding the lock")
+            .track(&self.pair, self.book.crossings());
+    }
+
+    /// Publish each view which clients are streaming, or only those which they've yet to see.
+    fn publish_views(&self, everything: bool) {
+        let summary = self.summary_sender.borrow();
+        let depth = self.book.depth();
+        let mut state = self.feed.subscriptions.lock();
+
+        // the spread is that of the best bid and ask themselves, not their buckets
+        View::publish_all(&mut state.consolidated, everything, |tick| {
+            let consolidate = |side| {
+                let levels = self.book.consolidated(side, depth, *tick);
+                levels.iter().map(Into::into).collect()
+            };
+            ConsolidatedSummary {
+                spread: summary.spread,
+                spread_exact: summary.spread_exact.clone(),
+                bids: consolidate(Side::Bid),
+                asks: consolidate(Side::Ask),
+                as_of: summary.as_of.clone(),
+            }
+        });
+
+        View::publish_all(&mut state.fee_adjusted, everything, |liquidity| {
+            let net_levels = |side| {
+                self.book.adjusted(side, depth, |exchange, price| {
+                    self.fees_for(exchange).net_price(*liquidity, side, price)
+                })
+            };
+            let (bids, asks) = (net_levels(Side::Bid), net_levels(Side::Ask));
+            let spread = spread(&bids, &asks);
+            FeeAdjustedSummary {
+                raw: Some(summary.clone()),
+                net: Some(Summary {
+                    spread: spread.to_f64().unwrap_or_default(),
+                    spread_exact: spread.to_string(),
+                    bids: bids.iter().map(Into::into).collect(),
+                    asks: asks.iter().map(Into::into).collect(),
+                    as_of: summary.as_of.clone(),
+                }),
+            }
+        });
+    }
+
+    /// The fees which the named exchange charges; none, unless configured.
+    fn fees_for(&self, exchange: &str) -> Fees {
+        self.fees.get(exchange).copied().unwrap_or_default()
+    }
+}
+
+/// The difference between the best ask and the best bid, or zero if either side is empty.
+fn spread(bids: &[Level], asks: &[Level]) -> Decimal {
+    match (bids.first(), asks.first()) {
+        (Some(bid), Some(ask)) => ask.price - bid.price,
+        _ => Decimal::ZERO,
     }
 }
 
 pub type SummaryResult = Result<Summary, Status>;
 
-/// This service can respond to gRPC requests for a book summary stream, and deliver appropriate updates to that stream.
+/// Limit a summary to the best `depth` bids and asks.
+fn truncate_summary(mut summary: Summary, depth: usize) -> Summary {
+    summary.bids.truncate(depth);
+    summary.asks.truncate(depth);
+    summary
+}
+
+/// Limit both of a fee-adjusted summary's summaries to the best `depth` bids and asks.
+fn truncate_fee_adjusted(summary: FeeAdjustedSummary, depth: usize) -> FeeAdjustedSummary {
+    FeeAdjustedSummary {
+        raw: summary.raw.map(|raw| truncate_summary(raw, depth)),
+        net: summary.net.map(|net| truncate_summary(net, depth)),
+    }
+}
+
+/// Limit a consolidated summary to the best `depth` prices on each side.
+fn truncate_consolidated(mut summary: ConsolidatedSummary, depth: usize) -> ConsolidatedSummary {
+    summary.bids.truncate(depth);
+    summary.asks.truncate(depth);
+    summary
+}
+
+/// This service can respond to gRPC requests for a market's book summary stream, and deliver appropriate updates
+/// to that stream.
 #[derive(Debug, Clone)]
 pub struct OrderbookAggregatorService {
-    summary_receiver: watch::Receiver<Summary>,
+    feeds: MarketFeeds,
+}
+
+impl OrderbookAggregatorService {
+    /// Find the market which a request names, or the first market aggregated if it names none.
+    #[allow(clippy::result_large_err)]
+    fn feed(&self, pair: &str) -> Result<MarketFeed, Status> {
+        let feeds = self
+            .feeds
+            .read()
+            .expect("no thread panics while holding the lock");
+        if pair.is_empty() {
+            return feeds
+                .first()
+                .cloned()
+                .ok_or_else(|| Status::unavailable("no market is being aggregated yet"));
+        }
+        let pair: Pair = pair
+            .parse()
+            .map_err(|err: UnrecognizedPair| Status::invalid_argument(err.to_string()))?;
+        feeds
+            .iter()
+            .find(|feed| feed.pair == pair)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("{pair} is not being aggregated")))
+    }
+}
+
+/// The depth which a request asks for, or the market's default if it asks for none.
+#[allow(clippy::result_large_err)]
+fn requested_depth(feed: &MarketFeed, depth: u32) -> Result<usize, Status> {
+    match depth as usize {
+        0 => Ok(feed.subscriptions.default_depth),
+        depth if depth <= MAX_SUMMARY_DEPTH => Ok(depth),
+        depth => Err(Status::invalid_argument(format!(
+            "depth {depth} exceeds the maximum of {MAX_SUMMARY_DEPTH}"
+        ))),
+    }
+}
+
+/// The tick which a request asks for; without one, only levels at exactly the same price are consolidated.
+#[allow(clippy::result_large_err)]
+fn requested_tick(tick: &str) -> Result<Decimal, Status> {
+    if tick.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    match tick.parse::<Decimal>() {
+        Ok(tick) if !tick.is_sign_negative() => Ok(tick),
+        _ => Err(Status::invalid_argument(format!(
+            "tick \"{tick}\" is not a non-negative decimal"
+        ))),
+    }
 }
 
 #[tonic::async_trait]
+#[allow(clippy::result_large_err)]
 impl orderbook_aggregator_server::OrderbookAggregator for OrderbookAggregatorService {
     type BookSummaryStream = Pin<Box<dyn Stream<Item = SummaryResult> + Send>>;
+    type ConsolidatedBookSummaryStream =
+        Pin<Box<dyn Stream<Item = Result<ConsolidatedSummary, Status>> + Send>>;
+    type ConnectionStatesStream =
+        Pin<Box<dyn Stream<Item = Result<ExchangeStates, Status>> + Send>>;
+    type FeeAdjustedBookSummaryStream =
+        Pin<Box<dyn Stream<Item = Result<FeeAdjustedSummary, Status>> + Send>>;
+    type OpportunitiesStream = Pin<Box<dyn Stream<Item = Result<OpportunityEvent, Status>> + Send>>;
 
     async fn book_summary(
         &self,
-        _request: Request<Empty>,
+        request: Request<MarketRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
+        let feed = self.feed(&request.get_ref().pair)?;
+        let depth = requested_depth(&feed, request.get_ref().depth)?;
+        // the market keeps enough levels for this client for as long as it's streaming
+        let subscription = feed.subscriptions.subscribe(depth);
+        Ok(Response::new(Box::pin(WatchStream::new(feed.summary).map(
+            move |summary| {
+                let _subscription = &subscription;
+                Ok(truncate_summary(summary, depth))
+            },
+        ))))
+    }
+
+    async fn consolidated_book_summary(
+        &self,
+        request: Request<ConsolidatedRequest>,
+    ) -> Result<Response<Self::ConsolidatedBookSummaryStream>, Status> {
+        let feed = self.feed(&request.get_ref().pair)?;
+        let depth = requested_depth(&feed, request.get_ref().depth)?;
+        let tick = requested_tick(&request.get_ref().tick)?;
+        // the market publishes this view for as long as any client is streaming it
+        let (subscription, summaries) = feed.subscriptions.subscribe_consolidated(depth, tick);
+        Ok(Response::new(Box::pin(WatchStream::new(summaries).map(
+            move |summary| {
+                let _subscription = &subscription;
+                Ok(truncate_consolidated(summary, depth))
+            },
+        ))))
+    }
+
+    async fn fee_adjusted_book_summary(
+        &self,
+        request: Request<FeeAdjustedRequest>,
+    ) -> Result<Response<Self::FeeAdjustedBookSummaryStream>, Status> {
+        let feed = self.feed(&request.get_ref().pair)?;
+        let depth = requested_depth(&feed, request.get_ref().depth)?;
+        let liquidity = Liquidity::from_i32(request.get_ref().liquidity)
+            .ok_or_else(|| Status::invalid_argument("unknown liquidity"))?;
+        // the market publishes this view for as long as any client is streaming it
+        let (subscription, summaries) = feed.subscriptions.subscribe_fee_adjusted(depth, liquidity);
+        Ok(Response::new(Box::pin(WatchStream::new(summaries).map(
+            move |summary| {
+                let _subscription = &subscription;
+                Ok(truncate_fee_adjusted(summary, depth))
+            },
+        ))))
+    }
+
+    async fn connection_states(
+        &self,
+        request: Request<MarketRequest>,
+    ) -> Result<Response<Self::ConnectionStatesStream>, Status> {
+        let feed = self.feed(&request.get_ref().pair)?;
+        Ok(Response::new(Box::pin(
+            WatchStream::new(feed.states).map(Ok),
+        )))
+    }
+
+    async fn opportunities(
+        &self,
+        request: Request<MarketRequest>,
+    ) -> Result<Response<Self::OpportunitiesStream>, Status> {
+        let feed = self.feed(&request.get_ref().pair)?;
+        let (open, events) = feed
+            .opportunities
+            .lock()
+            .expect("no thread panics while holding the lock")
+            .subscribe();
+        let events = BroadcastStream::new(events).map(|event| {
+            // a client which misses an event can't know which opportunities are open, so it must start over
+            event.map_err(|BroadcastStreamRecvError::Lagged(missed)| {
+                Status::data_loss(format!("fell {missed} events behind; subscribe again"))
+            })
+        });
         Ok(Response::new(Box::pin(
-            WatchStream::new(self.summary_receiver.clone()).map(|summary| Ok(summary)),
+            futures::stream::iter(open.into_iter().map(Ok)).chain(events),
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(price: i64) -> ExchangeUpdate {
+        SimpleOrderBook::new(
+            vec![AnonymousLevel {
+                price: Decimal::new(price, 2),
+                amount: Decimal::ONE,
+            }],
+            vec![AnonymousLevel {
+                price: Decimal::new(price + 1, 2),
+                amount: Decimal::ONE,
+            }],
+        )
+        .into()
+    }
+
+    fn market() -> Market {
+        Market::new(Pair::new("eth", "btc"), 10, HashMap::new())
+    }
+
+    /// The exchanges whose bids are in the published summary.
+    fn exchanges(feed: &MarketFeed) -> Vec<String> {
+        feed.summary
+            .borrow()
+            .bids
+            .iter()
+            .map(|level| level.exchange.clone())
+            .collect()
+    }
+
+    /// The published status and reason of the named exchange's connection.
+    fn state(feed: &MarketFeed, exchange: &str) -> (ConnectionStatus, String) {
+        let states = feed.states.borrow();
+        let state = states
+            .exchanges
+            .iter()
+            .find(|state| state.exchange == exchange)
+            .expect("every exchange which has sent anything has a state");
+        (
+            ConnectionStatus::from_i32(state.status).unwrap(),
+            state.reason.clone(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_exchanges_are_evicted() {
+        let deadline = Duration::from_secs(10);
+        let mut market = market();
+        let feed = market.feed();
+        market.on_update("a", book(100));
+        market.on_update("b", book(99));
+        assert_eq!(exchanges(&feed), ["a", "b"]);
+
+        tokio::time::advance(Duration::from_secs(8)).await;
+        market.on_update("b", book(98));
+        market.evict_stale(|_| deadline);
+        assert_eq!(exchanges(&feed), ["a", "b"]);
+
+        // "a" has now been quiet for 12 seconds, "b" for only 4
+        tokio::time::advance(Duration::from_secs(4)).await;
+        market.evict_stale(|_| deadline);
+        assert_eq!(exchanges(&feed), ["b"]);
+        assert_eq!(
+            state(&feed, "a"),
+            (
+                ConnectionStatus::Degraded,
+                "no order book for 10s".to_string()
+            )
+        );
+        assert_eq!(
+            state(&feed, "b"),
+            (ConnectionStatus::Streaming, String::new())
+        );
+        assert!(market.as_of.iter().all(|as_of| as_of.exchange == "b"));
+
+        // a fresh book brings the exchange back
+        market.on_update("a", book(97));
+        assert_eq!(exchanges(&feed), ["b", "a"]);
+        assert_eq!(
+            state(&feed, "a"),
+            (ConnectionStatus::Streaming, String::new())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadlines_are_per_exchange() {
+        let mut market = market();
+        let feed = market.feed();
+        market.on_update("slow", book(100));
+        market.on_update("fast", book(99));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        market.evict_stale(|name| match name {
+            "slow" => Duration::from_secs(30),
+            _ => Duration::from_secs(2),
+        });
+        assert_eq!(exchanges(&feed), ["slow"]);
+        assert_eq!(state(&feed, "fast").0, ConnectionStatus::Degraded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnected_exchanges_are_evicted() {
+        let mut market = market();
+        let feed = market.feed();
+        market.on_update("a", book(100));
+        market.on_update("b", book(99));
+
+        market.on_update(
+            "a",
+            ExchangeUpdate::State(ConnectionState::Disconnected("dropped".to_string())),
+        );
+        assert_eq!(exchanges(&feed), ["b"]);
+        assert_eq!(
+            state(&feed, "a"),
+            (ConnectionStatus::Disconnected, "dropped".to_string())
+        );
+
+        // a disconnected exchange is already gone, so it isn't evicted again as stale
+        tokio::time::advance(Duration::from_secs(60)).await;
+        market.evict_stale(|_| Duration::from_secs(10));
+        assert_eq!(
+            state(&feed, "a"),
+            (ConnectionStatus::Disconnected, "dropped".to_string())
+        );
+        assert!(exchanges(&feed).is_empty());
+    }
+}
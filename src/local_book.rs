@@ -0,0 +1,104 @@
+use crate::{AnonymousLevel, SimpleOrderBook};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+/// Which side of the book a level belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// A full-depth order book, maintained locally from a snapshot and a stream of incremental updates.
+///
+/// Exchanges which publish incremental updates send absolute amounts for each changed price level;
+/// an amount of zero removes the level.
+#[derive(Debug, Default, Clone)]
+pub struct LocalBook {
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalBook {
+    /// Remove every level from the book.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    /// `true` when the book has no levels on either side.
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Replace the entire contents of the book with a snapshot.
+    pub fn replace(
+        &mut self,
+        bids: impl IntoIterator<Item = AnonymousLevel>,
+        asks: impl IntoIterator<Item = AnonymousLevel>,
+    ) {
+        self.clear();
+        self.apply_all(Side::Bid, bids);
+        self.apply_all(Side::Ask, asks);
+    }
+
+    /// Apply a single level update.
+    pub fn apply(&mut self, side: Side, level: AnonymousLevel) {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        if level.amount.is_zero() {
+            levels.remove(&level.price);
+        } else {
+            levels.insert(level.price, level.amount);
+        }
+    }
+
+    /// Apply several level updates to the same side of the book.
+    pub fn apply_all(&mut self, side: Side, levels: impl IntoIterator<Item = AnonymousLevel>) {
+        for level in levels {
+            self.apply(side, level);
+        }
+    }
+
+    /// Discard all but the best `depth` levels on each side.
+    ///
+    /// Some exchanges expect this of clients which subscribe to a limited depth, because
+    /// they won't send removals for levels which fall off the end of the book.
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+
+    /// Iterate over the bids, best (highest) first.
+    pub fn bids(&self) -> impl '_ + Iterator<Item = AnonymousLevel> {
+        self.bids
+            .iter()
+            .rev()
+            .map(|(price, amount)| AnonymousLevel {
+                price: *price,
+                amount: *amount,
+            })
+    }
+
+    /// Iterate over the asks, best (lowest) first.
+    pub fn asks(&self) -> impl '_ + Iterator<Item = AnonymousLevel> {
+        self.asks.iter().map(|(price, amount)| AnonymousLevel {
+            price: *price,
+            amount: *amount,
+        })
+    }
+
+    /// Produce a [`SimpleOrderBook`] containing at most `depth` of the best levels on each side.
+    pub fn top(&self, depth: usize) -> SimpleOrderBook {
+        SimpleOrderBook::new(
+            self.bids().take(depth).collect(),
+            self.asks().take(depth).collect(),
+        )
+    }
+}
@@ -1,12 +1,25 @@
 #[cfg(feature = "tui")]
 mod tui;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use rust_decimal::Decimal;
 use spreadget::{
-    connections::{binance::BinanceConnection, bitstamp::BitstampConnection, ExchangeConnection},
-    OrderbookAggregator,
+    connections::{
+        binance::{BinanceConnection, BinanceMode},
+        bitfinex::BitfinexConnection,
+        bitstamp::{BitstampConnection, BitstampMode},
+        coinbase::CoinbaseConnection,
+        generic::GenericJsonConnection,
+        htx::HtxConnection,
+        kraken::KrakenConnection,
+        okx::{OkxConnection, OkxMode},
+        proxy::{Proxy, ProxyConfig},
+        ExchangeConnection, Liveness,
+    },
+    supervisor::{RestartPolicy, Supervision, Supervisor},
+    Fees, OrderbookAggregator, Pair, MAX_SUMMARY_DEPTH,
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 use structopt::StructOpt;
 
 #[cfg(feature = "tui")]
@@ -15,25 +28,194 @@ use {
     tokio::{select, task::JoinError},
 };
 
+/// Exchanges which we know how to connect to.
+const EXCHANGES: &[&str] = &[
+    "binance", "bitfinex", "bitstamp", "coinbase", "htx", "kraken", "okx",
+];
+
+/// Exchanges whose connections also use a REST API.
+const REST_EXCHANGES: &[&str] = &["binance", "bitstamp"];
+
+/// An endpoint to use for an exchange instead of its default, given as `exchange=url`.
+#[derive(Debug, Clone)]
+struct EndpointOverride {
+    exchange: String,
+    url: String,
+}
+
+impl FromStr for EndpointOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (exchange, url) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected \"exchange=url\", got \"{s}\""))?;
+        if !EXCHANGES.contains(&exchange) {
+            return Err(format!("unknown exchange \"{exchange}\""));
+        }
+        Ok(EndpointOverride {
+            exchange: exchange.to_string(),
+            url: url.to_string(),
+        })
+    }
+}
+
+/// The fees which an exchange charges, given as `exchange=maker:taker` in basis points.
+#[derive(Debug, Clone)]
+struct FeeSchedule {
+    exchange: String,
+    fees: Fees,
+}
+
+impl FromStr for FeeSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected \"exchange=maker:taker\", got \"{s}\"");
+        let (exchange, fees) = s.split_once('=').ok_or_else(expected)?;
+        let (maker, taker) = fees.split_once(':').ok_or_else(expected)?;
+        let basis_points = |fee: &str| {
+            fee.parse::<Decimal>()
+                .map_err(|err| format!("invalid fee \"{fee}\": {err}"))
+        };
+        Ok(FeeSchedule {
+            exchange: exchange.to_string(),
+            fees: Fees::from_basis_points(basis_points(maker)?, basis_points(taker)?),
+        })
+    }
+}
+
 #[derive(Debug, StructOpt, Clone)]
 struct Options {
-    /// Market symbol to examine
+    /// Market symbols to examine; exchanges which can do so follow them all over a single connection
     #[structopt(default_value = "ethbtc")]
-    symbol: String,
+    symbols: Vec<Pair>,
 
     /// Address on which to serve gRPC streams of order books
     #[structopt(short, long, default_value = "0.0.0.0:54321")]
     address: SocketAddr,
 
+    /// Exchanges from which to aggregate order books
+    #[structopt(
+        short,
+        long = "exchange",
+        default_value = "binance,bitfinex,bitstamp,coinbase,htx,kraken,okx",
+        possible_values = EXCHANGES,
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    exchanges: Vec<String>,
+
+    /// JSON file describing an additional snapshot-style exchange; may be repeated
+    #[structopt(long, number_of_values = 1)]
+    exchange_config: Vec<PathBuf>,
+
+    /// What to do when an exchange connection fails: "restart", "give-up", or "fail-all"
+    #[structopt(long, default_value = "restart")]
+    restart_policy: RestartPolicy,
+
+    /// Consecutive failures tolerated per exchange before giving up on it (unlimited if unset)
+    #[structopt(long)]
+    retry_budget: Option<u32>,
+
+    /// Best bids and asks to publish per summary, for clients which don't ask for a particular depth
+    #[structopt(long, default_value = "10")]
+    depth: usize,
+
+    /// Maker and taker fees which an exchange charges, in basis points, as "exchange=maker:taker"; may be repeated
+    #[structopt(long, number_of_values = 1)]
+    fees: Vec<FeeSchedule>,
+
+    /// Seconds without an order book after which an exchange's levels are evicted
+    #[structopt(long, default_value = "30")]
+    stale_after: u64,
+
+    /// Seconds without receiving anything, even a reply to a ping, after which a connection is restarted
+    #[structopt(long, default_value = "15")]
+    idle_timeout: u64,
+
+    /// Proxy through which to reach every exchange, as "http://host:port" or "socks5://host:port" [default: from environment]
+    #[structopt(long)]
+    proxy: Option<Proxy>,
+
+    /// Reach every exchange directly, even if the environment names a proxy
+    #[structopt(long, conflicts_with = "proxy")]
+    no_proxy: bool,
+
+    /// Websocket endpoint to use for an exchange instead of its default, as "exchange=url"; may be repeated
+    #[structopt(long, number_of_values = 1)]
+    websocket_endpoint: Vec<EndpointOverride>,
+
+    /// REST API base URL to use for binance or bitstamp instead of its default, as "exchange=url"; may be repeated
+    #[structopt(long, number_of_values = 1)]
+    rest_endpoint: Vec<EndpointOverride>,
+
+    /// Maintain a full-depth Binance book from its diff-depth stream instead of following its top 20 levels
+    #[structopt(long)]
+    binance_full_depth: bool,
+
+    /// Maintain a full-depth Bitstamp book from its diff channel instead of following its 100-level snapshots
+    #[structopt(long)]
+    bitstamp_incremental: bool,
+
+    /// Maintain an OKX book from its checksummed `books` channel instead of following its top 5 levels
+    #[structopt(long)]
+    okx_incremental: bool,
+
     /// Run a TUI dashboard instead of showing log output
     #[cfg(feature = "tui")]
     #[structopt(long)]
     tui: bool,
 }
 
+impl Options {
+    /// The websocket endpoint overriding the named exchange's default, if any.
+    fn websocket_endpoint_for(&self, exchange: &str) -> Option<&String> {
+        endpoint_for(&self.websocket_endpoint, exchange)
+    }
+
+    /// How every connection detects that it has gone silent.
+    fn liveness(&self) -> Liveness {
+        Liveness::new(Duration::from_secs(self.idle_timeout))
+    }
+
+    /// How every connection reaches its exchange.
+    fn proxy(&self) -> ProxyConfig {
+        match &self.proxy {
+            Some(proxy) => ProxyConfig::Through(proxy.clone()),
+            None if self.no_proxy => ProxyConfig::Direct,
+            None => ProxyConfig::FromEnvironment,
+        }
+    }
+
+    /// The REST API base URL overriding the named exchange's default, if any.
+    fn rest_endpoint_for(&self, exchange: &str) -> Option<&String> {
+        endpoint_for(&self.rest_endpoint, exchange)
+    }
+}
+
+/// The last of the overrides given for the named exchange, if any.
+fn endpoint_for<'a>(overrides: &'a [EndpointOverride], exchange: &str) -> Option<&'a String> {
+    overrides
+        .iter()
+        .rev()
+        .find(|endpoint| endpoint.exchange == exchange)
+        .map(|endpoint| &endpoint.url)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let options = Options::from_args();
+    if let Some(endpoint) = options
+        .rest_endpoint
+        .iter()
+        .find(|endpoint| !REST_EXCHANGES.contains(&endpoint.exchange.as_str()))
+    {
+        bail!("{} has no REST endpoint to override", endpoint.exchange);
+    }
+    if !(1..=MAX_SUMMARY_DEPTH).contains(&options.depth) {
+        bail!("--depth must be between 1 and {MAX_SUMMARY_DEPTH}");
+    }
 
     #[cfg(not(feature = "tui"))]
     env_logger::init();
@@ -45,15 +227,60 @@ async fn main() -> Result<()> {
         }
     }
 
-    let mut aggregator = OrderbookAggregator::new();
+    let supervisor = Supervisor::new(Supervision {
+        policy: options.restart_policy,
+        retry_budget: options.retry_budget,
+        ..Supervision::default()
+    });
+
+    let generic_connections = options
+        .exchange_config
+        .iter()
+        .map(GenericJsonConnection::from_config_file)
+        .collect::<Result<Vec<_>, _>>()?;
+    // books and fees are keyed by exchange name, so each must be unique
+    for (index, connection) in generic_connections.iter().enumerate() {
+        let name = connection.exchange_name();
+        if EXCHANGES.contains(&name) {
+            bail!("exchange config name \"{name}\" is already used by a built-in exchange");
+        }
+        if generic_connections[..index]
+            .iter()
+            .any(|earlier| earlier.exchange_name() == name)
+        {
+            bail!("exchange config name \"{name}\" is used by more than one config");
+        }
+    }
+
+    if let Some(schedule) = options.fees.iter().find(|schedule| {
+        !EXCHANGES.contains(&schedule.exchange.as_str())
+            && !generic_connections
+                .iter()
+                .any(|connection| connection.exchange_name() == schedule.exchange)
+    }) {
+        bail!("fees given for unknown exchange \"{}\"", schedule.exchange);
+    }
+
+    let mut aggregator = OrderbookAggregator::new()
+        .with_supervisor(supervisor)
+        .with_summary_depth(options.depth)
+        .with_freshness_deadline(Duration::from_secs(options.stale_after));
+    for schedule in options.fees.iter() {
+        aggregator = aggregator.with_exchange_fees(schedule.exchange.clone(), schedule.fees);
+    }
     aggregator.launch_grpc_service(options.address);
-    let aggregator_future = aggregator.aggregate_orderbooks(
-        &options.symbol,
-        [
-            Box::new(BinanceConnection) as Box<dyn 'static + ExchangeConnection + Send + Sync>,
-            Box::new(BitstampConnection),
-        ],
-    );
+    let connections = options
+        .exchanges
+        .iter()
+        .map(|exchange| make_connection(exchange, &options))
+        .chain(generic_connections.into_iter().map(|connection| {
+            Box::new(
+                connection
+                    .with_liveness(options.liveness())
+                    .with_proxy(options.proxy()),
+            ) as Box<dyn 'static + ExchangeConnection + Send + Sync>
+        }));
+    let aggregator_future = aggregator.aggregate_markets(&options.symbols, connections);
 
     #[cfg(not(feature = "tui"))]
     aggregator_future.await;
@@ -85,3 +312,105 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Construct a connection to the named exchange, configured according to the options.
+///
+/// `exchange` must be one of [`EXCHANGES`].
+fn make_connection(
+    exchange: &str,
+    options: &Options,
+) -> Box<dyn 'static + ExchangeConnection + Send + Sync> {
+    let websocket_endpoint = options.websocket_endpoint_for(exchange).cloned();
+    let liveness = options.liveness();
+    let proxy = options.proxy();
+    let rest_endpoint = options.rest_endpoint_for(exchange).cloned();
+
+    match exchange {
+        "binance" => {
+            let connection = BinanceConnection::new(if options.binance_full_depth {
+                BinanceMode::FullDepth
+            } else {
+                BinanceMode::PartialDepth
+            })
+            .with_liveness(liveness)
+            .with_proxy(proxy);
+            let connection = with_override(
+                connection,
+                websocket_endpoint,
+                BinanceConnection::with_websocket_endpoint,
+            );
+            Box::new(with_override(
+                connection,
+                rest_endpoint,
+                BinanceConnection::with_rest_endpoint,
+            ))
+        }
+        "bitfinex" => Box::new(with_override(
+            BitfinexConnection::default()
+                .with_liveness(liveness)
+                .with_proxy(proxy),
+            websocket_endpoint,
+            BitfinexConnection::with_websocket_endpoint,
+        )),
+        "bitstamp" => {
+            let connection = BitstampConnection::new(if options.bitstamp_incremental {
+                BitstampMode::Incremental
+            } else {
+                BitstampMode::Snapshot
+            })
+            .with_liveness(liveness)
+            .with_proxy(proxy);
+            let connection = with_override(
+                connection,
+                websocket_endpoint,
+                BitstampConnection::with_websocket_endpoint,
+            );
+            Box::new(with_override(
+                connection,
+                rest_endpoint,
+                BitstampConnection::with_rest_endpoint,
+            ))
+        }
+        "coinbase" => Box::new(with_override(
+            CoinbaseConnection::default()
+                .with_liveness(liveness)
+                .with_proxy(proxy),
+            websocket_endpoint,
+            CoinbaseConnection::with_websocket_endpoint,
+        )),
+        "htx" => Box::new(with_override(
+            HtxConnection::default()
+                .with_liveness(liveness)
+                .with_proxy(proxy),
+            websocket_endpoint,
+            HtxConnection::with_websocket_endpoint,
+        )),
+        "kraken" => Box::new(with_override(
+            KrakenConnection::default()
+                .with_liveness(liveness)
+                .with_proxy(proxy),
+            websocket_endpoint,
+            KrakenConnection::with_websocket_endpoint,
+        )),
+        "okx" => Box::new(with_override(
+            OkxConnection::new(if options.okx_incremental {
+                OkxMode::Incremental
+            } else {
+                OkxMode::Snapshot
+            })
+            .with_liveness(liveness)
+            .with_proxy(proxy),
+            websocket_endpoint,
+            OkxConnection::with_websocket_endpoint,
+        )),
+        _ => unreachable!("structopt only permits known exchanges"),
+    }
+}
+
+/// Apply an endpoint override to a connection, if one was given.
+fn with_override<C>(connection: C, endpoint: Option<String>, apply: fn(C, String) -> C) -> C {
+    match endpoint {
+        Some(endpoint) => apply(connection, endpoint),
+        None => connection,
+    }
+}
@@ -0,0 +1,292 @@
+//! Following crossed books across exchanges as arbitrage opportunities, which start, change, and end.
+//!
+//! An opportunity is a pair of exchanges, one to buy from and one to sell to, whose books cross. It starts when
+//! they first cross, is updated whenever the crossed levels change, and ends when they no longer cross, or when
+//! either exchange's levels are evicted.
+
+use crate::{proto, unix_micros, Crossing, OpportunityEvent, OpportunityEventKind, Pair};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::{
+    collections::BTreeMap,
+    time::{Instant, SystemTime},
+};
+use tokio::sync::broadcast;
+
+/// How many events a client may fall behind by before its stream fails.
+const EVENT_BUFFER_LEN: usize = 1024;
+
+/// The opportunities open in a market, and a channel of events as they change.
+#[derive(Debug)]
+pub(crate) struct Opportunities {
+    /// The open opportunities, keyed by the exchange to buy from, then the exchange to sell to.
+    open: BTreeMap<(&'static str, &'static str), Opportunity>,
+    events: broadcast::Sender<OpportunityEvent>,
+}
+
+#[derive(Debug)]
+struct Opportunity {
+    crossing: Crossing,
+    started: SystemTime,
+    started_at: Instant,
+}
+
+impl Opportunity {
+    fn event(&self, kind: OpportunityEventKind) -> OpportunityEvent {
+        let crossing = &self.crossing;
+        OpportunityEvent {
+            kind: kind as i32,
+            opportunity: Some(proto::Opportunity {
+                buy_exchange: crossing.buy_exchange.to_string(),
+                sell_exchange: crossing.sell_exchange.to_string(),
+                best_ask: to_f64(crossing.best_ask),
+                best_ask_exact: crossing.best_ask.to_string(),
+                best_bid: to_f64(crossing.best_bid),
+                best_bid_exact: crossing.best_bid.to_string(),
+                quantity: to_f64(crossing.quantity),
+                quantity_exact: crossing.quantity.to_string(),
+                gross_profit: to_f64(crossing.gross_profit),
+                gross_profit_exact: crossing.gross_profit.to_string(),
+                started_timestamp_micros: unix_micros(self.started),
+                duration_micros: self.started_at.elapsed().as_micros() as u64,
+            }),
+        }
+    }
+}
+
+fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+impl Default for Opportunities {
+    fn default() -> Self {
+        Opportunities {
+            open: BTreeMap::new(),
+            events: broadcast::channel(EVENT_BUFFER_LEN).0,
+        }
+    }
+}
+
+impl Opportunities {
+    /// Compare the books' current crossings with the open opportunities, and send an event for each change.
+    pub(crate) fn track(&mut self, pair: &Pair, crossings: Vec<Crossing>) {
+        let crossings: BTreeMap<_, _> = crossings
+            .into_iter()
+            .map(|crossing| ((crossing.buy_exchange, crossing.sell_exchange), crossing))
+            .collect();
+
+        let ended: Vec<_> = self
+            .open
+            .keys()
+            .filter(|key| !crossings.contains_key(key))
+            .copied()
+            .collect();
+        for key in ended {
+            let opportunity = self.open.remove(&key).expect("only open keys are ended");
+            log::info!(
+                "opportunity to buy {pair} on {} and sell on {} ended after {:?}",
+                key.0,
+                key.1,
+                opportunity.started_at.elapsed()
+            );
+            self.send(opportunity.event(OpportunityEventKind::End));
+        }
+
+        for (key, crossing) in crossings {
+            match self.open.get_mut(&key) {
+                Some(opportunity) if opportunity.crossing == crossing => {}
+                Some(opportunity) => {
+                    opportunity.crossing = crossing;
+                    let event = opportunity.event(OpportunityEventKind::Update);
+                    self.send(event);
+                }
+                None => {
+                    log::info!(
+                        "opportunity to buy {pair} on {} at {} and sell on {} at {}: {} for a gross profit of {}",
+                        key.0,
+                        crossing.best_ask,
+                        key.1,
+                        crossing.best_bid,
+                        crossing.quantity,
+                        crossing.gross_profit
+                    );
+                    let opportunity = Opportunity {
+                        crossing,
+                        started: SystemTime::now(),
+                        started_at: Instant::now(),
+                    };
+                    self.send(opportunity.event(OpportunityEventKind::Start));
+                    self.open.insert(key, opportunity);
+                }
+            }
+        }
+    }
+
+    /// Follow the events from now on, starting with a `START` for each opportunity which is already open.
+    pub(crate) fn subscribe(
+        &self,
+    ) -> (Vec<OpportunityEvent>, broadcast::Receiver<OpportunityEvent>) {
+        let open = self
+            .open
+            .values()
+            .map(|opportunity| opportunity.event(OpportunityEventKind::Start))
+            .collect();
+        (open, self.events.subscribe())
+    }
+
+    fn send(&self, event: OpportunityEvent) {
+        // it's no matter if nobody is listening
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn crossing(
+        buy_exchange: &'static str,
+        sell_exchange: &'static str,
+        quantity: i64,
+    ) -> Crossing {
+        Crossing {
+            buy_exchange,
+            sell_exchange,
+            best_ask: Decimal::new(100, 2),
+            best_bid: Decimal::new(101, 2),
+            quantity: Decimal::from(quantity),
+            gross_profit: Decimal::new(quantity, 2),
+        }
+    }
+
+    /// The kind and exchanges of each event received since last asked.
+    fn received(
+        events: &mut broadcast::Receiver<OpportunityEvent>,
+    ) -> Vec<(OpportunityEventKind, String, String)> {
+        let mut received = Vec::new();
+        loop {
+            match events.try_recv() {
+                Ok(event) => {
+                    let opportunity = event.opportunity.unwrap();
+                    received.push((
+                        OpportunityEventKind::from_i32(event.kind).unwrap(),
+                        opportunity.buy_exchange,
+                        opportunity.sell_exchange,
+                    ));
+                }
+                Err(TryRecvError::Empty) => return received,
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    fn event(
+        kind: OpportunityEventKind,
+        buy: &str,
+        sell: &str,
+    ) -> (OpportunityEventKind, String, String) {
+        (kind, buy.to_string(), sell.to_string())
+    }
+
+    #[test]
+    fn start_update_end() {
+        use OpportunityEventKind::{End, Start, Update};
+
+        let pair = Pair::new("eth", "btc");
+        let mut opportunities = Opportunities::default();
+        let (open, mut events) = opportunities.subscribe();
+        assert!(open.is_empty());
+
+        opportunities.track(&pair, vec![crossing("a", "b", 1)]);
+        assert_eq!(received(&mut events), [event(Start, "a", "b")]);
+
+        // nothing changed, so nothing is sent
+        opportunities.track(&pair, vec![crossing("a", "b", 1)]);
+        assert!(received(&mut events).is_empty());
+
+        opportunities.track(&pair, vec![crossing("a", "b", 2), crossing("c", "a", 1)]);
+        assert_eq!(
+            received(&mut events),
+            [event(Update, "a", "b"), event(Start, "c", "a")]
+        );
+
+        // ends are sent before anything else
+        opportunities.track(&pair, vec![crossing("c", "a", 3)]);
+        assert_eq!(
+            received(&mut events),
+            [event(End, "a", "b"), event(Update, "c", "a")]
+        );
+
+        opportunities.track(&pair, Vec::new());
+        assert_eq!(received(&mut events), [event(End, "c", "a")]);
+
+        // the same exchanges crossing again is a new opportunity
+        opportunities.track(&pair, vec![crossing("a", "b", 1)]);
+        assert_eq!(received(&mut events), [event(Start, "a", "b")]);
+    }
+
+    #[test]
+    fn subscribers_start_with_the_open_opportunities() {
+        let pair = Pair::new("eth", "btc");
+        let mut opportunities = Opportunities::default();
+        opportunities.track(&pair, vec![crossing("b", "a", 1), crossing("a", "b", 2)]);
+
+        let (open, mut events) = opportunities.subscribe();
+        let open: Vec<_> = open
+            .into_iter()
+            .map(|event| {
+                let opportunity = event.opportunity.unwrap();
+                (
+                    OpportunityEventKind::from_i32(event.kind).unwrap(),
+                    opportunity.buy_exchange,
+                    opportunity.quantity_exact,
+                )
+            })
+            .collect();
+        assert_eq!(
+            open,
+            [
+                (
+                    OpportunityEventKind::Start,
+                    "a".to_string(),
+                    "2".to_string()
+                ),
+                (
+                    OpportunityEventKind::Start,
+                    "b".to_string(),
+                    "1".to_string()
+                ),
+            ]
+        );
+        // events from before subscribing aren't repeated
+        assert!(received(&mut events).is_empty());
+    }
+
+    #[test]
+    fn duration() {
+        let pair = Pair::new("eth", "btc");
+        let mut opportunities = Opportunities::default();
+        let (_, mut events) = opportunities.subscribe();
+
+        opportunities.track(&pair, vec![crossing("a", "b", 1)]);
+        std::thread::sleep(Duration::from_millis(20));
+        opportunities.track(&pair, vec![crossing("a", "b", 2)]);
+        std::thread::sleep(Duration::from_millis(20));
+        opportunities.track(&pair, Vec::new());
+
+        let events: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| event.opportunity.unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        // every event reports when the opportunity started, and how long it has been open
+        assert!(events
+            .iter()
+            .all(|event| event.started_timestamp_micros == events[0].started_timestamp_micros));
+        assert!(events[0].duration_micros < 20_000);
+        assert!(events[1].duration_micros >= 20_000);
+        assert!(events[2].duration_micros >= 40_000);
+        // the end carries the opportunity as it last was
+        assert_eq!(events[2].quantity_exact, "2");
+    }
+}
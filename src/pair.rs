@@ -0,0 +1,221 @@
+//! Identify a market independently of any exchange's naming conventions.
+//!
+//! Exchanges disagree about how to name the same market: `ethbtc`, `ETH-BTC`, `ETH/BTC`, and `tETHBTC`
+//! all refer to ether priced in bitcoin. Some also use their own names for particular currencies, such
+//! as `XBT` for bitcoin. A [`Pair`] is the canonical form, from which each connection produces its own
+//! exchange's symbol.
+
+use std::{fmt, str::FromStr};
+
+/// Quote currencies which we know how to split off the end of a market symbol like `ethbtc`.
+///
+/// Longer symbols come first, so that e.g. `usdt` is preferred over `usd`.
+const KNOWN_QUOTES: &[&str] = &[
+    "usdt", "usdc", "busd", "btc", "xbt", "eth", "usd", "eur", "gbp", "jpy", "dai",
+];
+
+/// Currencies which we recognize, by their canonical names.
+///
+/// A symbol without a separator must name two of these, so that a typo such as `etbtc` is caught at startup rather
+/// than leaving every exchange to report an unknown market. With a separator, the split is explicit, and any currency
+/// is accepted.
+const KNOWN_CURRENCIES: &[&str] = &[
+    "aave", "ada", "algo", "ape", "arb", "atom", "avax", "bat", "bch", "bnb", "btc", "busd",
+    "comp", "crv", "dai", "dash", "doge", "dot", "eos", "etc", "eth", "eur", "fil", "gbp", "grt",
+    "jpy", "link", "ltc", "mana", "matic", "mkr", "near", "op", "pepe", "sand", "shib", "snx",
+    "sol", "sushi", "trx", "uni", "usd", "usdc", "usdt", "xlm", "xmr", "xrp", "xtz", "yfi", "zec",
+    "zrx",
+];
+
+/// Alternative names for currencies, as `(alias, canonical)` pairs.
+const ALIASES: &[(&str, &str)] = &[("xbt", "btc"), ("xdg", "doge")];
+
+/// Characters which may separate the base and quote currencies in a market symbol.
+const SEPARATORS: &[char] = &['/', '-', '_', ':'];
+
+/// A traded market: the base currency, priced in the quote currency.
+///
+/// Both currencies are stored in lowercase, under their canonical names.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pair {
+    pub base: String,
+    pub quote: String,
+}
+
+impl Pair {
+    /// Create a pair from its base and quote currencies, canonicalizing their names.
+    pub fn new(base: &str, quote: &str) -> Self {
+        Pair {
+            base: canonicalize(base),
+            quote: canonicalize(quote),
+        }
+    }
+
+    /// Rename currencies according to an exchange's alias table of `(canonical, exchange)` pairs.
+    pub fn with_aliases<'a>(&self, aliases: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut pair = self.clone();
+        for (canonical, alias) in aliases {
+            if pair.base == canonical {
+                pair.base = alias.to_string();
+            }
+            if pair.quote == canonical {
+                pair.quote = alias.to_string();
+            }
+        }
+        pair
+    }
+
+    /// Join the base and quote currencies, in lowercase, with the given separator.
+    pub fn join(&self, separator: &str) -> String {
+        format!("{}{separator}{}", self.base, self.quote)
+    }
+}
+
+fn canonicalize(currency: &str) -> String {
+    let currency = currency.to_lowercase();
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == currency)
+        .map(|(_, canonical)| canonical.to_string())
+        .unwrap_or(currency)
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.base.to_uppercase(),
+            self.quote.to_uppercase()
+        )
+    }
+}
+
+impl FromStr for Pair {
+    type Err = UnrecognizedPair;
+
+    /// Parse a market symbol such as `ethbtc`, `ETH-BTC`, or `eth/xbt`.
+    ///
+    /// Without a separator, the quote currency must be one commonly used as such, and both currencies must be
+    /// [known][KNOWN_CURRENCIES]. With a separator, any alphanumeric currencies are accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.to_lowercase();
+        let (base, quote, separated) = match lowercase.split_once(SEPARATORS) {
+            Some((base, quote)) => (base, quote, true),
+            None => KNOWN_QUOTES
+                .iter()
+                .find_map(|quote| {
+                    lowercase
+                        .strip_suffix(quote)
+                        .map(|base| (base, *quote, false))
+                })
+                .ok_or_else(|| UnrecognizedPair::Format(s.to_string()))?,
+        };
+
+        let pair = Pair::new(base, quote);
+        for currency in [&pair.base, &pair.quote] {
+            if currency.is_empty() || !currency.chars().all(char::is_alphanumeric) {
+                return Err(UnrecognizedPair::Format(s.to_string()));
+            }
+            if !separated && !KNOWN_CURRENCIES.contains(&currency.as_str()) {
+                return Err(UnrecognizedPair::Currency {
+                    symbol: s.to_string(),
+                    currency: currency.clone(),
+                });
+            }
+        }
+        Ok(pair)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UnrecognizedPair {
+    #[error("unrecognized market symbol \"{0}\"; expected e.g. \"ethbtc\" or \"ETH/BTC\"")]
+    Format(String),
+    #[error("unrecognized currency \"{currency}\" in market symbol \"{symbol}\"")]
+    Currency { symbol: String, currency: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(base: &str, quote: &str) -> Pair {
+        Pair {
+            base: base.to_string(),
+            quote: quote.to_string(),
+        }
+    }
+
+    #[test]
+    fn separators() {
+        for symbol in [
+            "eth/btc", "ETH-BTC", "eth_btc", "Eth:Btc", "ethbtc", "ETHBTC",
+        ] {
+            assert_eq!(
+                symbol.parse::<Pair>().unwrap(),
+                pair("eth", "btc"),
+                "{symbol}"
+            );
+        }
+    }
+
+    #[test]
+    fn longest_known_quote_is_split_off() {
+        assert_eq!("ethusdt".parse::<Pair>().unwrap(), pair("eth", "usdt"));
+        assert_eq!("ethusd".parse::<Pair>().unwrap(), pair("eth", "usd"));
+        assert_eq!("btcusdc".parse::<Pair>().unwrap(), pair("btc", "usdc"));
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!("ethxbt".parse::<Pair>().unwrap(), pair("eth", "btc"));
+        assert_eq!("XBT/USD".parse::<Pair>().unwrap(), pair("btc", "usd"));
+        assert_eq!("xdg-btc".parse::<Pair>().unwrap(), pair("doge", "btc"));
+        assert_eq!("xdgxbt".parse::<Pair>().unwrap(), pair("doge", "btc"));
+    }
+
+    #[test]
+    fn rejected() {
+        for symbol in [
+            "",
+            "btc",
+            "eth/",
+            "/btc",
+            "eth btc",
+            "eth/b.c",
+            "ethfoo",
+            "kas/",
+            "sui-usd.c",
+        ] {
+            assert!(
+                matches!(symbol.parse::<Pair>(), Err(UnrecognizedPair::Format(_))),
+                "{symbol}"
+            );
+        }
+        for (symbol, unknown) in [("etbtc", "et"), ("KASUSDT", "kas"), ("wifusd", "wif")] {
+            match symbol.parse::<Pair>() {
+                Err(UnrecognizedPair::Currency { currency, .. }) => assert_eq!(currency, unknown),
+                other => panic!("{symbol} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn separated_currencies_need_not_be_known() {
+        assert_eq!("kas/usdt".parse::<Pair>().unwrap(), pair("kas", "usdt"));
+        assert_eq!("SUI-USDC".parse::<Pair>().unwrap(), pair("sui", "usdc"));
+        assert_eq!("wif/usd".parse::<Pair>().unwrap(), pair("wif", "usd"));
+        assert_eq!("eth_foo".parse::<Pair>().unwrap(), pair("eth", "foo"));
+        // aliases still apply
+        assert_eq!("kas:xbt".parse::<Pair>().unwrap(), pair("kas", "btc"));
+    }
+
+    #[test]
+    fn display_and_join() {
+        let pair = "xbt-usdt".parse::<Pair>().unwrap();
+        assert_eq!(pair.to_string(), "BTC/USDT");
+        assert_eq!(pair.join("-"), "btc-usdt");
+        assert_eq!(pair.with_aliases([("btc", "xbt")]).join(""), "xbtusdt");
+    }
+}
@@ -0,0 +1,444 @@
+//! Keep exchange connections alive.
+//!
+//! Websocket connections to exchanges drop routinely. Rather than taking the whole aggregator down
+//! when that happens, each connection runs under supervision: when it fails, it is restarted after
+//! a jittered, exponentially increasing delay, until its retry budget is exhausted.
+//!
+//! A connection which merely went [silent][Silent] is restarted immediately the first time, since
+//! replacing a dead socket is cheap and the exchange itself is probably fine. Repeated silences back off
+//! like any other failure.
+
+use crate::{
+    concatenate_errors,
+    connections::{ConnectionState, ExchangeConnection, ExchangeUpdate, Route, Silent},
+};
+use rand::Rng;
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// What to do when an exchange connection fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Restart the connection after a backoff delay.
+    ///
+    /// If the retry budget is exhausted, give up on this exchange only.
+    Restart,
+    /// Give up on this exchange, but leave the others running.
+    GiveUp,
+    /// Shut down every connection, forcing a restart of the entire system by some external user.
+    FailAll,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = UnknownRestartPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(RestartPolicy::Restart),
+            "give-up" => Ok(RestartPolicy::GiveUp),
+            "fail-all" => Ok(RestartPolicy::FailAll),
+            _ => Err(UnknownRestartPolicy(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown restart policy \"{0}\"; expected one of \"restart\", \"give-up\", \"fail-all\"")]
+pub struct UnknownRestartPolicy(String);
+
+/// Jittered exponential backoff.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Delay before the first restart.
+    pub initial: Duration,
+    /// Upper bound on the delay between restarts.
+    pub max: Duration,
+    /// Factor by which the delay grows after each consecutive failure.
+    pub multiplier: f64,
+    /// Fraction of the delay, in `0.0..=1.0`, which may be randomly subtracted from it.
+    ///
+    /// This keeps several connections which failed at once from all reconnecting in lockstep.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Compute the delay before restart number `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let delay = (self.initial.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 - rand::thread_rng().gen_range(0.0..=jitter);
+        Duration::from_secs_f64(delay * factor)
+    }
+}
+
+/// How a single exchange connection is supervised.
+#[derive(Debug, Clone, Copy)]
+pub struct Supervision {
+    pub policy: RestartPolicy,
+    pub backoff: Backoff,
+    /// How many consecutive failures are tolerated before the policy is abandoned.
+    ///
+    /// `None` means the connection is restarted indefinitely.
+    pub retry_budget: Option<u32>,
+    /// A connection which stays up at least this long is considered healthy, and resets both
+    /// the backoff delay and the retry budget.
+    pub healthy_after: Duration,
+}
+
+impl Default for Supervision {
+    fn default() -> Self {
+        Supervision {
+            policy: RestartPolicy::Restart,
+            backoff: Backoff::default(),
+            retry_budget: None,
+            healthy_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Supervision settings for a collection of exchange connections.
+///
+/// Every exchange uses the default settings unless they have been overridden for that exchange.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    default: Supervision,
+    overrides: HashMap<String, Supervision>,
+}
+
+impl Supervisor {
+    /// Create a supervisor which applies `default` to every exchange.
+    pub fn new(default: Supervision) -> Self {
+        Supervisor {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Supervise the named exchange differently from the default.
+    pub fn with_override(mut self, exchange: impl Into<String>, supervision: Supervision) -> Self {
+        self.overrides.insert(exchange.into(), supervision);
+        self
+    }
+
+    /// Get the supervision settings which apply to the named exchange.
+    pub fn supervision_for(&self, exchange: &str) -> Supervision {
+        self.overrides
+            .get(exchange)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Run a connection following the given routes under supervision, until it is given up on or its receivers close.
+///
+/// The connection's state is reported along every route as [`Connecting`][ConnectionState::Connecting] whenever
+/// it is (re)started and as [`Disconnected`][ConnectionState::Disconnected] whenever it ends, for whatever reason,
+/// so that the aggregator can stop relying on its data.
+///
+/// This returns an error only when the connection fails under [`RestartPolicy::FailAll`]. In every
+/// other case, a failure of this connection should not affect any other.
+pub(crate) async fn supervise(
+    connection: Arc<dyn ExchangeConnection + Sync + Send>,
+    routes: Vec<Route>,
+    supervision: Supervision,
+) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+    let name = connection.exchange_name();
+    let mut consecutive_failures = 0;
+
+    loop {
+        let started = Instant::now();
+        report(&routes, name, ConnectionState::Connecting).await;
+        let result = connection.connect_many(routes.clone()).await;
+        let reason = match &result {
+            Ok(()) => "connection concluded".to_string(),
+            Err(err) => concatenate_errors(&**err),
+        };
+        report(&routes, name, ConnectionState::Disconnected(reason)).await;
+
+        let err = match result {
+            Ok(()) => {
+                log::debug!("[{name}] connection concluded normally; no longer supervising");
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        if started.elapsed() >= supervision.healthy_after {
+            consecutive_failures = 0;
+        }
+
+        log::warn!(
+            "[{name}] connection failed after {:?}: {}",
+            started.elapsed(),
+            concatenate_errors(&*err)
+        );
+
+        match supervision.policy {
+            RestartPolicy::FailAll => return Err(err),
+            RestartPolicy::GiveUp => {
+                log::error!("[{name}] giving up on this exchange");
+                return Ok(());
+            }
+            RestartPolicy::Restart => {}
+        }
+
+        if routes.iter().all(|route| route.updates.is_closed()) {
+            log::debug!("[{name}] receiver closed; not restarting");
+            return Ok(());
+        }
+
+        if let Some(budget) = supervision.retry_budget {
+            if consecutive_failures >= budget {
+                log::error!(
+                    "[{name}] retry budget of {budget} exhausted; giving up on this exchange"
+                );
+                return Ok(());
+            }
+        }
+
+        let delay = if consecutive_failures == 0 && Silent::is_cause_of(&*err) {
+            Duration::ZERO
+        } else {
+            supervision.backoff.delay(consecutive_failures)
+        };
+        consecutive_failures += 1;
+        log::info!("[{name}] restarting in {delay:?} (attempt {consecutive_failures})");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Report a connection's state along each of its routes.
+async fn report(routes: &[Route], name: &'static str, state: ConnectionState) {
+    for route in routes {
+        // if this fails, the aggregator is gone, in which case there's nobody to tell
+        let _ = route
+            .updates
+            .send((name, ExchangeUpdate::State(state.clone())))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pair;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn delay_grows_exponentially_up_to_the_max() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn delay_stays_within_the_jitter() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(8),
+            multiplier: 2.0,
+            jitter: 0.25,
+        };
+        for attempt in 0..5 {
+            let full = Duration::from_secs(1 << attempt.min(3));
+            for _ in 0..100 {
+                let delay = backoff.delay(attempt);
+                assert!(delay <= full, "{delay:?} > {full:?}");
+                assert!(delay >= full.mul_f64(0.75), "{delay:?} < 0.75 * {full:?}");
+            }
+        }
+
+        // jitter beyond the whole delay is clamped to it
+        let backoff = Backoff {
+            jitter: 3.0,
+            ..backoff
+        };
+        assert!(backoff.delay(0) <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parse_restart_policy() {
+        assert_eq!(
+            "restart".parse::<RestartPolicy>().unwrap(),
+            RestartPolicy::Restart
+        );
+        assert_eq!(
+            "give-up".parse::<RestartPolicy>().unwrap(),
+            RestartPolicy::GiveUp
+        );
+        assert_eq!(
+            "fail-all".parse::<RestartPolicy>().unwrap(),
+            RestartPolicy::FailAll
+        );
+        for policy in ["", "Restart", "give_up", "failall", "restart "] {
+            assert!(policy.parse::<RestartPolicy>().is_err(), "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let give_up = Supervision {
+            policy: RestartPolicy::GiveUp,
+            ..Supervision::default()
+        };
+        let supervisor = Supervisor::default().with_override("kraken", give_up);
+        assert_eq!(
+            supervisor.supervision_for("kraken").policy,
+            RestartPolicy::GiveUp
+        );
+        assert_eq!(
+            supervisor.supervision_for("okx").policy,
+            RestartPolicy::Restart
+        );
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("stub failure")]
+    struct StubError;
+
+    /// A connection which stays up for each of the given durations in turn, then fails.
+    ///
+    /// Once the durations run out, it fails immediately.
+    struct Flaky {
+        uptimes: Mutex<Vec<Duration>>,
+        attempts: Mutex<u32>,
+    }
+
+    impl Flaky {
+        fn new(uptimes: &[Duration]) -> Arc<Self> {
+            Arc::new(Flaky {
+                uptimes: Mutex::new(uptimes.iter().rev().copied().collect()),
+                attempts: Mutex::new(0),
+            })
+        }
+
+        fn attempts(&self) -> u32 {
+            *self.attempts.lock().unwrap()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ExchangeConnection for Flaky {
+        fn exchange_name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn exchange_symbol(&self, pair: &Pair) -> String {
+            pair.join("")
+        }
+
+        async fn connect(
+            &self,
+            _symbol: String,
+            _updates: mpsc::Sender<(&'static str, ExchangeUpdate)>,
+        ) -> Result<(), Box<dyn 'static + std::error::Error + Send>> {
+            *self.attempts.lock().unwrap() += 1;
+            let uptime = self.uptimes.lock().unwrap().pop().unwrap_or_default();
+            tokio::time::sleep(uptime).await;
+            Err(Box::new(StubError))
+        }
+    }
+
+    /// Supervise the connection to the end, returning the result and the states which it reported.
+    async fn run(connection: Arc<Flaky>, supervision: Supervision) -> (bool, Vec<ConnectionState>) {
+        let (updates, mut received) = mpsc::channel(1024);
+        let routes = vec![Route {
+            symbol: "ethbtc".to_string(),
+            updates,
+        }];
+        let result = supervise(connection, routes, supervision).await;
+        let mut states = Vec::new();
+        while let Ok((_, update)) = received.try_recv() {
+            if let ExchangeUpdate::State(state) = update {
+                states.push(state);
+            }
+        }
+        (result.is_ok(), states)
+    }
+
+    fn restart_with_budget(retry_budget: u32) -> Supervision {
+        Supervision {
+            policy: RestartPolicy::Restart,
+            retry_budget: Some(retry_budget),
+            healthy_after: Duration::from_secs(60),
+            ..Supervision::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_once_the_retry_budget_is_spent() {
+        let connection = Flaky::new(&[]);
+        let (ok, states) = run(connection.clone(), restart_with_budget(3)).await;
+        assert!(ok);
+        // the first attempt, then one restart for each failure in the budget
+        assert_eq!(connection.attempts(), 4);
+        let disconnected = ConnectionState::Disconnected("stub failure".to_string());
+        assert_eq!(
+            states,
+            std::iter::repeat_n([ConnectionState::Connecting, disconnected], 4)
+                .flatten()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn healthy_run_resets_the_retry_budget() {
+        let quick = Duration::from_secs(1);
+        let healthy = Duration::from_secs(61);
+        let connection = Flaky::new(&[quick, quick, healthy, quick, quick]);
+        let (ok, states) = run(connection.clone(), restart_with_budget(2)).await;
+        assert!(ok);
+        // two failures spend the budget, but the healthy run restores it for two more
+        assert_eq!(connection.attempts(), 5);
+        assert_eq!(states.len(), 10);
+        assert!(states.iter().skip(1).step_by(2).all(|state| matches!(
+            state,
+            ConnectionState::Disconnected(reason) if reason == "stub failure"
+        )));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_policies_do_not_restart() {
+        let connection = Flaky::new(&[]);
+        let supervision = Supervision {
+            policy: RestartPolicy::GiveUp,
+            ..Supervision::default()
+        };
+        let (ok, states) = run(connection.clone(), supervision).await;
+        assert!(ok);
+        assert_eq!(connection.attempts(), 1);
+        assert_eq!(states.len(), 2);
+
+        let connection = Flaky::new(&[]);
+        let supervision = Supervision {
+            policy: RestartPolicy::FailAll,
+            ..Supervision::default()
+        };
+        let (ok, _) = run(connection.clone(), supervision).await;
+        assert!(!ok);
+        assert_eq!(connection.attempts(), 1);
+    }
+}
@@ -1,10 +1,11 @@
-use spreadget::Summary;
+use spreadget::{ExchangeStates, Pair, Summary};
 
 use crate::Options;
 
 pub(crate) struct App {
     pub options: Options,
     pub summary: Summary,
+    pub states: ExchangeStates,
     pub should_quit: bool,
 }
 
@@ -13,10 +14,16 @@ impl App {
         App {
             options,
             summary: Summary::default(),
+            states: ExchangeStates::default(),
             should_quit: false,
         }
     }
 
+    /// The market on display: the first of those aggregated.
+    pub fn pair(&self) -> &Pair {
+        &self.options.symbols[0]
+    }
+
     pub fn on_quit_key(&mut self) {
         self.should_quit = true;
     }
@@ -24,4 +31,8 @@ impl App {
     pub fn on_new_summary(&mut self, summary: Summary) {
         self.summary = summary;
     }
+
+    pub fn on_new_states(&mut self, states: ExchangeStates) {
+        self.states = states;
+    }
 }
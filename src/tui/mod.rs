@@ -9,7 +9,7 @@ use crossterm::{
 };
 use futures::{FutureExt, StreamExt};
 use spreadget::{
-    concatenate_errors, orderbook_aggregator_client::OrderbookAggregatorClient, Empty,
+    concatenate_errors, orderbook_aggregator_client::OrderbookAggregatorClient, MarketRequest,
 };
 use std::{io, time::Duration};
 use tokio::{select, time::sleep};
@@ -39,7 +39,7 @@ pub(crate) async fn run(options: Options) -> Result<()> {
     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
     terminal.show_cursor()?;
 
-    res.map_err(Into::into)
+    res
 }
 
 async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result<()> {
@@ -78,13 +78,19 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result
             )
         }
     };
-    let mut summary_stream = client.book_summary(Empty {}).await?.into_inner();
+    let market = MarketRequest {
+        pair: app.pair().to_string(),
+        depth: 0,
+    };
+    let mut summary_stream = client.book_summary(market.clone()).await?.into_inner();
+    let mut states_stream = client.connection_states(market).await?.into_inner();
 
     loop {
         terminal.draw(|f| ui::draw(f, &mut app))?;
 
         let event = event_stream.next().fuse();
         let summary = summary_stream.next().fuse();
+        let states = states_stream.next().fuse();
 
         select! {
             maybe_event = event => {
@@ -110,6 +116,15 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result
                     None => break,
                 }
             }
+            maybe_states = states => {
+                match maybe_states {
+                    Some(Ok(states)) => {
+                        app.on_new_states(states);
+                    }
+                    Some(Err(err)) => log::error!("[states] {err}"),
+                    None => break,
+                }
+            }
         }
 
         if app.should_quit {
@@ -1,5 +1,5 @@
 use super::app::App;
-use spreadget::Level;
+use spreadget::{proto::Level, ConnectionStatus, ExchangeState};
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Direction, Layout},
@@ -16,6 +16,7 @@ pub(crate) fn draw<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
                 Constraint::Length(3),
                 Constraint::Length(1),
                 Constraint::Length(2),
+                Constraint::Length(2),
                 Constraint::Min(15),
             ]
             .as_ref(),
@@ -26,7 +27,7 @@ pub(crate) fn draw<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
     let addr_style = Style::default().fg(Color::DarkGray);
 
     let title_text = Spans::from(vec![
-        Span::styled(app.options.symbol.clone(), symbol_style),
+        Span::styled(app.pair().to_string(), symbol_style),
         Span::raw(" <- "),
         Span::styled(format!("{}", app.options.address), addr_style),
     ]);
@@ -48,7 +49,7 @@ pub(crate) fn draw<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
     let spread_text = Spans::from(vec![
         Span::raw("Spread: "),
         Span::styled(
-            format!("{:.10}", app.summary.spread),
+            app.summary.spread_exact.clone(),
             Style::default().add_modifier(Modifier::BOLD),
         ),
     ]);
@@ -57,22 +58,48 @@ pub(crate) fn draw<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
         .alignment(Alignment::Left);
     frame.render_widget(spread, chunks[2]);
 
+    let mut states_text = vec![Span::raw("Exchanges:")];
+    for state in app.states.exchanges.iter() {
+        states_text.push(Span::raw(" "));
+        states_text.push(state_as_span(state));
+    }
+    let states = Paragraph::new(Spans::from(states_text))
+        .style(Style::default().fg(Color::Black).bg(Color::White))
+        .alignment(Alignment::Left);
+    frame.render_widget(states, chunks[3]);
+
     let table_halves = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
-        .split(chunks[3]);
+        .split(chunks[4]);
     let bids = levels_as_table("Bids", &app.summary.bids);
     frame.render_widget(bids, table_halves[0]);
     let asks = levels_as_table("Asks", &app.summary.asks);
     frame.render_widget(asks, table_halves[1]);
 }
 
+fn state_as_span(state: &ExchangeState) -> Span<'static> {
+    let (label, color) = match ConnectionStatus::from_i32(state.status) {
+        Some(ConnectionStatus::Connecting) => ("connecting", Color::DarkGray),
+        Some(ConnectionStatus::Subscribed) => ("subscribed", Color::Blue),
+        Some(ConnectionStatus::Streaming) => ("streaming", Color::Green),
+        Some(ConnectionStatus::Degraded) => ("degraded", Color::Yellow),
+        Some(ConnectionStatus::Disconnected) | None => ("disconnected", Color::Red),
+    };
+    let text = if state.reason.is_empty() {
+        format!("{} ({label})", state.exchange)
+    } else {
+        format!("{} ({label}: {})", state.exchange, state.reason)
+    };
+    Span::styled(text, Style::default().fg(color))
+}
+
 fn levels_as_table(which: &str, levels: &[Level]) -> Table<'static> {
     Table::new(levels.iter().map(|level| {
         Row::new([
-            Cell::from(format!("{:.5}", level.amount)),
+            Cell::from(level.amount_exact.clone()),
             Cell::from("@").style(Style::default().fg(Color::Red)),
-            Cell::from(format!("{:.10}", level.price)),
+            Cell::from(level.price_exact.clone()),
             Cell::from(format!("({})", level.exchange))
                 .style(Style::default().fg(Color::LightBlue)),
         ])